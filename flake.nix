@@ -81,6 +81,11 @@
                 program = nixpkgs.lib.getExe' self.packages.${system}.default "hoipc";
               };
               hoipc = client;
+              relay = {
+                type = "app";
+                program = nixpkgs.lib.getExe' self.packages.${system}.default "hoipr";
+              };
+              hoipr = relay;
             };
           }
         );
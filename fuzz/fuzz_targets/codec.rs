@@ -0,0 +1,56 @@
+//! Feeds arbitrary bytes to the decoder. The first 4 bytes pick the negotiated
+//! features. Whatever decodes must survive re-encoding and decoding again.
+//!
+//! Run with `cargo fuzz run codec`.
+
+#![no_main]
+
+use hid_over_ip::{
+    codec::{Codec, Message},
+    handshake::Features,
+};
+use libfuzzer_sys::fuzz_target;
+use tokio_util::{
+    bytes::BytesMut,
+    codec::{Decoder, Encoder},
+};
+
+fuzz_target!(|data: &[u8]| {
+    let Some((features, data)) = data.split_first_chunk::<4>() else {
+        return;
+    };
+    let features = Features::from_bits(u32::from_be_bytes(*features))
+        .intersection(Features::supported())
+        .with_dependencies();
+    let mut decoder = Codec::new(features);
+    let mut buf = BytesMut::from(data);
+    while let Ok(Some(msg)) = decoder.decode(&mut buf) {
+        let mut codec = Codec::new(features);
+        let mut encoded = BytesMut::new();
+        codec
+            .encode(msg.clone(), &mut encoded)
+            .expect("Decoded message must be encodable");
+        let decoded = codec
+            .decode(&mut encoded)
+            .expect("Re-encoded message must decode")
+            .expect("Re-encoded message must be complete");
+        assert!(encoded.is_empty());
+        match (&msg, &decoded) {
+            // timestamps are not preserved exactly
+            (Message::Packet(a), Message::Packet(b)) => {
+                assert_eq!(a.device, b.device);
+                assert!(a.events.iter().zip(&b.events).all(|(a, b)| (
+                    a.event_type(),
+                    a.code(),
+                    a.value()
+                ) == (
+                    b.event_type(),
+                    b.code(),
+                    b.value()
+                )));
+                assert_eq!(a.events.len(), b.events.len());
+            }
+            _ => assert_eq!(msg, decoded),
+        }
+    }
+});
@@ -0,0 +1,166 @@
+//! Allow/deny rules for peer addresses.
+
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+/// An address or CIDR range, optionally restricted to an interface with
+/// `%ifname`, e.g. `192.168.1.10`, `10.0.0.0/8` or `fe80::/10%eth0`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Rule {
+    addr: IpAddr,
+    prefix: u8,
+    /// Interface index. Only IPv6 link-local peers have one, so a rule with an
+    /// interface never matches anything else.
+    iface: Option<u32>,
+}
+
+impl Rule {
+    pub fn matches(&self, peer: SocketAddr) -> bool {
+        if let Some(iface) = self.iface {
+            let SocketAddr::V6(peer) = peer else {
+                return false;
+            };
+            if peer.scope_id() != iface {
+                return false;
+            }
+        }
+        // listening on [::] gives v4 peers as v4-mapped v6 addresses.
+        match (self.addr, peer.ip().to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = (usize::from(prefix / 8), prefix % 8);
+    net[..bytes] == ip[..bytes]
+        && (bits == 0 || (net[bytes] ^ ip[bytes]) & (0xff << (8 - bits)) == 0)
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (s, iface) = match s.split_once('%') {
+            Some((s, ifname)) => {
+                let iface = getifaddrs::if_nametoindex(ifname)
+                    .with_context(|| format!("Unknown interface {ifname}"))?;
+                (s, Some(iface))
+            }
+            None => (s, None),
+        };
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().context("Invalid address")?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().context("Invalid prefix length")?,
+            None => max,
+        };
+        anyhow::ensure!(prefix <= max, "Prefix length is over {max}");
+        Ok(Self {
+            addr: addr.to_canonical(),
+            prefix,
+            iface,
+        })
+    }
+}
+
+/// Deny rules take precedence. If there are any allow rules, peers have to
+/// match one of them; otherwise, anyone not denied is allowed.
+#[derive(Clone, Default, Debug)]
+pub struct Acl {
+    pub allow: Vec<Rule>,
+    pub deny: Vec<Rule>,
+}
+
+impl Acl {
+    pub fn permits(&self, peer: SocketAddr) -> bool {
+        !self.deny.iter().any(|rule| rule.matches(peer))
+            && (self.allow.is_empty() || self.allow.iter().any(|rule| rule.matches(peer)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddrV6;
+
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        SocketAddr::new(s.parse().unwrap(), 1234)
+    }
+
+    #[test]
+    fn test_rule() {
+        let rule: Rule = "192.168.1.0/24".parse().unwrap();
+        assert!(rule.matches(addr("192.168.1.77")));
+        assert!(rule.matches(addr("::ffff:192.168.1.77")));
+        assert!(!rule.matches(addr("192.168.2.1")));
+        assert!(!rule.matches(addr("::1")));
+
+        let rule: Rule = "10.1.2.3".parse().unwrap();
+        assert!(rule.matches(addr("10.1.2.3")));
+        assert!(!rule.matches(addr("10.1.2.4")));
+
+        let rule: Rule = "fe80::/10".parse().unwrap();
+        assert!(rule.matches(addr("fe80::1")));
+        assert!(rule.matches(addr("febf::1")));
+        assert!(!rule.matches(addr("fec0::1")));
+
+        let rule: Rule = "0.0.0.0/0".parse().unwrap();
+        assert!(rule.matches(addr("1.2.3.4")));
+
+        assert!("10.0.0.0/33".parse::<Rule>().is_err());
+        assert!("fe80::/10%no-such-iface".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_rule_iface() {
+        let rule = Rule {
+            iface: Some(2),
+            ..("fe80::/10".parse().unwrap())
+        };
+        let peer = |scope_id| {
+            SocketAddr::V6(SocketAddrV6::new(
+                "fe80::1".parse().unwrap(),
+                0,
+                0,
+                scope_id,
+            ))
+        };
+        assert!(rule.matches(peer(2)));
+        assert!(!rule.matches(peer(3)));
+        assert!(!rule.matches(addr("1.2.3.4")));
+    }
+
+    #[test]
+    fn test_acl() {
+        assert!(Acl::default().permits(addr("1.2.3.4")));
+        let acl = Acl {
+            allow: vec!["192.168.1.0/24".parse().unwrap()],
+            deny: vec!["192.168.1.13".parse().unwrap()],
+        };
+        assert!(acl.permits(addr("192.168.1.12")));
+        assert!(!acl.permits(addr("192.168.1.13")));
+        assert!(!acl.permits(addr("10.0.0.1")));
+        let acl = Acl {
+            allow: vec![],
+            deny: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        assert!(acl.permits(addr("192.168.1.1")));
+        assert!(!acl.permits(addr("10.0.0.1")));
+    }
+}
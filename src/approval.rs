@@ -0,0 +1,175 @@
+//! Interactive approval of new connections on the accepting side. With
+//! [`Features::APPROVAL`](crate::handshake::Features::APPROVAL), the accepting
+//! side sends a verdict right after the handshake (and pairing, if enabled),
+//! and the connecting side neither describes nor grabs its devices until it
+//! gets a positive one. This way, `hoipc` can hold a connection until someone
+//! physically at the machine presses a key chord.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    time::Duration,
+};
+
+use anyhow::Context;
+use evdev::{EventSummary, InputEvent, KeyCode};
+use futures::TryStreamExt;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    time::Instant,
+};
+
+use crate::{handshake::HANDSHAKE_TIMEOUT, keyboard, transport::Stream};
+
+/// How long the operator has to approve a connection.
+pub const APPROVAL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Tells the connecting side whether the connection may proceed.
+pub async fn send_verdict(stream: &mut Stream, approved: bool) -> anyhow::Result<()> {
+    stream
+        .write_u8(approved.into())
+        .await
+        .context("Send approval verdict")?;
+    stream.flush().await.context("Send approval verdict")
+}
+
+/// Waits for the accepting side to approve the connection. Fails if it
+/// doesn't.
+pub async fn recv_verdict(stream: &mut Stream) -> anyhow::Result<()> {
+    let verdict = tokio::time::timeout(APPROVAL_TIMEOUT + HANDSHAKE_TIMEOUT, stream.read_u8())
+        .await
+        .context("Timed out waiting for the peer to approve the connection")?
+        .context("Read approval verdict")?;
+    anyhow::ensure!(verdict != 0, "Peer didn't approve the connection");
+    Ok(())
+}
+
+/// Keys that have to be held down together.
+#[derive(Debug)]
+pub struct Chord {
+    keys: BTreeSet<KeyCode>,
+    pressed: BTreeSet<KeyCode>,
+}
+
+impl Chord {
+    pub fn new(keys: impl IntoIterator<Item = KeyCode>) -> Self {
+        Self {
+            keys: keys.into_iter().collect(),
+            pressed: BTreeSet::new(),
+        }
+    }
+
+    /// Tracks key state, returns `true` when `evt` completes the chord.
+    pub fn event(&mut self, evt: &InputEvent) -> bool {
+        let EventSummary::Key(_, key, value) = evt.destructure() else {
+            return false;
+        };
+        if !self.keys.contains(&key) {
+            return false;
+        }
+        match value {
+            0 => {
+                self.pressed.remove(&key);
+                false
+            }
+            // autorepeat doesn't complete the chord again.
+            1 => self.pressed.insert(key) && self.pressed == self.keys,
+            _ => false,
+        }
+    }
+
+    /// Waits until the chord is pressed on a keyboard attached to this
+    /// machine.
+    pub async fn wait(mut self) -> anyhow::Result<()> {
+        let keys: Vec<_> = self.keys.iter().copied().collect();
+        let mut events = keyboard::events(&keys).context("No keyboard to approve on")?;
+        while let Some(evt) = events.try_next().await.context("Read keyboard")? {
+            if self.event(&evt) {
+                return Ok(());
+            }
+        }
+        anyhow::bail!("Keyboards went away")
+    }
+}
+
+/// Peers approved recently, by address and name.
+#[derive(Debug)]
+pub struct Approvals {
+    remember: Option<Duration>,
+    until: HashMap<(IpAddr, String), Instant>,
+}
+
+impl Approvals {
+    /// With `remember` unset, nothing is remembered.
+    pub fn new(remember: Option<Duration>) -> Self {
+        Self {
+            remember,
+            until: HashMap::new(),
+        }
+    }
+
+    pub fn contains(&mut self, ip: IpAddr, name: &str) -> bool {
+        let now = Instant::now();
+        self.until.retain(|_, until| *until > now);
+        self.until
+            .contains_key(&(ip.to_canonical(), name.to_owned()))
+    }
+
+    pub fn insert(&mut self, ip: IpAddr, name: &str) {
+        if let Some(remember) = self.remember {
+            self.until.insert(
+                (ip.to_canonical(), name.to_owned()),
+                Instant::now() + remember,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use evdev::EventType;
+
+    use super::*;
+
+    #[test]
+    fn test_chord() {
+        let key = |key: KeyCode, value| InputEvent::new(EventType::KEY.0, key.0, value);
+        let mut chord = Chord::new([KeyCode::KEY_LEFTCTRL, KeyCode::KEY_A]);
+        assert!(!chord.event(&key(KeyCode::KEY_LEFTCTRL, 1)));
+        assert!(!chord.event(&key(KeyCode::KEY_B, 1)));
+        assert!(chord.event(&key(KeyCode::KEY_A, 1)));
+        assert!(!chord.event(&key(KeyCode::KEY_A, 2)));
+        assert!(!chord.event(&key(KeyCode::KEY_A, 0)));
+        assert!(chord.event(&key(KeyCode::KEY_A, 1)));
+        assert!(!chord.event(&key(KeyCode::KEY_LEFTCTRL, 0)));
+        assert!(!chord.event(&key(KeyCode::KEY_A, 0)));
+        assert!(!chord.event(&key(KeyCode::KEY_A, 1)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_approvals() {
+        let ip: IpAddr = "192.168.1.2".parse().unwrap();
+        let mut approvals = Approvals::new(Some(Duration::from_secs(60)));
+        assert!(!approvals.contains(ip, "laptop"));
+        approvals.insert(ip, "laptop");
+        assert!(approvals.contains(ip, "laptop"));
+        assert!(approvals.contains("::ffff:192.168.1.2".parse().unwrap(), "laptop"));
+        assert!(!approvals.contains(ip, "desktop"));
+        tokio::time::advance(Duration::from_secs(61)).await;
+        assert!(!approvals.contains(ip, "laptop"));
+
+        let mut approvals = Approvals::new(None);
+        approvals.insert(ip, "laptop");
+        assert!(!approvals.contains(ip, "laptop"));
+    }
+
+    #[tokio::test]
+    async fn test_verdict() {
+        let (a, b) = tokio::io::duplex(64);
+        let (mut a, mut b): (Stream, Stream) = (Box::new(a), Box::new(b));
+        send_verdict(&mut a, true).await.unwrap();
+        recv_verdict(&mut b).await.unwrap();
+        send_verdict(&mut a, false).await.unwrap();
+        assert!(recv_verdict(&mut b).await.is_err());
+    }
+}
@@ -1,26 +1,60 @@
-use std::collections::BTreeSet;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    task::Poll,
+    time::SystemTime,
+};
 
 use anyhow::Context;
 use evdev::{
-    AttributeSet, EventType, InputEvent, InputId, KeyCode, PropType, RelativeAxisCode,
-    SynchronizationCode, uinput::VirtualDevice,
+    EventSummary, EventType, InputEvent, InputId, KeyCode, LedCode, PropType, RelativeAxisCode,
+};
+use futures::{SinkExt, TryStreamExt, future::OptionFuture};
+use hid_over_ip::{
+    acl::Acl,
+    approval::{self, APPROVAL_TIMEOUT, Approvals, Chord},
+    codec::{Codec, Message, Packet},
+    device::{self, DeviceInfo},
+    discovery::Discovery,
+    handshake::{self, Features, Hello},
+    heartbeat::Heartbeat,
+    pairing,
+    relay::{Intro, Role},
+    transport::{self, Acceptor, Connection, Endpoint, Listener, Security, SideChannel},
+    uinput::VirtualDevice,
 };
-use futures::{TryFutureExt, TryStreamExt};
-use hid_over_ip::{codec::Codec, discovery::Discovery};
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio_util::codec::Framed;
 
 use crate::Cli;
 
 pub struct App<'a> {
     config: &'a Cli,
-    disc: &'a Discovery,
-    dev: VirtualDevice,
-    pressed_keys: BTreeSet<KeyCode>,
+    /// Unset when not listening on the network.
+    disc: Option<&'a Discovery>,
+    /// Device built from command-line options, used with peers that don't
+    /// describe their devices.
+    default_dev: VirtualDevice,
+    /// Devices built from the last received descriptors, indexed by remote
+    /// device id. Kept around while the descriptors stay the same, so that
+    /// reconnecting doesn't recreate them.
+    described: Vec<VirtualDevice>,
+    /// Whether the current connection uses `described`.
+    use_described: bool,
+    pressed_keys: BTreeSet<(u16, KeyCode)>,
+    /// Kept between connections for QUIC, since the endpoint outlives the
+    /// connections it accepted and can't be bound again while they're around.
+    listener: Option<Listener>,
+    security: Security,
+    acl: Acl,
+    approvals: Approvals,
 }
 
 impl<'a> App<'a> {
-    pub async fn run(config: &Cli, disc: &Discovery) -> anyhow::Result<()> {
+    pub async fn run(config: &Cli, disc: Option<&Discovery>) -> anyhow::Result<()> {
         let mut app = App::new(config, disc).context("Construct App")?;
+        if config.stdio {
+            return app.connect_loop().await;
+        }
         loop {
             if let Err(e) = app.connect_loop().await {
                 tracing::error!("{e:?}");
@@ -28,20 +62,92 @@ impl<'a> App<'a> {
         }
     }
 
-    fn new(config: &'a Cli, disc: &'a Discovery) -> anyhow::Result<Self> {
+    fn new(config: &'a Cli, disc: Option<&'a Discovery>) -> anyhow::Result<Self> {
+        let security = Security {
+            tls: config
+                .tls
+                .load(config.transport)
+                .context("Load TLS certificates")?,
+            psk: config
+                .psk
+                .load(config.transport)
+                .context("Load pre-shared key")?,
+            pairing: config
+                .pairing
+                .load(config.transport)
+                .context("Load identity")?,
+            relay: config.relay_secret.load().context("Load relay secret")?,
+        };
+        anyhow::ensure!(
+            config.relay.is_none() || security.relay.is_some(),
+            "--relay needs --relay-secret or --relay-secret-file"
+        );
         Ok(Self {
-            dev: {
-                let mut dev = builder(config)?.build().context("Build virtual device")?;
-                tracing::info!(
-                    path = %dev.get_syspath().context("Get device syspath")?.display(),
-                    "Created virtual device"
-                );
-                dev
-            },
+            default_dev: build(&default_info(config))?,
+            described: vec![],
+            use_described: false,
             config,
             disc,
             pressed_keys: BTreeSet::new(),
+            listener: None,
+            acl: config.acl(),
+            approvals: Approvals::new(config.approve_remember),
+            security,
+        })
+    }
+
+    fn dev(&self, id: u16) -> anyhow::Result<&VirtualDevice> {
+        if !self.use_described {
+            return Ok(&self.default_dev);
+        }
+        self.described
+            .get(usize::from(id))
+            .with_context(|| format!("Unknown device id {id}"))
+    }
+
+    /// Devices used by the current connection, with their ids.
+    fn devs(&self) -> impl Iterator<Item = (u16, &VirtualDevice)> {
+        let devs: Vec<_> = if self.use_described {
+            self.described.iter().collect()
+        } else {
+            vec![&self.default_dev]
+        };
+        (0..).zip(devs)
+    }
+
+    /// Waits for the next LED change on any of the current devices.
+    async fn next_led(&self) -> anyhow::Result<Packet> {
+        std::future::poll_fn(|cx| {
+            for (id, dev) in self.devs() {
+                while let Poll::Ready(evt) = dev.poll_event(cx) {
+                    let evt = evt.context("Read virtual device event")?;
+                    if evt.event_type() == EventType::LED {
+                        return Poll::Ready(Ok(Packet {
+                            device: id,
+                            events: vec![evt],
+                        }));
+                    }
+                }
+            }
+            Poll::Pending
         })
+        .await
+    }
+
+    fn use_descriptors(&mut self, infos: Vec<DeviceInfo>) -> anyhow::Result<()> {
+        let mut old = std::mem::take(&mut self.described);
+        // drop devices that went away first, so that they're not confused with
+        // new ones.
+        old.retain(|dev| infos.contains(dev.info()));
+        for info in infos {
+            let dev = match old.iter().position(|dev| *dev.info() == info) {
+                Some(idx) => old.swap_remove(idx),
+                None => build(&info)?,
+            };
+            self.described.push(dev);
+        }
+        self.use_described = true;
+        Ok(())
     }
 
     async fn connect_loop(&mut self) -> anyhow::Result<()> {
@@ -49,81 +155,370 @@ impl<'a> App<'a> {
 
         impl Drop for DropGuard<'_, '_> {
             fn drop(&mut self) {
-                if !self.0.pressed_keys.is_empty() {
-                    tracing::info!("Cleaning up stuck keys");
-                    let mut evts = Vec::with_capacity(self.0.pressed_keys.len());
-                    while let Some(key) = self.0.pressed_keys.pop_first() {
-                        evts.push(InputEvent::new(EventType::KEY.0, key.0, 0));
-                    }
-                    if let Err(e) = self.0.dev.emit(&evts) {
-                        tracing::error!("Error while cleaning up stuck keys: {e:?}")
-                    }
-                }
+                self.0.release_all();
             }
         }
 
         let drop = DropGuard(self);
         let this = &mut *drop.0;
-        let (tcp_stream, remote) = {
-            let listener = tokio::net::TcpListener::bind(&this.config.listen)
+        let mut conn = match (&this.config.connect, &this.config.relay) {
+            (Some(server), _) => this.dial(server).await?,
+            (None, Some(relay)) => this.dial(relay).await?,
+            (None, None) => this.accept().await?,
+        };
+        let remote = conn.remote.clone();
+        let mut hello = Hello::new(&this.config.name);
+        hello.features = this.config.transport.features();
+        let negotiated = handshake::accept(&mut conn.stream, &hello)
+            .await
+            .context("Handshake")?;
+        tracing::info!(
+            %remote,
+            peer_name = negotiated.peer_name,
+            features = ?negotiated.features,
+            "Handshake complete"
+        );
+        if let (Some(pairing), Some(peer)) = (&this.security.pairing, &conn.peer) {
+            pairing
+                .pair(
+                    &mut conn.stream,
+                    &negotiated.peer_name,
+                    peer,
+                    pairing::confirm_on_keyboards(),
+                )
+                .await?;
+        }
+        if negotiated.features.contains(Features::APPROVAL) {
+            let approved = this.approve(&remote, &negotiated.peer_name).await?;
+            approval::send_verdict(&mut conn.stream, approved).await?;
+            anyhow::ensure!(approved, "Connection from {remote} not approved");
+        } else if !this.config.approve_chord.is_empty() {
+            anyhow::bail!("Remote doesn't support connection approval");
+        }
+        this.use_described = false;
+        if negotiated.features.contains(Features::DEVICES) {
+            let infos = device::recv(&mut conn.stream)
                 .await
-                .context("Bind TCP listener")?;
-            tracing::info!(address = %this.config.listen, "Started listener");
-            tokio::try_join!(listener.accept().err_into(), this.disc.advertise())
-                .context("Listener accept/advertise")?
-                .0
+                .context("Receive device descriptors")?;
+            for info in &infos {
+                tracing::info!(name = info.name, input_id = ?info.input_id, "Remote device");
+            }
+            let infos = if negotiated.features.contains(Features::MULTIPLEX) {
+                infos
+            } else {
+                vec![DeviceInfo::merge(
+                    &this.config.name,
+                    input_id(this.config),
+                    &infos,
+                )]
+            };
+            this.use_descriptors(infos)
+                .context("Create devices from descriptors")?;
+        }
+        let features = negotiated.features;
+        let side = conn.side_channel(features).await?;
+        let mut framed = Framed::new(conn.stream, Codec::new(features));
+        let res = this.event_loop(&mut framed, side, features).await;
+        if let Err(e) = &res
+            && features.contains(Features::CONTROL)
+        {
+            // best effort, so that the server knows what happened.
+            let error = framed.send(Message::Error(format!("{e:#}")));
+            let _ = tokio::time::timeout(this.config.heartbeat_timeout, error).await;
+        }
+        if res.is_ok() {
+            tracing::info!(%remote, "Connection closed normally");
+        }
+        res
+    }
+
+    async fn accept(&mut self) -> anyhow::Result<Connection> {
+        let listener = match self.listener.take() {
+            Some(listener) => listener,
+            None => {
+                let endpoint = self.config.endpoint();
+                let listener = Listener::bind(self.config.transport, &endpoint, &self.security)
+                    .await
+                    .context("Bind listener")?;
+                tracing::info!(address = %endpoint, "Started listener");
+                listener
+            }
         };
-        tracing::info!(%remote, "Accepted remote connection");
-        let mut framed = Framed::new(tcp_stream, Codec);
+        let advertise = async {
+            match self.disc {
+                Some(disc) => disc.advertise().await,
+                None => Ok(()),
+            }
+        };
+        let conn = tokio::try_join!(listener.accept(&self.acl), advertise)
+            .context("Listener accept/advertise")?
+            .0;
+        if let Listener::Quic(_) = listener {
+            self.listener = Some(listener);
+        }
+        tracing::info!(remote = %conn.remote, "Accepted remote connection");
+        Ok(conn)
+    }
+
+    /// Connects to a `hoips --listen` or registers with a relay, retrying
+    /// until it's up, then waits for the server to switch to us. That can take
+    /// a while, so the handshake only starts once the server begins talking.
+    ///
+    /// Through a relay, the server is the one connecting as far as TLS and
+    /// Noise are concerned, so those handshakes only run once it's there.
+    async fn dial(&self, server: &Endpoint) -> anyhow::Result<Connection> {
+        let relay_secret = self.config.relay.as_ref().and(self.security.relay.as_ref());
+        let mut conn = loop {
+            let conn = async {
+                let Some(secret) = relay_secret else {
+                    return transport::connect(self.config.transport, server, &self.security).await;
+                };
+                let mut conn =
+                    transport::connect(self.config.transport, server, &Security::default()).await?;
+                let intro = Intro {
+                    role: Role::Client,
+                    name: self.config.name.clone(),
+                    encrypted: self.security.encrypted(),
+                };
+                intro.write(&mut conn.stream, secret).await?;
+                anyhow::Ok(conn)
+            };
+            match conn.await {
+                Ok(conn) => break conn,
+                Err(e) => {
+                    tracing::warn!(
+                        %server,
+                        retry_in = ?self.config.reconnect_interval,
+                        "Couldn't connect to server: {e:#}"
+                    );
+                    tokio::time::sleep(self.config.reconnect_interval).await;
+                }
+            }
+        };
+        tracing::info!(%server, "Connected to server, waiting for it to switch to us");
+        let mut stream = BufReader::new(conn.stream);
+        let pending = stream.fill_buf().await.context("Wait for server")?;
+        anyhow::ensure!(!pending.is_empty(), "Server closed the connection");
+        conn.stream = Box::new(stream);
+        if relay_secret.is_some() {
+            conn = Acceptor::new(&self.security)?.secure(conn).await?;
+        }
+        Ok(conn)
+    }
+
+    /// Asks the local operator to approve the connection, unless approval
+    /// isn't required or the peer was approved recently.
+    async fn approve(&mut self, remote: &Endpoint, peer_name: &str) -> anyhow::Result<bool> {
+        let keys = &self.config.approve_chord;
+        if keys.is_empty() {
+            return Ok(true);
+        }
+        // only peers with an address are remembered.
+        let ip = remote.addr().map(|addr| addr.ip());
+        if ip.is_some_and(|ip| self.approvals.contains(ip, peer_name)) {
+            tracing::info!(%remote, peer_name, "Connection approved earlier");
+            return Ok(true);
+        }
+        tracing::warn!(
+            %remote,
+            peer_name,
+            chord = ?keys,
+            "Connection pending. Press the chord on a local keyboard to approve"
+        );
+        match tokio::time::timeout(APPROVAL_TIMEOUT, Chord::new(keys.iter().copied()).wait()).await
+        {
+            Ok(res) => res.context("Approve connection")?,
+            Err(_) => {
+                tracing::warn!(%remote, "Connection not approved in time");
+                return Ok(false);
+            }
+        }
+        tracing::info!(%remote, peer_name, "Connection approved");
+        if let Some(ip) = ip {
+            self.approvals.insert(ip, peer_name);
+        }
+        Ok(true)
+    }
+
+    async fn event_loop(
+        &mut self,
+        framed: &mut Framed<transport::Stream, Codec>,
+        mut side: Option<SideChannel>,
+        features: Features,
+    ) -> anyhow::Result<()> {
+        let timestamps = features.contains(Features::TIMESTAMPS);
+        let leds = features.contains(Features::LEDS);
+        let heartbeats = features.contains(Features::HEARTBEAT);
+        let mut heartbeat = Heartbeat::new(
+            self.config.heartbeat_interval,
+            self.config.heartbeat_timeout,
+        );
+        if leds {
+            for (id, dev) in self.devs() {
+                let events = dev.led_state();
+                if !events.is_empty() {
+                    framed
+                        .feed(Packet { device: id, events }.into())
+                        .await
+                        .context("Send LED state")?;
+                }
+            }
+            framed.flush().await.context("Send LED state")?;
+        }
         tracing::info!("Starting event loop");
-        let mut buf = Vec::with_capacity(16);
-        while let Some(next) = framed.try_next().await.context("Get next data frame")? {
-            match next.destructure() {
-                evdev::EventSummary::Key(_, key_code, value) => {
-                    if matches!(value, 0) {
-                        this.pressed_keys.remove(&key_code);
-                    } else {
-                        this.pressed_keys.insert(key_code);
+        loop {
+            let msg = tokio::select! {
+                msg = framed.try_next() => msg.context("Get next data frame")?,
+                Some(packet) = OptionFuture::from(side.as_mut().map(SideChannel::recv)) => {
+                    Some(Message::Packet(packet.context("Get next datagram")?))
+                }
+                led = self.next_led(), if leds => {
+                    let led = led?;
+                    tracing::debug!(device = led.device, events = ?led.events, "LED change");
+                    framed.send(led.into()).await.context("Send LED change")?;
+                    continue;
+                }
+                token = heartbeat.tick(), if heartbeats => {
+                    framed.send(Message::Ping(token?)).await.context("Send ping")?;
+                    continue;
+                }
+            };
+            heartbeat.received();
+            let mut packet = match msg {
+                Some(Message::Packet(packet)) => packet,
+                Some(Message::Ping(token)) => {
+                    framed
+                        .send(Message::Pong(token))
+                        .await
+                        .context("Send pong")?;
+                    continue;
+                }
+                Some(Message::Pong(token)) => {
+                    if let Some(rtt) = heartbeat.pong(token) {
+                        tracing::trace!(?rtt, "Round trip time");
                     }
+                    continue;
                 }
-                evdev::EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, 0) => {
-                    this.dev.emit(&buf).context("Emit events")?;
-                    buf.clear();
+                Some(Message::ReleaseAll) => {
+                    self.release_all();
                     continue;
                 }
-                _ => (),
+                Some(Message::GrabState(grabbed)) => {
+                    tracing::info!(grabbed, "Remote grab state changed");
+                    if !grabbed {
+                        self.release_all();
+                    }
+                    continue;
+                }
+                Some(Message::Goodbye(reason)) => {
+                    tracing::info!(reason, "Remote said goodbye");
+                    return Ok(());
+                }
+                Some(Message::Error(reason)) => {
+                    anyhow::bail!("Remote failed: {reason}");
+                }
+                None => return Ok(()),
+            };
+            for evt in &packet.events {
+                if let EventSummary::Key(_, key_code, value) = evt.destructure() {
+                    if matches!(value, 0) {
+                        self.pressed_keys.remove(&(packet.device, key_code));
+                    } else {
+                        self.pressed_keys.insert((packet.device, key_code));
+                    }
+                }
+            }
+            if timestamps && self.is_stale(&packet) {
+                tracing::debug!(
+                    events = packet.events.len(),
+                    "Dropping stale pointer motion"
+                );
+                continue;
             }
-            buf.push(next);
+            let dev = self.dev(packet.device)?;
+            let total = packet.events.len();
+            packet.events.retain(|evt| dev.supports(evt));
+            if packet.events.len() < total {
+                tracing::debug!(
+                    dropped = total - packet.events.len(),
+                    "Dropping events the device doesn't declare"
+                );
+            }
+            dev.emit(&packet.events).context("Emit events")?;
         }
-        tracing::info!(%remote, "Connection closed normally");
-        anyhow::Ok(())
+    }
+
+    /// Releases all keys and buttons the remote left pressed.
+    fn release_all(&mut self) {
+        if self.pressed_keys.is_empty() {
+            return;
+        }
+        tracing::info!("Cleaning up stuck keys");
+        let mut evts = BTreeMap::<u16, Vec<_>>::new();
+        while let Some((id, key)) = self.pressed_keys.pop_first() {
+            evts.entry(id)
+                .or_default()
+                .push(InputEvent::new(EventType::KEY.0, key.0, 0));
+        }
+        for (id, evts) in evts {
+            if let Err(e) = self.dev(id).and_then(|dev| Ok(dev.emit(&evts)?)) {
+                tracing::error!("Error while cleaning up stuck keys: {e:?}")
+            }
+        }
+    }
+
+    /// Whether the packet is only relative motion and arrived later than
+    /// `--max-event-age`.
+    fn is_stale(&self, packet: &Packet) -> bool {
+        let Some(time) = packet.timestamp() else {
+            return false;
+        };
+        let latency = SystemTime::now().duration_since(time).unwrap_or_default();
+        tracing::trace!(?latency, "Packet latency");
+        self.config.max_event_age.is_some_and(|max| latency > max)
+            && packet
+                .events
+                .iter()
+                .all(|evt| matches!(evt.destructure(), EventSummary::RelativeAxis(..)))
     }
 }
 
-fn builder(config: &Cli) -> anyhow::Result<evdev::uinput::VirtualDeviceBuilder<'_>> {
-    // TODO: absolute, force feedback, switches
-    anyhow::Ok(
-        VirtualDevice::builder()
-            .context("Construct device builder")?
-            .name(&config.name)
-            .input_id(InputId::new(
-                config.bus,
-                config.vendor_id,
-                config.product_id,
-                config.product_version,
-            ))
-            .with_properties(&AttributeSet::from_iter([PropType::POINTER]))
-            .context("Set device properties")?
-            .with_keys(&AttributeSet::from_iter((0..560).map(KeyCode)))
-            .context("Set device keys")?
-            .with_relative_axes(&AttributeSet::from_iter(
-                (if config.no_high_res_scroll {
-                    0..=10
-                } else {
-                    0..=12
-                })
-                .map(RelativeAxisCode),
-            ))
-            .context("Set device relative axes")?,
+fn input_id(config: &Cli) -> InputId {
+    InputId::new(
+        config.bus,
+        config.vendor_id,
+        config.product_id,
+        config.product_version,
     )
 }
+
+fn build(info: &DeviceInfo) -> anyhow::Result<VirtualDevice> {
+    let dev = VirtualDevice::new(info).context("Build virtual device")?;
+    tracing::info!(
+        path = %dev.syspath().context("Get device syspath")?.display(),
+        "Created virtual device"
+    );
+    Ok(dev)
+}
+
+fn default_info(config: &Cli) -> DeviceInfo {
+    DeviceInfo {
+        name: config.name.clone(),
+        input_id: input_id(config),
+        properties: vec![PropType::POINTER],
+        keys: (0..560).map(KeyCode).collect(),
+        relative_axes: (if config.no_high_res_scroll {
+            0..=10
+        } else {
+            0..=12
+        })
+        .map(RelativeAxisCode)
+        .collect(),
+        absolute_axes: vec![],
+        switches: vec![],
+        leds: (LedCode::LED_NUML.0..=LedCode::LED_KANA.0)
+            .map(LedCode)
+            .collect(),
+        misc: vec![],
+    }
+}
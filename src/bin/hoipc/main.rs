@@ -1,15 +1,25 @@
 mod app;
 
-use std::{net::SocketAddr, process::ExitCode};
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode, time::Duration};
 
 use anyhow::Context;
 use clap::Parser;
-use evdev::BusType;
+use evdev::{BusType, KeyCode};
+use futures::future::OptionFuture;
 use hid_over_ip::{
-    discovery::{DEFAULT_MULTICAST_SOCKET_V4, Discovery},
-    init_logging,
+    acl::{Acl, Rule},
+    discovery::{DEFAULT_MULTICAST_SOCKET_V4, Discovery, Interface, PeerInfo, SecretArgs},
+    init_logging, mdns,
+    noise::PskArgs,
+    pairing::PairingArgs,
+    relay,
+    tls::TlsArgs,
+    transport::{Endpoint, Transport},
 };
 
+const DEFAULT_LISTEN: &str = "[::]:27056";
+const DEFAULT_LISTEN_QUIC: &str = "[::]:27057";
+
 /// HoIP -- HID-over-IP. Share keyboard and mouse (or other HID inputs) over
 /// TCP/IP.
 ///
@@ -20,9 +30,67 @@ use hid_over_ip::{
 struct Cli {
     /// Address/port to listen on. `0.0.0.0` is any v4 address, `[::]` is
     /// usually any address, v4 or v6 (but depends on `net.ipv6.bindv6only`
-    /// sysctl)
-    #[arg(long, short, default_value = "[::]:27056")]
+    /// sysctl). With `--transport quic`, the default port is 27057 instead,
+    /// as QUIC runs over UDP and discovery uses 27056.
+    #[arg(long, short, default_value = DEFAULT_LISTEN)]
     listen: SocketAddr,
+    /// Listen on this Unix domain socket instead of `--listen`. Disables
+    /// discovery.
+    #[arg(long)]
+    listen_unix: Option<PathBuf>,
+    /// Talk to a single server over standard input and output instead of
+    /// listening, then exit, e.g. when run by `hoips --connect 'exec:ssh host
+    /// hoipc --stdio'`. Disables discovery.
+    #[arg(long, conflicts_with = "listen_unix")]
+    stdio: bool,
+    /// Connect out to a `hoips --listen` instead of listening, for when this
+    /// machine is behind NAT or a firewall. The connection is kept open until
+    /// the server switches to us, and reopened after. Same format as `hoips
+    /// --connect`. Disables discovery. Only works with TCP and WebSocket
+    /// transports.
+    #[arg(long, conflicts_with_all = ["listen_unix", "stdio"])]
+    connect: Option<Endpoint>,
+    /// Wait for servers at a `hoipr` relay instead of listening, registered
+    /// under `--name`. Servers reach us with `hoips --connect
+    /// relay:HOST:PORT/NAME`. Needs `--relay-secret`. Disables discovery. Only
+    /// works with the TCP transport.
+    #[arg(long, conflicts_with_all = ["listen_unix", "stdio", "connect"])]
+    relay: Option<Endpoint>,
+    /// How long to wait before trying again when `--connect` or `--relay`
+    /// fails.
+    #[arg(long, default_value = "2s", value_parser = humantime::parse_duration)]
+    reconnect_interval: Duration,
+    /// Transport for input events. Both sides have to use the same one.
+    #[arg(long, value_enum, default_value_t)]
+    transport: Transport,
+    #[command(flatten)]
+    tls: TlsArgs,
+    #[command(flatten)]
+    psk: PskArgs,
+    #[command(flatten)]
+    pairing: PairingArgs,
+    #[command(flatten)]
+    relay_secret: relay::RelaySecretArgs,
+    /// Only accept servers from this address or network, e.g. `192.168.1.10`,
+    /// `192.168.1.0/24`, or `fe80::/10%eth0` for link-local IPv6 on a given
+    /// interface. Can be given multiple times. Discovery requests from other
+    /// addresses are ignored as well.
+    #[arg(long)]
+    allow: Vec<Rule>,
+    /// Never accept servers from this address or network, even if `--allow`
+    /// matches. Same format as `--allow`, can be given multiple times.
+    #[arg(long)]
+    deny: Vec<Rule>,
+    /// Hold new connections until this key chord is pressed on a keyboard
+    /// physically attached to this machine. Give each key separately, e.g.
+    /// `--approve-chord KEY_LEFTCTRL --approve-chord KEY_ENTER`. Servers that
+    /// don't support waiting for approval are refused.
+    #[arg(long)]
+    approve_chord: Vec<KeyCode>,
+    /// Don't ask again for a server approved within this long, identified by
+    /// its address and name.
+    #[arg(long, requires = "approve_chord", value_parser = humantime::parse_duration)]
+    approve_remember: Option<Duration>,
     /// Name of the virtual device.
     #[arg(long, short, default_value = "hoipc")]
     name: String,
@@ -41,16 +109,68 @@ struct Cli {
     /// Disable high-resolution scrolling events in the device description.
     #[arg(long)]
     no_high_res_scroll: bool,
+    /// Drop pointer motion that is older than this by the time it arrives.
+    /// Only effective if the server sends event timestamps. Key and button
+    /// events are never dropped.
+    #[arg(long, value_parser = humantime::parse_duration)]
+    max_event_age: Option<Duration>,
+    /// How often to send heartbeats, if the peer supports them.
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    heartbeat_interval: Duration,
+    /// Drop the connection if nothing is received from the peer for this long.
+    /// Only effective if the peer supports heartbeats.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    heartbeat_timeout: Duration,
     /// What multicast address to use for peer discovery. If listen address is a
     /// V6-only address, and this is not, will default to a V6 multicast
     /// address.
     #[arg(long, default_value = DEFAULT_MULTICAST_SOCKET_V4)]
     discovery_multicast: SocketAddr,
-    /// Which network interface to run discovery on. If unspecified, will try to
-    /// choose based on listen address if possible. Only used for IPv6
-    /// multicast.
-    #[arg(long)]
-    discovery_ifname: Option<String>,
+    /// Which network interfaces to run discovery on, comma-separated or
+    /// repeated. If unspecified, will try to choose based on listen address if
+    /// possible, which only works for IPv6 multicast.
+    #[arg(long, value_delimiter = ',')]
+    discovery_ifname: Vec<String>,
+    /// Run discovery on every multicast-capable interface that's up, except
+    /// loopback.
+    #[arg(long, conflicts_with = "discovery_ifname")]
+    discovery_all_interfaces: bool,
+    #[command(flatten)]
+    discovery_secret: SecretArgs,
+    /// Also advertise as a `_hoip._tcp` DNS-SD service over mDNS, for `hoips
+    /// --mdns` and tools like `avahi-browse`. mDNS can't be authenticated, so
+    /// this doesn't go with `--discovery-secret`.
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "listen_unix",
+            "stdio",
+            "connect",
+            "relay",
+            "discovery_secret",
+            "discovery_secret_file",
+        ]
+    )]
+    mdns: bool,
+}
+
+impl Cli {
+    fn endpoint(&self) -> Endpoint {
+        if self.stdio {
+            Endpoint::Stdio
+        } else if let Some(path) = &self.listen_unix {
+            Endpoint::Unix(path.clone())
+        } else {
+            Endpoint::Addr(self.listen)
+        }
+    }
+
+    fn acl(&self) -> Acl {
+        Acl {
+            allow: self.allow.clone(),
+            deny: self.deny.clone(),
+        }
+    }
 }
 
 #[tokio::main]
@@ -67,22 +187,91 @@ async fn main() -> ExitCode {
 }
 
 async fn main_imp(mut config: Cli) -> anyhow::Result<()> {
+    if config.tls.generate()? {
+        return Ok(());
+    }
+
     let ctrl_c = tokio::signal::ctrl_c();
 
+    if config.transport == Transport::Quic && config.listen == DEFAULT_LISTEN.parse()? {
+        config.listen = DEFAULT_LISTEN_QUIC.parse()?;
+    }
+
     hid_over_ip::fix_socket_addr_iface(
         &mut config.listen,
         &mut config.discovery_multicast,
-        config.discovery_ifname.as_deref(),
+        config.discovery_ifname.first().map(String::as_str),
         false,
     )?;
 
-    let disc = Discovery::new(config.discovery_multicast, config.listen)
-        .await
-        .context("Bind discovery")?;
+    if config.transport == Transport::Quic
+        && config.connect.is_none()
+        && config.relay.is_none()
+        && config.listen.port() == config.discovery_multicast.port()
+    {
+        anyhow::bail!(
+            "QUIC listens on UDP port {}, which is already used for discovery. \
+            Use a different port with --listen",
+            config.listen.port()
+        );
+    }
+
+    // nothing to advertise unless listening on the network.
+    anyhow::ensure!(
+        config.connect.is_none() || config.transport.is_stream(),
+        "Only TCP and WebSocket transports work with --connect"
+    );
+    anyhow::ensure!(
+        config.relay.is_none() || config.transport == Transport::Tcp,
+        "Only the TCP transport works with --relay"
+    );
+
+    let listen = match config.endpoint() {
+        Endpoint::Addr(listen) if config.connect.is_none() && config.relay.is_none() => {
+            Some(listen)
+        }
+        _ => None,
+    };
+    let info = PeerInfo::local(&config.name, config.listen.port(), config.transport);
+    let disc = match listen {
+        Some(listen) => Some(
+            Discovery::new(
+                config.discovery_multicast,
+                listen,
+                info.clone(),
+                config
+                    .discovery_secret
+                    .load()
+                    .context("Load discovery secret")?,
+                Interface::select(&config.discovery_ifname, config.discovery_all_interfaces)
+                    .context("Select discovery interfaces")?,
+            )
+            .await
+            .context("Bind discovery")?,
+        ),
+        None => None,
+    };
+    let acl = config.acl();
+    let mdns = listen
+        .filter(|_| config.mdns)
+        .map(|listen| mdns::advertise(&info, listen, &acl));
 
     tokio::select! {
         _ = ctrl_c => Ok(()),
-        res = disc.respond() => res,
-        res = app::App::run(&config, &disc) => res,
+        Some(res) = OptionFuture::from(disc.as_ref().map(|disc| disc.respond(&acl))) => res,
+        Some(res) = OptionFuture::from(mdns) => res.map(|never| match never {}),
+        res = app::App::run(&config, disc.as_ref()) => res,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
     }
 }
@@ -0,0 +1,236 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    process::ExitCode,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Context;
+use clap::Parser;
+use hid_over_ip::{
+    acl::{Acl, Rule},
+    handshake::HANDSHAKE_TIMEOUT,
+    init_logging,
+    relay::{self, Intro, RelaySecretArgs, Role, Secret},
+    transport::{Connection, Endpoint, Listener, Security, Stream, Transport},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    sync::oneshot,
+};
+
+/// HoIP -- HID-over-IP. Share keyboard and mouse (or other HID inputs) over
+/// TCP/IP.
+///
+/// HoIP relay. Both "servers" and "clients" connect to it, with `hoips
+/// --connect relay:HOST:PORT/CLIENT` and `hoipc --relay HOST:PORT`
+/// respectively, and it forwards sessions between them. For machines that
+/// can't reach each other directly. Both have to know the `--relay-secret`.
+#[derive(clap::Parser)]
+#[command(version)]
+struct Cli {
+    /// Address/port to listen on.
+    #[arg(long, short, default_value = "[::]:27058")]
+    listen: SocketAddr,
+    /// Only accept servers and clients from this address or network. Same
+    /// format as `hoipc --allow`, can be given multiple times.
+    #[arg(long)]
+    allow: Vec<Rule>,
+    /// Never accept servers and clients from this address or network, even if
+    /// `--allow` matches.
+    #[arg(long)]
+    deny: Vec<Rule>,
+    #[command(flatten)]
+    secret: RelaySecretArgs,
+}
+
+/// Clients waiting for a server, by name: where to ask for their connection,
+/// and whether their sessions are encrypted.
+type Clients = Arc<Mutex<HashMap<String, (oneshot::Sender<Handoff>, bool)>>>;
+/// Where a waiting client hands its connection to the server that asked.
+type Handoff = oneshot::Sender<Stream>;
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    init_logging();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => ExitCode::SUCCESS,
+        res = imp(Cli::parse()) => match res {
+            Ok(()) => ExitCode::SUCCESS,
+            Err(error) => {
+                tracing::error!("{error:?}");
+                ExitCode::FAILURE
+            },
+        },
+    }
+}
+
+async fn imp(config: Cli) -> anyhow::Result<()> {
+    let secret = config
+        .secret
+        .load()?
+        .context("The relay needs --relay-secret or --relay-secret-file")?;
+    let listener = Listener::bind(
+        Transport::Tcp,
+        &Endpoint::Addr(config.listen),
+        &Security::default(),
+    )
+    .await
+    .context("Bind listener")?;
+    tracing::info!(address = %config.listen, "Started listener");
+    let acl = Acl {
+        allow: config.allow,
+        deny: config.deny,
+    };
+    let clients = Clients::default();
+    loop {
+        let conn = listener.accept(&acl).await?;
+        let (clients, secret) = (clients.clone(), secret.clone());
+        tokio::spawn(async move {
+            let remote = conn.remote.clone();
+            if let Err(e) = handle(conn, &clients, &secret).await {
+                tracing::error!(%remote, "{e:?}");
+            }
+        });
+    }
+}
+
+async fn handle(mut conn: Connection, clients: &Clients, secret: &Secret) -> anyhow::Result<()> {
+    let remote = conn.remote;
+    let Intro {
+        role,
+        name,
+        encrypted,
+    } = tokio::time::timeout(HANDSHAKE_TIMEOUT, Intro::read(&mut conn.stream, secret))
+        .await
+        .context("Timed out waiting for intro")??;
+    match role {
+        Role::Client => {
+            tracing::info!(%remote, name, encrypted, "Client waiting");
+            let (offer, asked) = oneshot::channel();
+            // a client reconnecting leaves its old connection behind.
+            if clients
+                .lock()
+                .unwrap()
+                .insert(name, (offer, encrypted))
+                .is_some()
+            {
+                tracing::info!(%remote, "Replaced earlier connection");
+            }
+            if wait(conn.stream, asked, clients).await? {
+                tracing::info!(%remote, "Client left before a server connected");
+            }
+        }
+        Role::Server => {
+            let client = claim(clients, &name).await;
+            conn.stream
+                .write_u8(client.is_some().into())
+                .await
+                .context("Send relay answer")?;
+            conn.stream.flush().await.context("Send relay answer")?;
+            let (client, client_encrypted) =
+                client.with_context(|| format!("No client named {name:?}"))?;
+            tracing::info!(%remote, client = name, encrypted, "Server connected");
+            // if only one side encrypts, its handshake fails on its own.
+            if encrypted || client_encrypted {
+                relay::forward(conn.stream, client).await?;
+            } else {
+                relay::splice(conn.stream, client).await?;
+            }
+            tracing::info!(%remote, client = name, "Session ended");
+        }
+    }
+    Ok(())
+}
+
+/// Hands a waiting client's `stream` to the server that `asked` for it, or
+/// drops it once a newer connection replaced it. True if the client went
+/// away first, which takes it off `clients` too.
+async fn wait(
+    mut stream: Stream,
+    asked: oneshot::Receiver<Handoff>,
+    clients: &Clients,
+) -> anyhow::Result<bool> {
+    // clients don't speak until the server does, so all a read can see is
+    // them leaving.
+    let read = tokio::select! {
+        biased;
+        handoff = asked => {
+            if let Ok(handoff) = handoff {
+                let _ = handoff.send(stream);
+            }
+            return Ok(false);
+        }
+        read = stream.read_u8() => read,
+    };
+    clients
+        .lock()
+        .unwrap()
+        .retain(|_, (offer, _)| !offer.is_closed());
+    match read {
+        Ok(_) => anyhow::bail!("Client sent data before a server connected"),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(true),
+        Err(e) => Err(e).context("Wait for a server"),
+    }
+}
+
+/// Takes the connection of the client waiting as `name`, if it's still there.
+async fn claim(clients: &Clients, name: &str) -> Option<(Stream, bool)> {
+    let (offer, encrypted) = clients.lock().unwrap().remove(name)?;
+    let (handoff, handed) = oneshot::channel();
+    offer.send(handoff).ok()?;
+    Some((handed.await.ok()?, encrypted))
+}
+
+#[cfg(test)]
+mod test {
+    use clap::CommandFactory;
+    use tokio::{io::DuplexStream, task::JoinHandle};
+
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
+
+    /// A client waiting as `desk`.
+    fn waiting(
+        clients: &Clients,
+        encrypted: bool,
+    ) -> (DuplexStream, JoinHandle<anyhow::Result<bool>>) {
+        let (client, stream) = tokio::io::duplex(64);
+        let (offer, asked) = oneshot::channel();
+        clients
+            .lock()
+            .unwrap()
+            .insert("desk".to_owned(), (offer, encrypted));
+        let clients = clients.clone();
+        let waiting = tokio::spawn(async move { wait(Box::new(stream), asked, &clients).await });
+        (client, waiting)
+    }
+
+    #[tokio::test]
+    async fn test_client_left() {
+        let clients = Clients::default();
+        let (client, waiting) = waiting(&clients, false);
+        drop(client);
+        assert!(waiting.await.unwrap().unwrap());
+        assert!(clients.lock().unwrap().is_empty());
+        assert!(claim(&clients, "desk").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_claim() {
+        let clients = Clients::default();
+        let (mut client, waiting) = waiting(&clients, true);
+        let (mut stream, encrypted) = claim(&clients, "desk").await.unwrap();
+        assert!(encrypted);
+        assert!(!waiting.await.unwrap().unwrap());
+        stream.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+    }
+}
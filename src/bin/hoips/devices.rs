@@ -0,0 +1,107 @@
+use std::{
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+};
+
+use evdev::{
+    AttributeSet, Device, EventStream, EventSummary, EventType, InputEvent, LedCode,
+    SynchronizationCode,
+};
+use futures::{Stream, stream::SelectAll};
+use hid_over_ip::codec::Packet;
+
+pub type UdevStream = futures::stream::ErrInto<SelectAll<DeviceStream>, anyhow::Error>;
+
+/// Events from a single device, grouped into packets tagged with the device's
+/// index.
+pub struct DeviceStream {
+    stream: EventStream,
+    packet: Packet,
+    /// Host's LED state from before the grab, restored on ungrab.
+    leds: Option<AttributeSet<LedCode>>,
+}
+
+impl DeviceStream {
+    pub fn new(id: u16, stream: EventStream) -> Self {
+        Self {
+            stream,
+            packet: Packet {
+                device: id,
+                events: vec![],
+            },
+            leds: None,
+        }
+    }
+
+    pub fn id(&self) -> u16 {
+        self.packet.device
+    }
+
+    pub fn device(&self) -> &Device {
+        self.stream.device()
+    }
+
+    pub fn device_mut(&mut self) -> &mut Device {
+        self.stream.device_mut()
+    }
+
+    pub fn grab(&mut self) -> io::Result<()> {
+        if self.device().supported_leds().is_some() {
+            self.leds = Some(self.device().get_led_state()?);
+        }
+        self.device_mut().grab()
+    }
+
+    pub fn ungrab(&mut self) -> io::Result<()> {
+        self.device_mut().ungrab()?;
+        if let Some(leds) = self.leds.take()
+            && let Some(supported) = self.device().supported_leds()
+        {
+            let events: Vec<_> = supported
+                .iter()
+                .map(|led| InputEvent::new(EventType::LED.0, led.0, leds.contains(led).into()))
+                .collect();
+            self.device_mut().send_events(&events)?;
+        }
+        Ok(())
+    }
+
+    /// Applies LED events received from the remote, if the device has LEDs.
+    pub fn set_leds(&mut self, events: &[InputEvent]) -> io::Result<()> {
+        let Some(supported) = self.device().supported_leds() else {
+            return Ok(());
+        };
+        let events: Vec<_> = events
+            .iter()
+            .filter(|evt| {
+                evt.event_type() == EventType::LED && supported.contains(LedCode(evt.code()))
+            })
+            .copied()
+            .collect();
+        if events.is_empty() {
+            return Ok(());
+        }
+        self.device_mut().send_events(&events)
+    }
+}
+
+impl Stream for DeviceStream {
+    type Item = io::Result<Packet>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let evt = ready!(self.stream.poll_event(cx))?;
+            if let EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, 0) =
+                evt.destructure()
+            {
+                let packet = Packet {
+                    device: self.packet.device,
+                    events: std::mem::take(&mut self.packet.events),
+                };
+                return Poll::Ready(Some(Ok(packet)));
+            }
+            self.packet.events.push(evt);
+        }
+    }
+}
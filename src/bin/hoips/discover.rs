@@ -0,0 +1,202 @@
+use std::{
+    collections::{BTreeMap, btree_map::Entry},
+    fmt::Write,
+    net::SocketAddr,
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use futures::TryStreamExt;
+use hid_over_ip::discovery::{Discovered, PeerInfo};
+use tokio::time::Instant;
+
+use crate::Source;
+
+/// A client that answered, with its fastest answer.
+struct Found {
+    peer: Discovered,
+    latency: Duration,
+}
+
+/// Sends requests every `period` for `duration`, then prints every client
+/// that answered, without connecting to any.
+pub async fn list(
+    source: &Source,
+    duration: Duration,
+    period: Duration,
+    json: bool,
+) -> anyhow::Result<()> {
+    let mut found = BTreeMap::<SocketAddr, Found>::new();
+    let deadline = tokio::time::sleep(duration);
+    let mut deadline = std::pin::pin!(deadline);
+    let mut discovered = std::pin::pin!(source.discovered());
+    let mut interval = tokio::time::interval(period);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    let mut last_sent = None;
+    loop {
+        tokio::select! {
+            _ = &mut deadline => break,
+            _ = interval.tick() => {
+                last_sent = Some(Instant::now());
+                source.request().await?;
+            }
+            peer = discovered.try_next() => {
+                let peer = peer?.context("Discovery stopped")?;
+                // without a signed response, there's no telling which request
+                // it answers; most likely the latest, even if earlier ones got
+                // lost.
+                let Some(sent) = peer.requested.or(last_sent) else {
+                    continue;
+                };
+                let latency = sent.elapsed();
+                match found.entry(peer.addr) {
+                    Entry::Vacant(entry) => {
+                        entry.insert(Found { peer, latency });
+                    }
+                    Entry::Occupied(mut entry) => {
+                        let entry = entry.get_mut();
+                        entry.latency = entry.latency.min(latency);
+                        // answers in the legacy format carry nothing.
+                        if entry.peer.info == PeerInfo::default() {
+                            entry.peer.info = peer.info;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    if found.is_empty() {
+        tracing::warn!("No clients answered");
+    }
+    let found: Vec<_> = found.into_values().collect();
+    if json {
+        println!("{}", to_json(&found));
+    } else {
+        print!("{}", to_text(&found));
+    }
+    Ok(())
+}
+
+fn transports(info: &PeerInfo) -> Vec<String> {
+    info.transports
+        .iter()
+        .filter_map(|t| t.to_possible_value())
+        .map(|t| t.get_name().to_owned())
+        .collect()
+}
+
+fn to_text(found: &[Found]) -> String {
+    let mut out = String::new();
+    for Found { peer, latency } in found {
+        let info = &peer.info;
+        write!(
+            out,
+            "{}\t{}\t{latency:.1?}",
+            peer.addr,
+            peer.interface.as_deref().unwrap_or("-")
+        )
+        .unwrap();
+        if let Some(name) = &info.name {
+            write!(out, "\tname={}", name.escape_debug()).unwrap();
+        }
+        if let Some(hostname) = &info.hostname {
+            write!(out, "\thostname={}", hostname.escape_debug()).unwrap();
+        }
+        if let Some(version) = info.version {
+            write!(out, "\tversion={version}").unwrap();
+        }
+        if !info.transports.is_empty() {
+            write!(out, "\ttransports={}", transports(info).join(",")).unwrap();
+        }
+        if info.instance != 0 {
+            write!(out, "\tinstance={:016x}", info.instance).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// How each client is listed with `--json`.
+#[derive(serde::Serialize)]
+struct JsonEntry<'a> {
+    address: String,
+    interface: Option<&'a str>,
+    latency_ms: f64,
+    name: Option<&'a str>,
+    hostname: Option<&'a str>,
+    version: Option<u16>,
+    transports: Vec<String>,
+    instance: Option<String>,
+}
+
+fn to_json(found: &[Found]) -> String {
+    let entries: Vec<_> = found
+        .iter()
+        .map(|found| {
+            let info = &found.peer.info;
+            JsonEntry {
+                address: found.peer.addr.to_string(),
+                interface: found.peer.interface.as_deref(),
+                latency_ms: found.latency.as_secs_f64() * 1000.0,
+                name: info.name.as_deref(),
+                hostname: info.hostname.as_deref(),
+                version: info.version,
+                transports: transports(info),
+                instance: (info.instance != 0).then(|| format!("{:016x}", info.instance)),
+            }
+        })
+        .collect();
+    serde_json::to_string(&entries).expect("serializing to a string can't fail")
+}
+
+#[cfg(test)]
+mod test {
+    use hid_over_ip::transport::Transport;
+
+    use super::*;
+
+    #[test]
+    fn test_output() {
+        let found = [
+            Found {
+                peer: Discovered {
+                    addr: "192.168.1.2:27056".parse().unwrap(),
+                    interface: Some("eth0".to_owned()),
+                    info: PeerInfo {
+                        hostname: Some("desk".to_owned()),
+                        name: Some("say \"hi\"\n".to_owned()),
+                        instance: 0xff,
+                        version: Some(1),
+                        transports: vec![Transport::Tcp, Transport::Quic],
+                    },
+                    requested: None,
+                },
+                latency: Duration::from_micros(1500),
+            },
+            Found {
+                peer: Discovered {
+                    addr: "192.168.1.3:27056".parse().unwrap(),
+                    interface: None,
+                    info: PeerInfo::default(),
+                    requested: None,
+                },
+                latency: Duration::from_millis(2),
+            },
+        ];
+        assert_eq!(
+            to_text(&found),
+            "192.168.1.2:27056\teth0\t1.5ms\tname=say \\\"hi\\\"\\n\thostname=desk\tversion=1\t\
+            transports=tcp,quic\tinstance=00000000000000ff\n\
+            192.168.1.3:27056\t-\t2.0ms\n"
+        );
+        assert_eq!(
+            to_json(&found),
+            "[{\"address\":\"192.168.1.2:27056\",\"interface\":\"eth0\",\"latency_ms\":1.5,\
+            \"name\":\"say \\\"hi\\\"\\n\",\"hostname\":\"desk\",\"version\":1,\
+            \"transports\":[\"tcp\",\"quic\"],\"instance\":\"00000000000000ff\"},\
+            {\"address\":\"192.168.1.3:27056\",\"interface\":null,\"latency_ms\":2.0,\
+            \"name\":null,\"hostname\":null,\"version\":null,\"transports\":[],\"instance\":null}]"
+        );
+    }
+}
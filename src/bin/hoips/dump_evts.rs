@@ -1,44 +1,55 @@
 use anyhow::Context;
-use evdev::{EventSummary, InputEvent};
+use evdev::{EventSummary, EventType, SynchronizationCode};
 use futures::{TryStream, TryStreamExt};
+use hid_over_ip::codec::Packet;
 
 pub async fn dump_events(
-    mut udev_stream: impl TryStream<Ok = InputEvent, Error = anyhow::Error> + Unpin,
+    mut udev_stream: impl TryStream<Ok = Packet, Error = anyhow::Error> + Unpin,
 ) -> anyhow::Result<()> {
-    while let Some(event) = udev_stream
+    while let Some(packet) = udev_stream
         .try_next()
         .await
         .context("Monitoring events to dump")?
     {
-        macro_rules! dump {
-            ($($i:ident),* $(,)*) => {
-                match event.destructure() {
-                    $(
-                    EventSummary::$i(event, code, value) => {
-                        println!(
-                            "type={:?} code={code:?} value={value}",
-                            event.event_type(),
-                        );
+        for event in packet.events {
+            macro_rules! dump {
+                ($($i:ident),* $(,)*) => {
+                    match event.destructure() {
+                        $(
+                        EventSummary::$i(event, code, value) => {
+                            println!(
+                                "device={} type={:?} code={code:?} value={value}",
+                                packet.device,
+                                event.event_type(),
+                            );
+                        }
+                        )*
                     }
-                    )*
-                }
-            };
+                };
+            }
+            dump!(
+                Synchronization,
+                Key,
+                RelativeAxis,
+                AbsoluteAxis,
+                Misc,
+                Switch,
+                Led,
+                Sound,
+                Repeat,
+                ForceFeedback,
+                Power,
+                ForceFeedbackStatus,
+                UInput,
+                Other,
+            );
         }
-        dump!(
-            Synchronization,
-            Key,
-            RelativeAxis,
-            AbsoluteAxis,
-            Misc,
-            Switch,
-            Led,
-            Sound,
-            Repeat,
-            ForceFeedback,
-            Power,
-            ForceFeedbackStatus,
-            UInput,
-            Other,
+        // packets are split on these, so they never show up among the events.
+        println!(
+            "device={} type={:?} code={:?} value=0",
+            packet.device,
+            EventType::SYNCHRONIZATION,
+            SynchronizationCode::SYN_REPORT,
         );
     }
     Ok(())
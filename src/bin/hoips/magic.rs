@@ -1,8 +1,9 @@
 use std::collections::{BTreeMap, btree_map::Entry};
 
 use anyhow::Context;
-use evdev::{EventSummary, InputEvent, KeyCode};
+use evdev::{EventSummary, KeyCode};
 use futures::{Stream, StreamExt};
+use hid_over_ip::codec::Packet;
 
 pub enum Error<E> {
     MagicKey,
@@ -22,7 +23,7 @@ pub struct Magic {
 }
 
 impl Magic {
-    fn from_iter<'a>(iter: impl IntoIterator<Item = &'a KeyCode>) -> Self {
+    pub fn from_iter<'a>(iter: impl IntoIterator<Item = &'a KeyCode>) -> Self {
         Self {
             keys: BTreeMap::from_iter(iter.into_iter().map(|k| (*k, 0))),
             armed: false,
@@ -41,22 +42,31 @@ impl Magic {
         false
     }
 
+    /// Whether the magic key combination was just released.
+    pub fn packet(&mut self, packet: &Packet) -> bool {
+        let mut triggered = false;
+        for evt in &packet.events {
+            if let EventSummary::Key(_, key_code, value) = evt.destructure() {
+                triggered |= self.key(key_code, value);
+            }
+        }
+        triggered
+    }
+
     pub fn map_stream<E>(
         magic_key: &[KeyCode],
-        stream: impl Stream<Item = Result<InputEvent, E>> + Unpin,
-    ) -> impl Stream<Item = Result<InputEvent, Error<E>>> + Unpin {
+        stream: impl Stream<Item = Result<Packet, E>> + Unpin,
+    ) -> impl Stream<Item = Result<Packet, Error<E>>> + Unpin {
         let mut magic = Magic::from_iter(magic_key);
-        stream.map(move |evt| match evt.as_ref().map(|x| x.destructure()) {
-            Ok(EventSummary::Key(_, key_code, value)) if magic.key(key_code, value) => {
-                Err(Error::MagicKey)
-            }
-            _ => evt.map_err(Error::Other),
+        stream.map(move |packet| match packet {
+            Ok(packet) if magic.packet(&packet) => Err(Error::MagicKey),
+            _ => packet.map_err(Error::Other),
         })
     }
 
     pub async fn wait(
         magic_key: &[KeyCode],
-        stream: impl Stream<Item = anyhow::Result<InputEvent>> + Unpin,
+        stream: impl Stream<Item = anyhow::Result<Packet>> + Unpin,
     ) -> anyhow::Result<()> {
         tracing::info!("Waiting for magic key...");
         let mut stream = Self::map_stream(magic_key, stream);
@@ -80,10 +90,17 @@ impl Magic {
 mod test {
     use std::time::Duration;
 
-    use evdev::EventType;
+    use evdev::{EventType, InputEvent};
 
     use super::*;
 
+    fn key(code: KeyCode, value: i32) -> Packet {
+        Packet {
+            device: 0,
+            events: vec![InputEvent::new(EventType::KEY.0, code.0, value)],
+        }
+    }
+
     #[tokio::test]
     async fn test_wait() {
         let magic_key = [
@@ -93,27 +110,39 @@ mod test {
             KeyCode::KEY_4,
         ];
         let stream = futures::stream::iter([
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_1.0, 1),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_2.0, 1),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_3.0, 1),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_4.0, 1),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_1.0, 0),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_2.0, 0),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_3.0, 0),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_4.0, 0),
+            key(KeyCode::KEY_1, 1),
+            key(KeyCode::KEY_2, 1),
+            key(KeyCode::KEY_3, 1),
+            key(KeyCode::KEY_4, 1),
+            key(KeyCode::KEY_1, 0),
+            key(KeyCode::KEY_2, 0),
+            key(KeyCode::KEY_3, 0),
+            key(KeyCode::KEY_4, 0),
         ])
         .map(Ok);
         Magic::wait(&magic_key, stream).await.expect("Is OK");
+        // several keys in one packet
+        let mut all_pressed = key(KeyCode::KEY_1, 1);
+        all_pressed.events.extend(
+            [KeyCode::KEY_2, KeyCode::KEY_3, KeyCode::KEY_4]
+                .map(|code| InputEvent::new(EventType::KEY.0, code.0, 1)),
+        );
+        let mut all_released = all_pressed.clone();
+        for evt in &mut all_released.events {
+            *evt = InputEvent::new(EventType::KEY.0, evt.code(), 0);
+        }
+        let stream = futures::stream::iter([all_pressed, all_released]).map(Ok);
+        Magic::wait(&magic_key, stream).await.expect("Is OK");
         let stream = futures::stream::iter([
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_1.0, 1),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_2.0, 1),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_3.0, 1),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_4.0, 1),
+            key(KeyCode::KEY_1, 1),
+            key(KeyCode::KEY_2, 1),
+            key(KeyCode::KEY_3, 1),
+            key(KeyCode::KEY_4, 1),
             // one key is still pressed
-            // InputEvent::new(EventType::KEY.0, KeyCode::KEY_1.0, 0),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_2.0, 0),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_3.0, 0),
-            InputEvent::new(EventType::KEY.0, KeyCode::KEY_4.0, 0),
+            // key(KeyCode::KEY_1, 0),
+            key(KeyCode::KEY_2, 0),
+            key(KeyCode::KEY_3, 0),
+            key(KeyCode::KEY_4, 0),
         ])
         .chain(futures::stream::pending())
         .map(Ok);
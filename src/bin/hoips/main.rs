@@ -1,26 +1,44 @@
+mod devices;
+mod discover;
 mod dump_evts;
 mod magic;
 
 use std::{
     collections::{BTreeSet, HashSet, VecDeque},
-    net::{IpAddr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
     process::ExitCode,
     sync::Mutex,
     time::Duration,
 };
 
-use anyhow::{Context, anyhow};
+use anyhow::Context;
 use clap::Parser;
 use evdev::KeyCode;
-use futures::{SinkExt, StreamExt, TryStreamExt};
+use futures::{SinkExt, Stream, StreamExt, TryStreamExt, future::OptionFuture, never::Never};
 use hid_over_ip::{
-    codec::Codec,
-    discovery::{DEFAULT_MULTICAST_SOCKET_V4, Discovery},
-    init_logging,
+    acl::{Acl, Rule},
+    approval,
+    codec::{Codec, Message},
+    device::{self, DeviceInfo},
+    discovery::{
+        DEFAULT_MULTICAST_SOCKET_V4, Discovered, Discovery, Interface, PeerInfo, SecretArgs,
+    },
+    handshake::{self, Features, Hello},
+    heartbeat::Heartbeat,
+    init_logging, mdns,
+    noise::PskArgs,
+    pairing::{self, PairingArgs},
+    relay,
+    tls::TlsArgs,
+    transport::{self, Connection, Endpoint, Listener, Security, SideChannel, Transport},
 };
+use tokio::sync::mpsc;
 use tokio_util::codec::Framed;
 
-use self::magic::Magic;
+use self::{
+    devices::{DeviceStream, UdevStream},
+    magic::Magic,
+};
 
 /// HoIP -- HID-over-IP. Share keyboard and mouse (or other HID inputs) over
 /// TCP/IP.
@@ -32,25 +50,81 @@ use self::magic::Magic;
 struct Cli {
     /// Devices to grab events from. Either path to /dev/input/event*, a name,
     /// or a unique identifier. Use `--list-devices` to get a list.
-    #[arg(long, short, required_unless_present_any = ["list_devices", "dump_events"])]
+    #[arg(
+        long,
+        short,
+        required_unless_present_any = ["list_devices", "dump_events", "discover", "generate_cert"]
+    )]
     device: Vec<String>,
     /// Clients to send events to. Only one client can be active at a time, will
     /// round-robin between them. If unspecified, LAN multicast discovery will
-    /// be used.
-    #[arg(long, short, value_parser = parse_socketaddr)]
-    connect: Vec<SocketAddr>,
+    /// be used. Besides `host:port`, can be `unix:PATH` for a Unix domain
+    /// socket, `exec:COMMAND` to talk to a command over its stdin and stdout,
+    /// e.g. `exec:ssh host hoipc --stdio`, or a `ws://` or `wss://` URL of a
+    /// `hoipc --transport websocket`.
+    #[arg(long, short)]
+    connect: Vec<Endpoint>,
+    /// Let clients connect to us instead, with `hoipc --connect`, e.g. when
+    /// they're behind NAT. Connected clients take turns in the order they
+    /// connected. Either `host:port` or `unix:PATH`. Only works with TCP and
+    /// WebSocket transports.
+    #[arg(long, conflicts_with = "connect")]
+    listen: Option<Endpoint>,
+    /// Only accept clients from this address or network, with `--listen`.
+    /// Same format as `hoipc --allow`, can be given multiple times.
+    #[arg(long, requires = "listen")]
+    allow: Vec<Rule>,
+    /// Never accept clients from this address or network, even if `--allow`
+    /// matches.
+    #[arg(long, requires = "listen")]
+    deny: Vec<Rule>,
     /// List devices and exit.
-    #[arg(long, short, conflicts_with_all = ["device", "connect"])]
+    #[arg(long, short, conflicts_with_all = ["device", "connect", "listen"])]
     list_devices: bool,
     /// Dump all events to stdout from all devices listed with `--device`.
-    #[arg(long, conflicts_with_all = ["list_devices", "connect"])]
+    #[arg(long, conflicts_with_all = ["list_devices", "connect", "listen"])]
     dump_events: bool,
+    /// Run discovery for this long, default 3s, then list the clients that
+    /// answered, with address, interface, latency and whatever they tell
+    /// about themselves, and exit. Doesn't connect to any.
+    #[arg(
+        long,
+        num_args = 0..=1,
+        default_missing_value = "3s",
+        value_parser = humantime::parse_duration,
+        conflicts_with_all = ["list_devices", "dump_events", "connect", "listen"]
+    )]
+    discover: Option<Duration>,
+    /// List clients found by `--discover` as JSON.
+    #[arg(long, requires = "discover")]
+    json: bool,
     /// Keys, when pressed, will release the grab or connect to the next client.
     #[arg(long, short, default_values = ["KEY_LEFTCTRL","KEY_LEFTSHIFT","KEY_F12"])]
     magic_key: Vec<KeyCode>,
+    /// Name to introduce ourselves to clients with.
+    #[arg(long, default_value = "hoips")]
+    name: String,
+    /// Transport for input events. Both sides have to use the same one.
+    #[arg(long, value_enum, default_value_t)]
+    transport: Transport,
+    #[command(flatten)]
+    tls: TlsArgs,
+    #[command(flatten)]
+    psk: PskArgs,
+    #[command(flatten)]
+    pairing: PairingArgs,
+    #[command(flatten)]
+    relay_secret: relay::RelaySecretArgs,
     /// Connect immediately on start. If not set, will wait for magic key first.
     #[arg(long)]
     connect_on_start: bool,
+    /// How often to send heartbeats, if the peer supports them.
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    heartbeat_interval: Duration,
+    /// Drop the connection if nothing is received from the peer for this long.
+    /// Only effective if the peer supports heartbeats.
+    #[arg(long, default_value = "5s", value_parser = humantime::parse_duration)]
+    heartbeat_timeout: Duration,
     /// What multicast address to use for peer discovery. If
     /// `--discovery-bind-addr` is V6 while this is V4, will default to a V6
     /// instead.
@@ -60,10 +134,26 @@ struct Cli {
     /// IPv4-only.
     #[arg(long)]
     discovery_force_v6: bool,
-    /// Which network interface to run discovery on. Will try to guess if
-    /// unspecified.
-    #[arg(long)]
-    discovery_ifname: Option<String>,
+    /// Which network interfaces to run discovery on, comma-separated or
+    /// repeated. Requests go out on each, and clients are reached through the
+    /// one they answered on. Will try to guess if unspecified.
+    #[arg(long, value_delimiter = ',')]
+    discovery_ifname: Vec<String>,
+    /// Run discovery on every multicast-capable interface that's up, except
+    /// loopback.
+    #[arg(long, conflicts_with = "discovery_ifname")]
+    discovery_all_interfaces: bool,
+    #[command(flatten)]
+    discovery_secret: SecretArgs,
+    /// Browse for `_hoip._tcp` services over mDNS, as advertised by `hoipc
+    /// --mdns`, instead of using the discovery protocol. Uses the mDNS group in
+    /// the family of `--discovery-multicast`. mDNS can't be authenticated, so
+    /// this doesn't go with `--discovery-secret`.
+    #[arg(
+        long,
+        conflicts_with_all = ["connect", "listen", "discovery_secret", "discovery_secret_file"]
+    )]
+    mdns: bool,
     /// Which address to bind to when doing peer discovery. Will default to
     /// wildcard if unspecified.
     #[arg(long)]
@@ -85,10 +175,95 @@ struct Cli {
     discovery_timeout: Duration,
 }
 
-fn parse_socketaddr(addr: &str) -> anyhow::Result<SocketAddr> {
-    addr.to_socket_addrs()?
-        .next()
-        .ok_or_else(|| anyhow!("{addr} did not resolve to an address"))
+/// Where discovered clients come from.
+enum Source {
+    Multicast(Box<Discovery>),
+    Mdns(mdns::Browser),
+}
+
+impl Source {
+    async fn new(config: &Cli, bind: SocketAddr) -> anyhow::Result<Self> {
+        if config.mdns {
+            let browser = mdns::Browser::new(bind, config.discovery_multicast)
+                .await
+                .context("Create mDNS browser")?;
+            return Ok(Source::Mdns(browser));
+        }
+        let info = PeerInfo::local(&config.name, bind.port(), config.transport);
+        let secret = config
+            .discovery_secret
+            .load()
+            .context("Load discovery secret")?;
+        let interfaces =
+            Interface::select(&config.discovery_ifname, config.discovery_all_interfaces)
+                .context("Select discovery interfaces")?;
+        let discovery = Discovery::new(config.discovery_multicast, bind, info, secret, interfaces)
+            .await
+            .context("Create discovery")?;
+        Ok(Source::Multicast(Box::new(discovery)))
+    }
+
+    async fn request(&self) -> anyhow::Result<()> {
+        match self {
+            Source::Multicast(discovery) => discovery.request().await,
+            Source::Mdns(browser) => browser.query().await,
+        }
+    }
+
+    async fn discover(&self, period: Duration) -> anyhow::Result<Never> {
+        match self {
+            Source::Multicast(discovery) => discovery.discover(period).await,
+            Source::Mdns(browser) => browser.discover(period).await,
+        }
+    }
+
+    fn discovered(&self) -> impl Stream<Item = anyhow::Result<Discovered>> {
+        match self {
+            Source::Multicast(discovery) => discovery.discovered().left_stream(),
+            Source::Mdns(browser) => browser.discovered().right_stream(),
+        }
+    }
+}
+
+/// A client to try next.
+enum Peer {
+    /// Has to be connected to.
+    Remote(Endpoint),
+    /// Connected to us, see `--listen`.
+    Connected(Connection),
+}
+
+impl Peer {
+    fn endpoint(&self) -> &Endpoint {
+        match self {
+            Peer::Remote(remote) => remote,
+            Peer::Connected(conn) => &conn.remote,
+        }
+    }
+}
+
+/// Queues up clients connecting to us, for as long as anyone takes them.
+async fn accept_clients(listener: Listener, acl: Acl, clients: mpsc::UnboundedSender<Peer>) {
+    while !clients.is_closed() {
+        let incoming = match listener.incoming(&acl).await {
+            Ok(incoming) => incoming,
+            Err(e) => {
+                tracing::warn!("{e:?}");
+                continue;
+            }
+        };
+        // so that a client stalling its handshake doesn't hold up the others.
+        let clients = clients.clone();
+        tokio::spawn(async move {
+            match incoming.secure().await {
+                Ok(conn) => {
+                    tracing::info!(remote = %conn.remote, "Client connected");
+                    let _ = clients.send(Peer::Connected(conn));
+                }
+                Err(e) => tracing::warn!("{e:?}"),
+            }
+        });
+    }
 }
 
 #[tokio::main]
@@ -110,6 +285,15 @@ async fn main() -> ExitCode {
         return ExitCode::SUCCESS;
     }
 
+    match config.tls.generate() {
+        Ok(true) => return ExitCode::SUCCESS,
+        Ok(false) => {}
+        Err(error) => {
+            tracing::error!("{error:?}");
+            return ExitCode::FAILURE;
+        }
+    }
+
     tokio::select! {
         _ = tokio::signal::ctrl_c() => ExitCode::SUCCESS,
         res = imp(config) => match res {
@@ -123,6 +307,21 @@ async fn main() -> ExitCode {
 }
 
 async fn imp(mut config: Cli) -> anyhow::Result<()> {
+    let security = Security {
+        tls: config
+            .tls
+            .load(config.transport)
+            .context("Load TLS certificates")?,
+        psk: config
+            .psk
+            .load(config.transport)
+            .context("Load pre-shared key")?,
+        pairing: config
+            .pairing
+            .load(config.transport)
+            .context("Load identity")?,
+        relay: config.relay_secret.load().context("Load relay secret")?,
+    };
     let mut disc_bind_sock = SocketAddr::new(
         config
             .discovery_bind_addr
@@ -132,10 +331,21 @@ async fn imp(mut config: Cli) -> anyhow::Result<()> {
     hid_over_ip::fix_socket_addr_iface(
         &mut disc_bind_sock,
         &mut config.discovery_multicast,
-        config.discovery_ifname.as_deref(),
+        config.discovery_ifname.first().map(String::as_str),
         config.discovery_force_v6,
     )?;
 
+    if let Some(duration) = config.discover {
+        let source = Source::new(&config, disc_bind_sock).await?;
+        return discover::list(
+            &source,
+            duration,
+            config.discovery_request_period,
+            config.json,
+        )
+        .await;
+    }
+
     let mut requested_devices: HashSet<_> = config.device.iter().map(|x| x.as_str()).collect();
     let mut devices: Vec<evdev::Device> = vec![];
     for (path, dev) in evdev::enumerate() {
@@ -164,8 +374,9 @@ async fn imp(mut config: Cli) -> anyhow::Result<()> {
     tracing::info!("Opened devices");
     let streams: Vec<_> = devices
         .into_iter()
-        .map(|dev| dev.into_event_stream())
-        .collect::<Result<_, _>>()
+        .zip(0..)
+        .map(|(dev, id)| Ok(DeviceStream::new(id, dev.into_event_stream()?)))
+        .collect::<std::io::Result<_>>()
         .context("Collect event streams")?;
 
     let mut udev_stream = futures::stream::select_all(streams).err_into();
@@ -176,17 +387,35 @@ async fn imp(mut config: Cli) -> anyhow::Result<()> {
 
     let discovery;
     let invalid_peers = Mutex::new(BTreeSet::<SocketAddr>::new());
-    let remotes = if config.connect.is_empty() {
-        discovery = Discovery::new(config.discovery_multicast, disc_bind_sock)
+    let remotes = if let Some(listen) = &config.listen {
+        anyhow::ensure!(
+            config.transport.is_stream(),
+            "Only TCP and WebSocket transports work with --listen"
+        );
+        let listener = Listener::bind(config.transport, listen, &security)
             .await
-            .context("Create discovery")?;
+            .context("Bind listener")?;
+        tracing::info!(address = %listen, "Started listener");
+        let (tx, rx) = mpsc::unbounded_channel();
+        let acl = Acl {
+            allow: config.allow.clone(),
+            deny: config.deny.clone(),
+        };
+        tokio::spawn(accept_clients(listener, acl, tx));
+        futures::stream::unfold(rx, |mut rx| async {
+            let peer = rx.recv().await?;
+            Some((Ok(peer), rx))
+        })
+        .left_stream()
+    } else if config.connect.is_empty() {
+        discovery = Source::new(&config, disc_bind_sock).await?;
         struct St<S> {
             cache: VecDeque<SocketAddr>,
             discovered: S,
         }
         let st = Box::new(St {
             cache: VecDeque::<SocketAddr>::new(),
-            discovered: discovery.discovered(),
+            discovered: discovery.discovered().map_ok(|peer| peer.addr),
         });
         let return_on_timeout = futures::stream::unfold(st, |mut st| async {
             {
@@ -223,14 +452,20 @@ async fn imp(mut config: Cli) -> anyhow::Result<()> {
             }
             Some((value, st))
         });
-        return_on_timeout.left_stream()
+        return_on_timeout
+            .map_ok(|addr| Peer::Remote(Endpoint::Addr(addr)))
+            .left_stream()
+            .right_stream()
     } else {
         futures::stream::iter(config.connect.iter().cycle())
-            .map(|x| Ok(*x))
+            .map(|x| Ok(Peer::Remote(x.clone())))
+            .right_stream()
             .right_stream()
     };
     let mut remotes = std::pin::pin!(remotes);
 
+    let mut hello = Hello::new(&config.name);
+    hello.features = config.transport.features();
     let mut do_wait = !config.connect_on_start;
 
     loop {
@@ -245,22 +480,25 @@ async fn imp(mut config: Cli) -> anyhow::Result<()> {
             tracing::warn!("No remote found, timeout elapsed");
             continue;
         };
-        let Some(remote) = remote.context("While getting remote peer")? else {
+        let Some(peer) = remote.context("While getting remote peer")? else {
             // stream ended
             break Ok(());
         };
+        let remote = peer.endpoint().clone();
         tracing::info!(remote = %remote, "Connecting...");
-        let mut magic = false;
-        if let Err(e) = connect(remote, &config.magic_key, &mut udev_stream).await {
-            match e {
-                magic::Error::MagicKey => {
-                    tracing::info!("Magic key pressed");
-                    magic = true;
-                }
-                magic::Error::Other(e) => {
-                    invalid_peers.lock().unwrap().insert(remote);
-                    tracing::error!("{e:?}");
+        // whether the connection ended deliberately, on either side.
+        let mut expected = true;
+        match connect(peer, &hello, &config, &security, &mut udev_stream).await {
+            Ok(()) => {}
+            Err(magic::Error::MagicKey) => {
+                tracing::info!("Magic key pressed");
+            }
+            Err(magic::Error::Other(e)) => {
+                expected = false;
+                if let Some(addr) = remote.addr() {
+                    invalid_peers.lock().unwrap().insert(addr);
                 }
+                tracing::error!("{e:?}");
             }
         }
         let is_grabbed = udev_stream
@@ -271,7 +509,7 @@ async fn imp(mut config: Cli) -> anyhow::Result<()> {
             // managed to connect to a remote, however briefly. wait for magic
             // next time around.
             do_wait = true;
-            if !magic {
+            if !expected {
                 // connection terminated unexpectedly. to prevent
                 // surprises, wait for magic key, then ungrab.
                 Magic::wait(&config.magic_key, &mut udev_stream)
@@ -279,32 +517,195 @@ async fn imp(mut config: Cli) -> anyhow::Result<()> {
                     .context("Wating for magic")?;
             }
             for dev in udev_stream.get_mut().iter_mut() {
-                dev.device_mut().ungrab().context("Ungrab device")?;
+                dev.ungrab().context("Ungrab device")?;
             }
             tracing::info!("Ungrabbed devices");
         }
     }
 }
 
+/// Returns `Ok` if the remote closed the connection deliberately.
 async fn connect(
-    connect: SocketAddr,
-    magic_key: &[KeyCode],
-    udev_stream: &mut futures::stream::ErrInto<
-        futures::stream::SelectAll<evdev::EventStream>,
-        anyhow::Error,
-    >,
+    peer: Peer,
+    hello: &Hello,
+    config: &Cli,
+    security: &Security,
+    udev_stream: &mut UdevStream,
 ) -> Result<(), magic::Error<anyhow::Error>> {
-    let tcp_stream = tokio::net::TcpStream::connect(connect)
+    let mut conn = match peer {
+        Peer::Remote(remote) => {
+            let conn = transport::connect(config.transport, &remote, security).await?;
+            tracing::info!(%remote, "Connected to remote");
+            conn
+        }
+        Peer::Connected(conn) => conn,
+    };
+    let connect = conn.remote.clone();
+    let negotiated = handshake::connect(&mut conn.stream, hello)
         .await
-        .context("Open TCP stream")?;
-    tracing::info!(remote = %connect, "Connected to remote");
-    let mut framed = Framed::new(tcp_stream, Codec).sink_err_into();
+        .context("Handshake")?;
+    tracing::info!(
+        remote = %connect,
+        peer_name = negotiated.peer_name,
+        features = ?negotiated.features,
+        "Handshake complete"
+    );
+    if let (Some(pairing), Some(peer)) = (&security.pairing, &conn.peer) {
+        // devices aren't grabbed yet, so this goes to the local system too.
+        let confirm = async {
+            loop {
+                let packet = udev_stream
+                    .try_next()
+                    .await?
+                    .context("Input stream ended unexpectedly")?;
+                if let Some(answer) = packet.events.iter().find_map(pairing::answer) {
+                    return Ok(answer);
+                }
+            }
+        };
+        pairing
+            .pair(&mut conn.stream, &negotiated.peer_name, peer, confirm)
+            .await?;
+    }
+    if negotiated.features.contains(Features::APPROVAL) {
+        tracing::debug!("Waiting for the remote to approve the connection");
+        // devices aren't grabbed yet, but the magic key can still give up.
+        let mut magic = Magic::from_iter(&config.magic_key);
+        let verdict = approval::recv_verdict(&mut conn.stream);
+        tokio::pin!(verdict);
+        loop {
+            tokio::select! {
+                res = &mut verdict => break res?,
+                packet = udev_stream.try_next() => {
+                    let packet = packet?.context("Input stream ended unexpectedly")?;
+                    if magic.packet(&packet) {
+                        return Err(magic::Error::MagicKey);
+                    }
+                }
+            }
+        }
+    }
+    if negotiated.features.contains(Features::DEVICES) {
+        let infos = udev_stream
+            .get_ref()
+            .iter()
+            .map(|stream| DeviceInfo::from_device(stream.device()))
+            .collect::<Result<Vec<_>, _>>()
+            .context("Describe devices")?;
+        device::send(&mut conn.stream, &infos).await?;
+    }
+    let features = negotiated.features;
+    let mut side = conn.side_channel(features).await?;
+    let multiplex = features.contains(Features::MULTIPLEX);
+    let heartbeats = features.contains(Features::HEARTBEAT);
+    let control = features.contains(Features::CONTROL);
+    let mut heartbeat = Heartbeat::new(config.heartbeat_interval, config.heartbeat_timeout);
+    let mut framed = Framed::new(conn.stream, Codec::new(features));
     for dev in udev_stream.get_mut().iter_mut() {
-        dev.device_mut().grab().context("Grab device")?;
+        dev.grab().context("Grab device")?;
     }
     tracing::info!("Grabbed devices");
-    framed
-        .send_all(&mut Magic::map_stream(magic_key, udev_stream))
+    if control {
+        send(
+            &mut framed,
+            Message::GrabState(true),
+            config.heartbeat_timeout,
+        )
         .await?;
-    Ok::<_, magic::Error<_>>(())
+    }
+    let mut magic = Magic::from_iter(&config.magic_key);
+    loop {
+        tokio::select! {
+            packet = udev_stream.try_next() => {
+                let packet = packet?.context("Input stream ended unexpectedly")?;
+                if magic.packet(&packet) {
+                    if control {
+                        // the magic key itself was sent pressed, but not
+                        // released.
+                        framed.feed(Message::ReleaseAll).await?;
+                        framed.feed(Message::GrabState(false)).await?;
+                        let goodbye = Message::Goodbye("Magic key pressed".to_owned());
+                        send(&mut framed, goodbye, config.heartbeat_timeout).await?;
+                    }
+                    return Err(magic::Error::MagicKey);
+                }
+                if let Some(side) = side.as_mut().filter(|side| side.carries(&packet)) {
+                    side.send(packet).await?;
+                } else {
+                    // send() flushes, so each packet goes out in one write as
+                    // soon as it's complete. a dead peer stops reading
+                    // eventually, so don't wait on it forever.
+                    send(&mut framed, packet.into(), config.heartbeat_timeout).await?;
+                }
+            }
+            // acks and retransmissions
+            Some(res) = OptionFuture::from(side.as_mut().map(SideChannel::recv)) => {
+                tracing::warn!(packet = ?res?, "Ignoring unexpected side channel packet");
+            }
+            token = heartbeat.tick(), if heartbeats => {
+                send(&mut framed, Message::Ping(token?), config.heartbeat_timeout).await?;
+            }
+            msg = framed.try_next() => {
+                let msg = msg
+                    .context("Get next data frame")?
+                    .context("Connection closed by remote")?;
+                heartbeat.received();
+                match msg {
+                    // LED changes
+                    Message::Packet(packet) => {
+                        tracing::debug!(
+                            device = packet.device,
+                            events = ?packet.events,
+                            "LED change"
+                        );
+                        for dev in udev_stream.get_mut().iter_mut() {
+                            if !multiplex || dev.id() == packet.device {
+                                dev.set_leds(&packet.events).context("Set LEDs")?;
+                            }
+                        }
+                    }
+                    Message::Ping(token) => {
+                        send(&mut framed, Message::Pong(token), config.heartbeat_timeout).await?;
+                    }
+                    Message::Pong(token) => {
+                        if let Some(rtt) = heartbeat.pong(token) {
+                            tracing::trace!(?rtt, "Round trip time");
+                        }
+                    }
+                    Message::Goodbye(reason) => {
+                        tracing::info!(reason, "Remote said goodbye");
+                        return Ok(());
+                    }
+                    Message::Error(reason) => {
+                        return Err(anyhow::anyhow!("Remote failed: {reason}").into());
+                    }
+                    msg @ (Message::ReleaseAll | Message::GrabState(_)) => {
+                        tracing::warn!(?msg, "Ignoring unexpected message");
+                    }
+                }
+            }
+        }
+    }
+}
+
+async fn send(
+    framed: &mut Framed<transport::Stream, Codec>,
+    msg: Message,
+    timeout: Duration,
+) -> anyhow::Result<()> {
+    tokio::time::timeout(timeout, framed.send(msg))
+        .await
+        .context("Timed out sending to remote")?
+}
+
+#[cfg(test)]
+mod test {
+    use clap::CommandFactory;
+
+    use super::*;
+
+    #[test]
+    fn test_cli() {
+        Cli::command().debug_assert();
+    }
 }
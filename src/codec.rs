@@ -1,43 +1,744 @@
-use evdev::InputEvent;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+
+use evdev::{EventSummary, EventType, InputEvent, SynchronizationCode};
 use tokio_util::{
-    bytes::{Buf, BufMut},
+    bytes::{Buf, BufMut, BytesMut},
     codec::{Decoder, Encoder},
 };
 
-pub struct Codec;
+use crate::handshake::Features;
 
-impl Encoder<InputEvent> for Codec {
-    type Error = anyhow::Error;
+const EVENT_LEN: usize = 8;
+const TIMESTAMP_LEN: usize = 8;
+/// Most events an unbatched packet may have, about as many as fit in a batch.
+const MAX_PENDING: usize = u16::MAX as usize / EVENT_LEN;
+
+/// Frame kinds, when [`Features::HEARTBEAT`] is negotiated.
+const KIND_PACKET: u8 = 0;
+const KIND_PING: u8 = 1;
+const KIND_PONG: u8 = 2;
+const KIND_RELEASE_ALL: u8 = 3;
+const KIND_GOODBYE: u8 = 4;
+const KIND_GRAB_STATE: u8 = 5;
+const KIND_ERROR: u8 = 6;
+
+/// All events up to, but not including, a `SYN_REPORT`. These should be
+/// emitted atomically.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Packet {
+    /// Index of the source device among those sent with
+    /// [`device::send`](crate::device::send). Only meaningful if
+    /// [`Features::MULTIPLEX`] was negotiated, otherwise always 0.
+    pub device: u16,
+    pub events: Vec<InputEvent>,
+}
+
+impl Packet {
+    /// Timestamp of the first event in the packet, if any.
+    pub fn timestamp(&self) -> Option<SystemTime> {
+        self.events.first().map(|evt| evt.timestamp())
+    }
+
+    /// Whether the packet is only pointer motion, so that losing it is fine:
+    /// the next one supersedes it.
+    pub fn is_motion(&self) -> bool {
+        !self.events.is_empty()
+            && self
+                .events
+                .iter()
+                .all(|evt| matches!(evt.event_type(), EventType::RELATIVE | EventType::ABSOLUTE))
+    }
+}
+
+/// Everything that can be sent over the connection after the handshake. Only
+/// [`Message::Packet`] is available without [`Features::HEARTBEAT`], see
+/// [`Message::feature`].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum Message {
+    Packet(Packet),
+    /// Sent periodically, so that peers can tell a quiet connection from a
+    /// dead one. Must be answered with a [`Message::Pong`] carrying the same
+    /// token.
+    Ping(u64),
+    Pong(u64),
+    /// Release all keys and buttons currently held down.
+    ReleaseAll,
+    /// The sender is closing the connection deliberately.
+    Goodbye(String),
+    /// The sender is closing the connection because something went wrong.
+    Error(String),
+    /// Whether the server has its physical devices grabbed, i.e. whether
+    /// input currently goes to this connection.
+    GrabState(bool),
+}
+
+impl Message {
+    /// What has to be negotiated for this message to be sent.
+    pub fn feature(&self) -> Features {
+        match self {
+            Message::Packet(_) => Features::empty(),
+            Message::Ping(_) | Message::Pong(_) => Features::HEARTBEAT,
+            Message::ReleaseAll
+            | Message::Goodbye(_)
+            | Message::Error(_)
+            | Message::GrabState(_) => Features::CONTROL,
+        }
+    }
+}
+
+impl From<Packet> for Message {
+    fn from(packet: Packet) -> Self {
+        Message::Packet(packet)
+    }
+}
+
+fn is_syn_report(evt: &InputEvent) -> bool {
+    matches!(
+        evt.destructure(),
+        EventSummary::Synchronization(_, SynchronizationCode::SYN_REPORT, 0)
+    )
+}
+
+/// Highest event code for each event type we forward, from
+/// `linux/input-event-codes.h`.
+fn max_code(event_type: EventType) -> Option<u16> {
+    Some(match event_type {
+        EventType::SYNCHRONIZATION => 0x0f,
+        EventType::KEY => 0x2ff,
+        EventType::RELATIVE => 0x0f,
+        EventType::ABSOLUTE => 0x3f,
+        EventType::MISC => 0x07,
+        EventType::SWITCH => 0x10,
+        EventType::LED => 0x0f,
+        EventType::SOUND => 0x07,
+        EventType::REPEAT => 0x01,
+        _ => return None,
+    })
+}
+
+/// Whether the event is something a peer could legitimately send inside a
+/// packet.
+fn is_valid(evt: &InputEvent) -> bool {
+    !is_syn_report(evt) && max_code(evt.event_type()).is_some_and(|max| evt.code() <= max)
+}
+
+pub struct Codec {
+    features: Features,
+    /// Per-connection reference point for timestamps. On the encoding side,
+    /// event timestamps are sent as offsets from this; on the decoding side,
+    /// offsets are added to this. Both ends set it right after the handshake,
+    /// so clock skew between the hosts doesn't matter.
+    epoch: SystemTime,
+    /// Events received so far for the current packet, when not batching.
+    pending: Vec<InputEvent>,
+    /// Set while skipping the rest of a packet with too many events.
+    overflowed: bool,
+    /// Number of received packets dropped for containing invalid events, or
+    /// too many.
+    rejected: u64,
+}
+
+impl Codec {
+    pub fn new(features: Features) -> Self {
+        Self::with_epoch(features, SystemTime::now())
+    }
+
+    /// With a given epoch rather than now, e.g. so that a relay decoding on
+    /// one side and encoding on the other passes timestamps through as is.
+    pub fn with_epoch(features: Features, epoch: SystemTime) -> Self {
+        Self {
+            features,
+            epoch,
+            pending: Vec::new(),
+            overflowed: false,
+            rejected: 0,
+        }
+    }
+
+    /// Number of received packets dropped so far for containing invalid
+    /// events, or too many.
+    pub fn rejected(&self) -> u64 {
+        self.rejected
+    }
+
+    fn timestamps(&self) -> bool {
+        self.features.contains(Features::TIMESTAMPS)
+    }
+
+    fn put_event(dst: &mut BytesMut, evt: &InputEvent) {
+        dst.put_u16(evt.event_type().0);
+        dst.put_u16(evt.code());
+        dst.put_i32(evt.value());
+    }
+
+    fn put_timestamp(&self, dst: &mut BytesMut, time: SystemTime) {
+        // events from before the connection was established are clamped to the
+        // epoch.
+        let offset = time.duration_since(self.epoch).unwrap_or_default();
+        dst.put_u64(offset.as_micros().try_into().unwrap_or(u64::MAX));
+    }
+
+    fn get_timestamp(&self, src: &mut BytesMut) -> SystemTime {
+        self.epoch + Duration::from_micros(src.get_u64())
+    }
+
+    /// Unbatched format: each event is its own frame, `SYN_REPORT` included.
+    fn encode_events(&self, item: Packet, dst: &mut BytesMut) {
+        let frame_len = EVENT_LEN + if self.timestamps() { TIMESTAMP_LEN } else { 0 };
+        dst.reserve(frame_len * (item.events.len() + 1));
+        let syn = InputEvent::new(
+            EventType::SYNCHRONIZATION.0,
+            SynchronizationCode::SYN_REPORT.0,
+            0,
+        );
+        let syn_time = item.timestamp().unwrap_or(self.epoch);
+        for (evt, time) in item
+            .events
+            .iter()
+            .map(|evt| (evt, evt.timestamp()))
+            .chain([(&syn, syn_time)])
+        {
+            Self::put_event(dst, evt);
+            if self.timestamps() {
+                self.put_timestamp(dst, time);
+            }
+        }
+    }
+
+    fn decode_events(&mut self, src: &mut BytesMut) -> Option<Packet> {
+        let frame_len = EVENT_LEN + if self.timestamps() { TIMESTAMP_LEN } else { 0 };
+        while src.remaining() >= frame_len {
+            let (type_, code, value) = (src.get_u16(), src.get_u16(), src.get_i32());
+            let evt = if self.timestamps() {
+                new_event_at(self.get_timestamp(src), type_, code, value)
+            } else {
+                InputEvent::new_now(type_, code, value)
+            };
+            if is_syn_report(&evt) {
+                if std::mem::take(&mut self.overflowed) {
+                    continue;
+                }
+                return Some(Packet {
+                    device: 0,
+                    events: std::mem::take(&mut self.pending),
+                });
+            }
+            if self.overflowed {
+                continue;
+            }
+            if self.pending.len() == MAX_PENDING {
+                self.pending = Vec::new();
+                self.overflowed = true;
+                self.rejected += 1;
+                tracing::warn!(
+                    rejected = self.rejected,
+                    "Dropping packet of more than {MAX_PENDING} events"
+                );
+                continue;
+            }
+            self.pending.push(evt);
+        }
+        None
+    }
+
+    fn multiplex(&self) -> bool {
+        self.features.contains(Features::MULTIPLEX)
+    }
+
+    fn tagged(&self) -> bool {
+        self.features.contains(Features::HEARTBEAT)
+    }
+
+    fn batch_header_len(&self) -> usize {
+        (if self.multiplex() { 2 } else { 0 }) + if self.timestamps() { TIMESTAMP_LEN } else { 0 }
+    }
+
+    /// Batched format: `u16` payload length, then the frame kind if tagged,
+    /// then the device index if multiplexing, then optional timestamp shared
+    /// by all events, then the events. `SYN_REPORT` is implied.
+    fn encode_batch(&self, item: Packet, dst: &mut BytesMut) -> anyhow::Result<()> {
+        let kind_len = if self.tagged() { 1 } else { 0 };
+        let payload_len = kind_len + self.batch_header_len() + EVENT_LEN * item.events.len();
+        let Ok(len) = u16::try_from(payload_len) else {
+            anyhow::bail!("Packet of {} events is too large", item.events.len());
+        };
+        dst.reserve(2 + payload_len);
+        dst.put_u16(len);
+        if self.tagged() {
+            dst.put_u8(KIND_PACKET);
+        }
+        if self.multiplex() {
+            dst.put_u16(item.device);
+        }
+        if self.timestamps() {
+            let time = item.timestamp().unwrap_or(self.epoch);
+            self.put_timestamp(dst, time);
+        }
+        for evt in &item.events {
+            Self::put_event(dst, evt);
+        }
+        Ok(())
+    }
+
+    fn decode_batch(&mut self, src: &mut BytesMut) -> anyhow::Result<Option<Message>> {
+        let Some(len) = src.get(..2) else {
+            return Ok(None);
+        };
+        let mut len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+        if src.remaining() < 2 + len {
+            src.reserve(2 + len - src.remaining());
+            return Ok(None);
+        }
+        src.advance(2);
+        if self.tagged() {
+            anyhow::ensure!(len >= 1, "Empty frame");
+            len -= 1;
+            let kind = src.get_u8();
+            if kind != KIND_PACKET {
+                let msg = Self::decode_control(kind, src.split_to(len))?;
+                anyhow::ensure!(
+                    self.features.contains(msg.feature()),
+                    "Unexpected message {msg:?}"
+                );
+                return Ok(Some(msg));
+            }
+        }
+        let header_len = self.batch_header_len();
+        anyhow::ensure!(
+            len >= header_len && (len - header_len).is_multiple_of(EVENT_LEN),
+            "Malformed packet frame of length {len}"
+        );
+        let device = if self.multiplex() { src.get_u16() } else { 0 };
+        let time = if self.timestamps() {
+            self.get_timestamp(src)
+        } else {
+            SystemTime::now()
+        };
+        let events = (0..(len - header_len) / EVENT_LEN)
+            .map(|_| new_event_at(time, src.get_u16(), src.get_u16(), src.get_i32()))
+            .collect();
+        Ok(Some(Packet { device, events }.into()))
+    }
 
-    fn encode(
-        &mut self,
-        item: InputEvent,
-        dst: &mut tokio_util::bytes::BytesMut,
-    ) -> Result<(), Self::Error> {
-        dst.put_u16(item.event_type().0);
-        dst.put_u16(item.code());
-        dst.put_i32(item.value());
+    fn encode_control(item: &Message, dst: &mut BytesMut) -> anyhow::Result<()> {
+        let mut body = BytesMut::new();
+        let kind = match item {
+            Message::Packet(_) => unreachable!("packets are encoded separately"),
+            Message::Ping(token) => {
+                body.put_u64(*token);
+                KIND_PING
+            }
+            Message::Pong(token) => {
+                body.put_u64(*token);
+                KIND_PONG
+            }
+            Message::ReleaseAll => KIND_RELEASE_ALL,
+            Message::Goodbye(reason) => {
+                body.put_slice(reason.as_bytes());
+                KIND_GOODBYE
+            }
+            Message::Error(reason) => {
+                body.put_slice(reason.as_bytes());
+                KIND_ERROR
+            }
+            Message::GrabState(grabbed) => {
+                body.put_u8((*grabbed).into());
+                KIND_GRAB_STATE
+            }
+        };
+        let len = u16::try_from(1 + body.len()).context("Message is too large")?;
+        dst.reserve(3 + body.len());
+        dst.put_u16(len);
+        dst.put_u8(kind);
+        dst.put_slice(&body);
         Ok(())
     }
+
+    fn decode_control(kind: u8, mut body: BytesMut) -> anyhow::Result<Message> {
+        let msg = match kind {
+            KIND_PING => Message::Ping(body.try_get_u64()?),
+            KIND_PONG => Message::Pong(body.try_get_u64()?),
+            KIND_RELEASE_ALL => Message::ReleaseAll,
+            KIND_GOODBYE => Message::Goodbye(String::from_utf8_lossy(&body.split()).into_owned()),
+            KIND_ERROR => Message::Error(String::from_utf8_lossy(&body.split()).into_owned()),
+            KIND_GRAB_STATE => Message::GrabState(body.try_get_u8()? != 0),
+            kind => anyhow::bail!("Unknown frame kind {kind}"),
+        };
+        anyhow::ensure!(body.is_empty(), "Trailing data in message {msg:?}");
+        Ok(msg)
+    }
+}
+
+impl Encoder<Message> for Codec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, item: Message, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match item {
+            Message::Packet(packet) if self.features.contains(Features::BATCH) => {
+                self.encode_batch(packet, dst)
+            }
+            Message::Packet(packet) => {
+                self.encode_events(packet, dst);
+                Ok(())
+            }
+            msg => {
+                anyhow::ensure!(
+                    self.features.contains(msg.feature()),
+                    "Can't send {msg:?}, {:?} was not negotiated",
+                    msg.feature()
+                );
+                Self::encode_control(&msg, dst)
+            }
+        }
+    }
 }
 
 impl Decoder for Codec {
-    type Item = InputEvent;
+    type Item = Message;
 
     type Error = anyhow::Error;
 
-    fn decode(
-        &mut self,
-        src: &mut tokio_util::bytes::BytesMut,
-    ) -> Result<Option<Self::Item>, Self::Error> {
-        if src.remaining() < 8 {
-            Ok(None)
-        } else {
-            Ok(Some(InputEvent::new_now(
-                src.get_u16(),
-                src.get_u16(),
-                src.get_i32(),
-            )))
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        loop {
+            let msg = if self.features.contains(Features::BATCH) {
+                self.decode_batch(src)?
+            } else {
+                self.decode_events(src).map(Message::Packet)
+            };
+            match msg {
+                // framing is intact, so skip just this packet and carry on.
+                Some(Message::Packet(packet)) if !packet.events.iter().all(is_valid) => {
+                    self.rejected += 1;
+                    tracing::warn!(
+                        rejected = self.rejected,
+                        events = ?packet.events,
+                        "Dropping packet with invalid events"
+                    );
+                }
+                msg => return Ok(msg),
+            }
+        }
+    }
+}
+
+fn new_event_at(time: SystemTime, type_: u16, code: u16, value: i32) -> InputEvent {
+    let since_unix = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    InputEvent::from(libc::input_event {
+        time: libc::timeval {
+            tv_sec: since_unix.as_secs() as libc::time_t,
+            tv_usec: since_unix.subsec_micros() as libc::suseconds_t,
+        },
+        type_,
+        code,
+        value,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use evdev::KeyCode;
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn key(code: KeyCode, value: i32) -> InputEvent {
+        InputEvent::new(EventType::KEY.0, code.0, value)
+    }
+
+    fn expect_packet(msg: Option<Message>) -> Packet {
+        match msg {
+            Some(Message::Packet(packet)) => packet,
+            other => panic!("Expected a packet, got {other:?}"),
+        }
+    }
+
+    fn summary(packet: &Packet) -> Vec<(EventType, u16, i32)> {
+        packet
+            .events
+            .iter()
+            .map(|evt| (evt.event_type(), evt.code(), evt.value()))
+            .collect()
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for features in [
+            Features::empty(),
+            Features::TIMESTAMPS,
+            Features::BATCH,
+            Features::BATCH.union(Features::TIMESTAMPS),
+            Features::BATCH.union(Features::HEARTBEAT),
+        ] {
+            let mut codec = Codec::new(features);
+            let mut buf = BytesMut::new();
+            let packet = Packet {
+                device: 0,
+                events: vec![key(KeyCode::KEY_A, 1), key(KeyCode::KEY_B, 0)],
+            };
+            codec.encode(packet.clone().into(), &mut buf).unwrap();
+            let decoded = expect_packet(codec.decode(&mut buf).unwrap());
+            assert_eq!(summary(&decoded), summary(&packet), "{features:?}");
+            assert!(buf.is_empty(), "{features:?}");
+        }
+    }
+
+    #[test]
+    fn test_frame_len() {
+        let packet = Packet {
+            device: 0,
+            events: vec![key(KeyCode::KEY_A, 1), key(KeyCode::KEY_B, 0)],
+        };
+        for (features, len) in [
+            (Features::empty(), 3 * 8),
+            (Features::TIMESTAMPS, 3 * 16),
+            (Features::BATCH, 2 + 2 * 8),
+            (Features::BATCH.union(Features::TIMESTAMPS), 2 + 8 + 2 * 8),
+            (Features::BATCH.union(Features::HEARTBEAT), 2 + 1 + 2 * 8),
+        ] {
+            let mut buf = BytesMut::new();
+            Codec::new(features)
+                .encode(packet.clone().into(), &mut buf)
+                .unwrap();
+            assert_eq!(buf.len(), len, "{features:?}");
+        }
+    }
+
+    #[test]
+    fn test_partial() {
+        for features in [Features::empty(), Features::BATCH] {
+            let mut codec = Codec::new(features);
+            let mut buf = BytesMut::new();
+            let packet = Packet {
+                device: 0,
+                events: vec![key(KeyCode::KEY_A, 1), key(KeyCode::KEY_B, 0)],
+            };
+            codec.encode(packet.clone().into(), &mut buf).unwrap();
+            let mut input = BytesMut::new();
+            let mut decoded = None;
+            while !buf.is_empty() {
+                assert!(decoded.is_none(), "{features:?}");
+                input.extend_from_slice(&buf.split_to(3));
+                decoded = codec.decode(&mut input).unwrap();
+            }
+            assert_eq!(
+                summary(&expect_packet(decoded)),
+                summary(&packet),
+                "{features:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_malformed_batch() {
+        let mut codec = Codec::new(Features::BATCH);
+        let mut buf = BytesMut::from(&[0u8, 3, 1, 2, 3][..]);
+        codec
+            .decode(&mut buf)
+            .expect_err("length is not a multiple of 8");
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let server_epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let client_epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(2_000_000);
+        let offset = Duration::from_micros(1_500_123);
+        for features in [
+            Features::TIMESTAMPS,
+            Features::BATCH.union(Features::TIMESTAMPS),
+        ] {
+            let mut enc = Codec::with_epoch(features, server_epoch);
+            let mut dec = Codec::with_epoch(features, client_epoch);
+            let evt = new_event_at(server_epoch + offset, EventType::KEY.0, 30, 1);
+            let mut buf = BytesMut::new();
+            let packet = Packet {
+                device: 0,
+                events: vec![evt],
+            };
+            enc.encode(packet.into(), &mut buf).unwrap();
+            let decoded = expect_packet(dec.decode(&mut buf).unwrap());
+            assert_eq!(decoded.timestamp(), Some(client_epoch + offset));
+        }
+    }
+
+    #[test]
+    fn test_timestamp_before_epoch() {
+        let epoch = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut codec = Codec::with_epoch(Features::TIMESTAMPS, epoch);
+        let mut buf = BytesMut::new();
+        // InputEvent::new has a zero timestamp
+        codec
+            .encode(
+                Packet {
+                    device: 0,
+                    events: vec![key(KeyCode::KEY_A, 1)],
+                }
+                .into(),
+                &mut buf,
+            )
+            .unwrap();
+        let decoded = expect_packet(codec.decode(&mut buf).unwrap());
+        assert_eq!(decoded.timestamp(), Some(epoch));
+    }
+
+    #[test]
+    fn test_multiplex() {
+        let features = Features::BATCH
+            .union(Features::DEVICES)
+            .union(Features::MULTIPLEX);
+        let mut codec = Codec::new(features);
+        let mut buf = BytesMut::new();
+        let packet = Packet {
+            device: 3,
+            events: vec![key(KeyCode::KEY_A, 1)],
+        };
+        codec.encode(packet.clone().into(), &mut buf).unwrap();
+        assert_eq!(buf.len(), 2 + 2 + 8);
+        let decoded = expect_packet(codec.decode(&mut buf).unwrap());
+        assert_eq!(decoded.device, 3);
+        assert_eq!(summary(&decoded), summary(&packet));
+    }
+
+    #[test]
+    fn test_invalid_events() {
+        for features in [Features::empty(), Features::BATCH] {
+            let mut codec = Codec::new(features);
+            let mut buf = BytesMut::new();
+            let good = Packet {
+                device: 0,
+                events: vec![key(KeyCode::KEY_A, 1)],
+            };
+            for events in [
+                vec![InputEvent::new(EventType::FORCEFEEDBACK.0, 0, 1)],
+                vec![
+                    key(KeyCode::KEY_A, 1),
+                    InputEvent::new(EventType::KEY.0, 0x300, 1),
+                ],
+                vec![InputEvent::new(EventType::RELATIVE.0, 0x10, 1)],
+                vec![InputEvent::new(0x1234, 0, 0)],
+            ] {
+                codec
+                    .encode(Packet { device: 0, events }.into(), &mut buf)
+                    .unwrap();
+            }
+            codec.encode(good.clone().into(), &mut buf).unwrap();
+            let decoded = expect_packet(codec.decode(&mut buf).unwrap());
+            assert_eq!(summary(&decoded), summary(&good), "{features:?}");
+            assert_eq!(codec.rejected(), 4, "{features:?}");
+            assert!(buf.is_empty(), "{features:?}");
+        }
+        // an unbatched packet that never ends
+        let mut codec = Codec::new(Features::empty());
+        let mut buf = BytesMut::new();
+        for _ in 0..=MAX_PENDING {
+            Codec::put_event(&mut buf, &key(KeyCode::KEY_A, 1));
+        }
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.rejected(), 1);
+        assert!(codec.pending.is_empty());
+        let good = Packet {
+            device: 0,
+            events: vec![key(KeyCode::KEY_B, 1)],
+        };
+        codec.encode(good.clone().into(), &mut buf).unwrap();
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        codec.encode(good.clone().into(), &mut buf).unwrap();
+        let decoded = expect_packet(codec.decode(&mut buf).unwrap());
+        assert_eq!(summary(&decoded), summary(&good));
+        assert_eq!(codec.rejected(), 1);
+        // SYN_REPORT is implied in batches
+        let mut codec = Codec::new(Features::BATCH);
+        let mut buf = BytesMut::from(&[0u8, 8, 0, 0, 0, 0, 0, 0, 0, 0][..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        assert_eq!(codec.rejected(), 1);
+    }
+
+    #[test]
+    fn test_control() {
+        let features = Features::BATCH
+            .union(Features::HEARTBEAT)
+            .union(Features::CONTROL);
+        let mut codec = Codec::new(features);
+        for msg in [
+            Message::Ping(42),
+            Message::Pong(u64::MAX),
+            Message::ReleaseAll,
+            Message::Goodbye("Switching to another client".to_owned()),
+            Message::Goodbye(String::new()),
+            Message::Error("Heartbeat timed out".to_owned()),
+            Message::GrabState(true),
+            Message::GrabState(false),
+        ] {
+            let mut buf = BytesMut::new();
+            codec.encode(msg.clone(), &mut buf).unwrap();
+            assert_eq!(codec.decode(&mut buf).unwrap(), Some(msg));
+            assert!(buf.is_empty());
+        }
+        let mut buf = BytesMut::new();
+        codec.encode(Message::ReleaseAll, &mut buf).unwrap();
+        assert_eq!(&buf[..], [0, 1, KIND_RELEASE_ALL]);
+        let mut buf = BytesMut::from(&[0u8, 1, 42][..]);
+        codec.decode(&mut buf).expect_err("unknown kind");
+        let mut buf = BytesMut::from(&[0u8, 3, KIND_GRAB_STATE, 1, 1][..]);
+        codec.decode(&mut buf).expect_err("trailing data");
+        // ping/pong only need heartbeats
+        let mut codec = Codec::new(Features::BATCH.union(Features::HEARTBEAT));
+        let mut buf = BytesMut::new();
+        codec.encode(Message::Ping(1), &mut buf).unwrap();
+        codec
+            .encode(Message::ReleaseAll, &mut buf)
+            .expect_err("not negotiated");
+        let mut buf = BytesMut::from(&[0u8, 1, KIND_RELEASE_ALL][..]);
+        codec.decode(&mut buf).expect_err("not negotiated");
+        Codec::new(Features::BATCH)
+            .encode(Message::Ping(1), &mut buf)
+            .expect_err("not negotiated");
+    }
+
+    fn features() -> impl Strategy<Value = Features> {
+        (0u32..1 << 7).prop_map(|bits| Features::from_bits(bits).with_dependencies())
+    }
+
+    fn valid_event() -> impl Strategy<Value = InputEvent> {
+        (0u16..0x20)
+            .prop_filter_map("unknown event type", |type_| {
+                max_code(EventType(type_)).map(|max| (type_, max))
+            })
+            .prop_flat_map(|(type_, max)| (Just(type_), 0..=max, any::<i32>()))
+            .prop_map(|(type_, code, value)| InputEvent::new(type_, code, value))
+            .prop_filter("SYN_REPORT", is_valid)
+    }
+
+    proptest! {
+        #[test]
+        fn prop_roundtrip(
+            features in features(),
+            device in any::<u16>(),
+            events in proptest::collection::vec(valid_event(), 0..32),
+        ) {
+            let device = if features.contains(Features::MULTIPLEX) { device } else { 0 };
+            let packet = Packet { device, events };
+            let mut codec = Codec::new(features);
+            let mut buf = BytesMut::new();
+            codec.encode(packet.clone().into(), &mut buf).unwrap();
+            let decoded = expect_packet(codec.decode(&mut buf).unwrap());
+            prop_assert_eq!(decoded.device, packet.device);
+            prop_assert_eq!(summary(&decoded), summary(&packet));
+            prop_assert!(buf.is_empty());
+            prop_assert_eq!(codec.rejected(), 0);
+        }
+
+        #[test]
+        fn prop_decode_garbage(
+            features in features(),
+            data in proptest::collection::vec(any::<u8>(), 0..256),
+        ) {
+            let mut codec = Codec::new(features);
+            let mut buf = BytesMut::from(&data[..]);
+            while let Ok(Some(_)) = codec.decode(&mut buf) {}
         }
     }
 }
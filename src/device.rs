@@ -0,0 +1,347 @@
+use std::collections::HashSet;
+
+use anyhow::Context;
+use evdev::{
+    AbsInfo, AbsoluteAxisCode, AttributeSetRef, BusType, Device, EvdevEnum, EventType, InputEvent,
+    InputId, KeyCode, LedCode, MiscCode, PropType, RelativeAxisCode, SwitchCode, UinputAbsSetup,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::bytes::{Buf, BufMut, Bytes, BytesMut};
+
+/// Upper bound on the size of encoded descriptors we're willing to receive.
+const MAX_DESCRIPTORS_LEN: u32 = 1 << 20;
+
+/// Capabilities of an input device, enough to create a look-alike virtual
+/// device on the other side with [`crate::uinput::VirtualDevice::new`].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct DeviceInfo {
+    pub name: String,
+    pub input_id: InputId,
+    pub properties: Vec<PropType>,
+    pub keys: Vec<KeyCode>,
+    pub relative_axes: Vec<RelativeAxisCode>,
+    pub absolute_axes: Vec<UinputAbsSetup>,
+    pub switches: Vec<SwitchCode>,
+    pub leds: Vec<LedCode>,
+    pub misc: Vec<MiscCode>,
+}
+
+impl DeviceInfo {
+    pub fn from_device(dev: &Device) -> anyhow::Result<Self> {
+        fn codes<T: EvdevEnum + Copy>(set: Option<&AttributeSetRef<T>>) -> Vec<T> {
+            set.map(|set| set.iter().collect()).unwrap_or_default()
+        }
+        let absolute_axes = match dev.supported_absolute_axes() {
+            Some(axes) => {
+                let state = dev.get_abs_state().context("Get absolute axes state")?;
+                axes.iter()
+                    .map(|axis| UinputAbsSetup::new(axis, AbsInfo::from(state[axis.0 as usize])))
+                    .collect()
+            }
+            None => vec![],
+        };
+        Ok(Self {
+            name: dev.name().unwrap_or("unnamed device").to_owned(),
+            input_id: dev.input_id(),
+            properties: dev.properties().iter().collect(),
+            keys: codes(dev.supported_keys()),
+            relative_axes: codes(dev.supported_relative_axes()),
+            absolute_axes,
+            switches: codes(dev.supported_switches()),
+            leds: codes(dev.supported_leds()),
+            misc: codes(dev.misc_properties()),
+        })
+    }
+
+    /// Combines capabilities of several devices into one.
+    pub fn merge<'a>(
+        name: impl Into<String>,
+        input_id: InputId,
+        infos: impl IntoIterator<Item = &'a DeviceInfo>,
+    ) -> Self {
+        fn extend<T: Copy + PartialEq>(dst: &mut Vec<T>, src: &[T], code: impl Fn(&T) -> u16) {
+            dst.extend_from_slice(src);
+            dst.sort_by_key(code);
+            dst.dedup();
+        }
+        let mut res = Self {
+            name: name.into(),
+            input_id,
+            properties: vec![],
+            keys: vec![],
+            relative_axes: vec![],
+            absolute_axes: vec![],
+            switches: vec![],
+            leds: vec![],
+            misc: vec![],
+        };
+        for info in infos {
+            extend(&mut res.properties, &info.properties, |x| x.0);
+            extend(&mut res.keys, &info.keys, |x| x.0);
+            extend(&mut res.relative_axes, &info.relative_axes, |x| x.0);
+            extend(&mut res.switches, &info.switches, |x| x.0);
+            extend(&mut res.leds, &info.leds, |x| x.0);
+            extend(&mut res.misc, &info.misc, |x| x.0);
+            for axis in &info.absolute_axes {
+                if !res.absolute_axes.iter().any(|x| x.code() == axis.code()) {
+                    res.absolute_axes.push(*axis);
+                }
+            }
+        }
+        res
+    }
+
+    /// The events a device with these capabilities can emit.
+    pub fn capabilities(&self) -> Capabilities {
+        let mut codes = HashSet::new();
+        codes.extend(self.keys.iter().map(|x| (EventType::KEY, x.0)));
+        codes.extend(
+            self.relative_axes
+                .iter()
+                .map(|x| (EventType::RELATIVE, x.0)),
+        );
+        codes.extend(
+            self.absolute_axes
+                .iter()
+                .map(|x| (EventType::ABSOLUTE, x.code())),
+        );
+        codes.extend(self.switches.iter().map(|x| (EventType::SWITCH, x.0)));
+        codes.extend(self.leds.iter().map(|x| (EventType::LED, x.0)));
+        codes.extend(self.misc.iter().map(|x| (EventType::MISC, x.0)));
+        Capabilities(codes)
+    }
+
+    fn encode(&self, dst: &mut BytesMut) -> anyhow::Result<()> {
+        fn put_codes(
+            dst: &mut BytesMut,
+            codes: impl ExactSizeIterator<Item = u16>,
+        ) -> anyhow::Result<()> {
+            dst.put_u16(u16::try_from(codes.len()).context("Too many codes")?);
+            for code in codes {
+                dst.put_u16(code);
+            }
+            Ok(())
+        }
+        let name = self.name.as_bytes();
+        dst.put_u16(u16::try_from(name.len()).context("Device name is too long")?);
+        dst.put_slice(name);
+        dst.put_u16(self.input_id.bus_type().0);
+        dst.put_u16(self.input_id.vendor());
+        dst.put_u16(self.input_id.product());
+        dst.put_u16(self.input_id.version());
+        put_codes(dst, self.properties.iter().map(|x| x.0))?;
+        put_codes(dst, self.keys.iter().map(|x| x.0))?;
+        put_codes(dst, self.relative_axes.iter().map(|x| x.0))?;
+        dst.put_u16(u16::try_from(self.absolute_axes.len()).context("Too many absolute axes")?);
+        for axis in &self.absolute_axes {
+            let info = axis.absinfo();
+            dst.put_u16(axis.code());
+            dst.put_i32(info.value());
+            dst.put_i32(info.minimum());
+            dst.put_i32(info.maximum());
+            dst.put_i32(info.fuzz());
+            dst.put_i32(info.flat());
+            dst.put_i32(info.resolution());
+        }
+        put_codes(dst, self.switches.iter().map(|x| x.0))?;
+        put_codes(dst, self.leds.iter().map(|x| x.0))?;
+        put_codes(dst, self.misc.iter().map(|x| x.0))?;
+        Ok(())
+    }
+
+    fn decode(src: &mut Bytes) -> anyhow::Result<Self> {
+        fn get_codes<T>(src: &mut Bytes, f: impl Fn(u16) -> T) -> anyhow::Result<Vec<T>> {
+            let len = src.try_get_u16()?;
+            (0..len).map(|_| Ok(f(src.try_get_u16()?))).collect()
+        }
+        let name_len = src.try_get_u16()?.into();
+        anyhow::ensure!(src.remaining() >= name_len, "Truncated device name");
+        let name = String::from_utf8_lossy(&src.split_to(name_len)).into_owned();
+        let input_id = InputId::new(
+            BusType(src.try_get_u16()?),
+            src.try_get_u16()?,
+            src.try_get_u16()?,
+            src.try_get_u16()?,
+        );
+        let properties = get_codes(src, PropType)?;
+        let keys = get_codes(src, KeyCode)?;
+        let relative_axes = get_codes(src, RelativeAxisCode)?;
+        let absolute_axes = (0..src.try_get_u16()?)
+            .map(|_| {
+                let code = AbsoluteAxisCode(src.try_get_u16()?);
+                let info = AbsInfo::new(
+                    src.try_get_i32()?,
+                    src.try_get_i32()?,
+                    src.try_get_i32()?,
+                    src.try_get_i32()?,
+                    src.try_get_i32()?,
+                    src.try_get_i32()?,
+                );
+                anyhow::Ok(UinputAbsSetup::new(code, info))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            name,
+            input_id,
+            properties,
+            keys,
+            relative_axes,
+            absolute_axes,
+            switches: get_codes(src, SwitchCode)?,
+            leds: get_codes(src, LedCode)?,
+            misc: get_codes(src, MiscCode)?,
+        })
+    }
+}
+
+/// Sends device descriptors. Used right after the handshake if
+/// [`Features::DEVICES`](crate::handshake::Features::DEVICES) was negotiated.
+pub async fn send(
+    stream: &mut (impl AsyncWrite + Unpin),
+    infos: &[DeviceInfo],
+) -> anyhow::Result<()> {
+    let mut buf = BytesMut::new();
+    buf.put_u32(0);
+    buf.put_u8(u8::try_from(infos.len()).context("Too many devices")?);
+    for info in infos {
+        info.encode(&mut buf).context("Encode device descriptor")?;
+    }
+    let len = u32::try_from(buf.len() - 4).context("Device descriptors are too large")?;
+    buf[..4].copy_from_slice(&len.to_be_bytes());
+    stream
+        .write_all(&buf)
+        .await
+        .context("Send device descriptors")?;
+    stream.flush().await.context("Flush device descriptors")?;
+    Ok(())
+}
+
+/// Receives device descriptors sent with [`send`].
+pub async fn recv(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Vec<DeviceInfo>> {
+    let len = stream
+        .read_u32()
+        .await
+        .context("Read device descriptors length")?;
+    anyhow::ensure!(
+        len <= MAX_DESCRIPTORS_LEN,
+        "Device descriptors are too large: {len} bytes"
+    );
+    let mut buf = vec![0u8; len as usize];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .context("Read device descriptors")?;
+    let mut buf = Bytes::from(buf);
+    let count = buf.try_get_u8()?;
+    let infos = (0..count)
+        .map(|_| DeviceInfo::decode(&mut buf))
+        .collect::<Result<_, _>>()
+        .context("Decode device descriptor")?;
+    anyhow::ensure!(buf.is_empty(), "Trailing data after device descriptors");
+    Ok(infos)
+}
+
+/// [`DeviceInfo`] codes by event type, to check events against without
+/// searching its lists.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct Capabilities(HashSet<(EventType, u16)>);
+
+impl Capabilities {
+    /// Whether the device can emit `evt`.
+    pub fn supports(&self, evt: &InputEvent) -> bool {
+        evt.event_type() == EventType::SYNCHRONIZATION
+            || self.0.contains(&(evt.event_type(), evt.code()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn mouse() -> DeviceInfo {
+        DeviceInfo {
+            name: "Test Mouse".to_owned(),
+            input_id: InputId::new(BusType::BUS_USB, 0x1234, 0x5678, 0x111),
+            properties: vec![PropType::POINTER],
+            keys: vec![KeyCode::BTN_LEFT, KeyCode::BTN_RIGHT],
+            relative_axes: vec![RelativeAxisCode::REL_X, RelativeAxisCode::REL_Y],
+            absolute_axes: vec![],
+            switches: vec![],
+            leds: vec![],
+            misc: vec![],
+        }
+    }
+
+    fn keyboard() -> DeviceInfo {
+        DeviceInfo {
+            name: "Test Keyboard".to_owned(),
+            input_id: InputId::new(BusType::BUS_USB, 0x4321, 0x8765, 0x222),
+            properties: vec![],
+            keys: vec![KeyCode::KEY_A, KeyCode::KEY_CAPSLOCK],
+            relative_axes: vec![],
+            absolute_axes: vec![UinputAbsSetup::new(
+                AbsoluteAxisCode::ABS_VOLUME,
+                AbsInfo::new(0, 0, 100, 1, 2, 3),
+            )],
+            switches: vec![SwitchCode::SW_LID],
+            leds: vec![LedCode::LED_CAPSL, LedCode::LED_NUML],
+            misc: vec![MiscCode::MSC_SCAN],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let infos = vec![mouse(), keyboard()];
+        let mut buf = vec![];
+        send(&mut buf, &infos).await.unwrap();
+        let decoded = recv(&mut &buf[..]).await.unwrap();
+        assert_eq!(decoded, infos);
+    }
+
+    #[tokio::test]
+    async fn test_truncated() {
+        let mut buf = vec![];
+        send(&mut buf, &[keyboard()]).await.unwrap();
+        // fix up length so that only the descriptor itself is truncated
+        let len = (buf.len() - 4 - 3) as u32;
+        buf[..4].copy_from_slice(&len.to_be_bytes());
+        buf.truncate(buf.len() - 3);
+        recv(&mut &buf[..]).await.expect_err("truncated");
+    }
+
+    #[test]
+    fn test_merge() {
+        let id = InputId::new(BusType::BUS_VIRTUAL, 1, 1, 1);
+        let merged = DeviceInfo::merge("merged", id.clone(), &[mouse(), keyboard(), mouse()]);
+        assert_eq!(merged.name, "merged");
+        assert_eq!(merged.input_id, id);
+        assert_eq!(merged.properties, [PropType::POINTER]);
+        assert_eq!(
+            merged.keys,
+            [
+                KeyCode::KEY_A,
+                KeyCode::KEY_CAPSLOCK,
+                KeyCode::BTN_LEFT,
+                KeyCode::BTN_RIGHT
+            ]
+        );
+        assert_eq!(
+            merged.relative_axes,
+            [RelativeAxisCode::REL_X, RelativeAxisCode::REL_Y]
+        );
+        assert_eq!(merged.absolute_axes, keyboard().absolute_axes);
+    }
+
+    #[test]
+    fn test_supports() {
+        let kbd = keyboard().capabilities();
+        let evt = |type_: EventType, code| InputEvent::new(type_.0, code, 1);
+        assert!(kbd.supports(&evt(EventType::KEY, KeyCode::KEY_A.0)));
+        assert!(!kbd.supports(&evt(EventType::KEY, KeyCode::BTN_LEFT.0)));
+        assert!(kbd.supports(&evt(EventType::ABSOLUTE, AbsoluteAxisCode::ABS_VOLUME.0)));
+        assert!(!kbd.supports(&evt(EventType::ABSOLUTE, AbsoluteAxisCode::ABS_X.0)));
+        assert!(kbd.supports(&evt(EventType::LED, LedCode::LED_CAPSL.0)));
+        assert!(!kbd.supports(&evt(EventType::RELATIVE, RelativeAxisCode::REL_X.0)));
+        assert!(!kbd.supports(&evt(EventType::FORCEFEEDBACK, 0)));
+    }
+}
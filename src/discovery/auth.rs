@@ -0,0 +1,144 @@
+//! Optional shared-secret authentication for discovery. Requests carry a
+//! random nonce, responses echo it, and both end in an HMAC-SHA256 over the
+//! rest of the packet. Without it, anyone on the segment can answer discovery
+//! and have `hoips` send them keystrokes.
+//!
+//! Responses also sign the address they're sent from, and ones arriving from
+//! any other are dropped, so a copy sent on from elsewhere can't redirect
+//! `hoips`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::Context;
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
+
+const KEY_CONTEXT: &[u8] = b"hoip discovery v1";
+
+/// How long responses to a request are accepted.
+const NONCE_LIFETIME: Duration = Duration::from_secs(5);
+
+pub type Nonce = [u8; 16];
+
+pub const MAC_LEN: usize = 32;
+
+/// Discovery secret options shared by `hoips` and `hoipc`.
+#[derive(clap::Args, Debug)]
+pub struct SecretArgs {
+    /// Passphrase for authenticating discovery. Both sides have to use the
+    /// same one; requests and responses without it are ignored, as are peers
+    /// that only speak the older discovery format.
+    #[arg(
+        long,
+        env = "HOIP_DISCOVERY_SECRET",
+        hide_env_values = true,
+        conflicts_with = "discovery_secret_file"
+    )]
+    pub discovery_secret: Option<String>,
+    /// File with the discovery secret, as an alternative to
+    /// `--discovery-secret`. Any contents will do.
+    #[arg(long)]
+    pub discovery_secret_file: Option<PathBuf>,
+}
+
+impl SecretArgs {
+    /// Loads the secret, if configured.
+    pub fn load(&self) -> anyhow::Result<Option<Secret>> {
+        let secret = match (&self.discovery_secret, &self.discovery_secret_file) {
+            (Some(secret), _) => secret.as_bytes().to_vec(),
+            (None, Some(path)) => std::fs::read(path)
+                .with_context(|| format!("Read discovery secret from {}", path.display()))?,
+            (None, None) => return Ok(None),
+        };
+        anyhow::ensure!(!secret.is_empty(), "Discovery secret is empty");
+        Ok(Some(Secret::derive(&secret)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Secret(hmac::Key);
+
+impl Secret {
+    pub fn derive(secret: &[u8]) -> Self {
+        let key = Sha256::new()
+            .chain_update(KEY_CONTEXT)
+            .chain_update(secret)
+            .finalize();
+        Self(hmac::Key::new(hmac::HMAC_SHA256, &key))
+    }
+
+    pub fn sign(&self, data: &[u8]) -> [u8; MAC_LEN] {
+        hmac::sign(&self.0, data).as_ref().try_into().unwrap()
+    }
+
+    /// Checks `mac` in constant time.
+    pub fn verify(&self, data: &[u8], mac: &[u8]) -> bool {
+        hmac::verify(&self.0, data, mac).is_ok()
+    }
+}
+
+/// Nonces of our recent requests, and the instances that answered them, so
+/// that a response is only accepted while fresh, and only once.
+#[derive(Default)]
+pub struct Nonces {
+    issued: HashMap<Nonce, (Instant, HashSet<u64>)>,
+}
+
+impl Nonces {
+    pub fn issue(&mut self) -> anyhow::Result<Nonce> {
+        let now = Instant::now();
+        self.issued
+            .retain(|_, (issued, _)| now.duration_since(*issued) < NONCE_LIFETIME);
+        let mut nonce = Nonce::default();
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .map_err(|_| anyhow::anyhow!("Generate nonce"))?;
+        self.issued.insert(nonce, (now, HashSet::new()));
+        Ok(nonce)
+    }
+
+    /// When the request with `nonce` was sent, if a response to it from
+    /// `instance` is expected.
+    pub fn accept(&mut self, nonce: &Nonce, instance: u64) -> Option<Instant> {
+        let (issued, seen) = self.issued.get_mut(nonce)?;
+        (issued.elapsed() < NONCE_LIFETIME && seen.insert(instance)).then_some(*issued)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_secret() {
+        let secret = Secret::derive(b"hunter2");
+        let mac = secret.sign(b"data");
+        assert!(secret.verify(b"data", &mac));
+        assert!(!secret.verify(b"date", &mac));
+        assert!(!Secret::derive(b"hunter3").verify(b"data", &mac));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_nonces() {
+        let (a, b) = (1, 2);
+        let mut nonces = Nonces::default();
+        let nonce = nonces.issue().unwrap();
+        let issued = Instant::now();
+        assert_eq!(nonces.accept(&Nonce::default(), a), None);
+        assert_eq!(nonces.accept(&nonce, a), Some(issued));
+        assert_eq!(nonces.accept(&nonce, b), Some(issued));
+        // replayed
+        assert_eq!(nonces.accept(&nonce, a), None);
+        let nonce = nonces.issue().unwrap();
+        tokio::time::advance(NONCE_LIFETIME).await;
+        assert_eq!(nonces.accept(&nonce, a), None);
+    }
+}
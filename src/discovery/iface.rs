@@ -0,0 +1,277 @@
+//! Network interfaces discovery runs on, when there's more than one.
+
+use std::{
+    collections::BTreeMap,
+    io::{self, IoSlice, IoSliceMut},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    os::fd::AsRawFd,
+    task::{Context as TaskContext, Poll},
+};
+
+use anyhow::Context;
+use getifaddrs::InterfaceFlags;
+use nix::sys::socket::{
+    AddressFamily, ControlMessage, ControlMessageOwned, MsgFlags, SockaddrLike, SockaddrStorage,
+    recvmsg, sendmsg, setsockopt, sockopt,
+};
+use tokio::{io::Interest, net::UdpSocket};
+
+/// Interface discovery joins the multicast group on and sends requests from.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Interface {
+    pub name: String,
+    pub index: u32,
+    /// Address IPv4 multicast is sent from, if the interface has one.
+    pub ipv4: Option<Ipv4Addr>,
+}
+
+impl Interface {
+    /// Interfaces named in `names`, or every multicast-capable interface
+    /// that's up with `all`. Loopback is never picked by `all`.
+    pub fn select(names: &[String], all: bool) -> anyhow::Result<Vec<Self>> {
+        let mut found = BTreeMap::<u32, Self>::new();
+        for iface in getifaddrs::getifaddrs().context("Getting interface addresses")? {
+            let Some(index) = iface.index else {
+                continue;
+            };
+            let wanted = if all {
+                iface
+                    .flags
+                    .contains(InterfaceFlags::UP | InterfaceFlags::MULTICAST)
+                    && !iface.flags.contains(InterfaceFlags::LOOPBACK)
+            } else {
+                names.contains(&iface.name)
+            };
+            if !wanted {
+                continue;
+            }
+            let entry = found.entry(index).or_insert_with(|| Self {
+                name: iface.name.clone(),
+                index,
+                ipv4: None,
+            });
+            if let Some(IpAddr::V4(ip)) = iface.address.ip_addr() {
+                entry.ipv4.get_or_insert(ip);
+            }
+        }
+        for name in names {
+            anyhow::ensure!(
+                found.values().any(|iface| &iface.name == name),
+                "No interface named {name}"
+            );
+        }
+        Ok(found.into_values().collect())
+    }
+}
+
+/// Where a datagram came in, as far as the kernel tells.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub(crate) struct Ingress {
+    /// Index of the interface.
+    pub index: Option<u32>,
+    /// Our unicast address the datagram was for: the one IPv4 would answer
+    /// from, or the IPv6 destination unless that was a multicast group.
+    pub local: Option<IpAddr>,
+}
+
+impl Ingress {
+    /// Our address an answer to `peer` should come from.
+    pub fn reply_source(&self, peer: SocketAddr) -> io::Result<IpAddr> {
+        if let Some(local) = self.local {
+            return Ok(local.to_canonical());
+        }
+        // what the kernel's routing would pick for an unbound socket.
+        let unspecified = match peer {
+            SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        };
+        let probe = std::net::UdpSocket::bind((unspecified, 0))?;
+        probe.connect(peer)?;
+        Ok(probe.local_addr()?.ip().to_canonical())
+    }
+}
+
+/// Makes the kernel tell where datagrams to `socket` come in, for
+/// [`poll_recv_from`].
+pub(crate) fn report_ingress(socket: &UdpSocket) -> anyhow::Result<()> {
+    if socket.local_addr().context("Get local address")?.is_ipv6() {
+        setsockopt(socket, sockopt::Ipv6RecvPacketInfo, &true).context("Set IPV6_RECVPKTINFO")?;
+        // IPv4 senders of a dual-stack socket, where the kernel supports it.
+        let _ = setsockopt(socket, sockopt::Ipv4PacketInfo, &true);
+    } else {
+        setsockopt(socket, sockopt::Ipv4PacketInfo, &true).context("Set IP_PKTINFO")?;
+    }
+    Ok(())
+}
+
+/// Like [`UdpSocket::poll_recv_from`], but also tells where the datagram
+/// came in, if [`report_ingress`] was called.
+pub(crate) fn poll_recv_from(
+    socket: &UdpSocket,
+    cx: &mut TaskContext<'_>,
+    buf: &mut [u8],
+) -> Poll<io::Result<(usize, SocketAddr, Ingress)>> {
+    loop {
+        futures::ready!(socket.poll_recv_ready(cx))?;
+        match socket.try_io(Interest::READABLE, || recv_from(socket, buf)) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            res => return Poll::Ready(res),
+        }
+    }
+}
+
+fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, Ingress)> {
+    let mut cmsg = nix::cmsg_space!(libc::in6_pktinfo);
+    let mut iov = [IoSliceMut::new(buf)];
+    let msg = recvmsg::<SockaddrStorage>(
+        socket.as_raw_fd(),
+        &mut iov,
+        Some(&mut cmsg),
+        MsgFlags::empty(),
+    )?;
+    let ingress = msg
+        .cmsgs()
+        .into_iter()
+        .flatten()
+        .find_map(|cmsg| match cmsg {
+            ControlMessageOwned::Ipv4PacketInfo(info) => Some(Ingress {
+                index: u32::try_from(info.ipi_ifindex).ok(),
+                local: Some(Ipv4Addr::from(u32::from_be(info.ipi_spec_dst.s_addr)).into()),
+            }),
+            ControlMessageOwned::Ipv6PacketInfo(info) => {
+                let dest = Ipv6Addr::from(info.ipi6_addr.s6_addr);
+                Some(Ingress {
+                    index: Some(info.ipi6_ifindex),
+                    local: (!dest.is_multicast()).then_some(dest.into()),
+                })
+            }
+            _ => None,
+        })
+        .unwrap_or_default();
+    let from = msg
+        .address
+        .as_ref()
+        .and_then(|addr| match addr.family()? {
+            AddressFamily::Inet => Some(SocketAddrV4::from(*addr.as_sockaddr_in()?).into()),
+            AddressFamily::Inet6 => Some(SocketAddrV6::from(*addr.as_sockaddr_in6()?).into()),
+            _ => None,
+        })
+        .ok_or_else(|| io::Error::other("Datagram without a source address"))?;
+    Ok((msg.bytes, from, ingress))
+}
+
+/// Like [`UdpSocket::send_to`], but from our address `source` rather than
+/// whichever the kernel would pick.
+pub(crate) async fn send_from(
+    socket: &UdpSocket,
+    buf: &[u8],
+    target: SocketAddr,
+    source: IpAddr,
+) -> io::Result<()> {
+    let v4_info;
+    let v6_info;
+    let cmsg = match source {
+        IpAddr::V4(ip) => {
+            v4_info = libc::in_pktinfo {
+                ipi_ifindex: 0,
+                ipi_spec_dst: libc::in_addr {
+                    s_addr: u32::from(ip).to_be(),
+                },
+                ipi_addr: libc::in_addr { s_addr: 0 },
+            };
+            ControlMessage::Ipv4PacketInfo(&v4_info)
+        }
+        IpAddr::V6(ip) => {
+            v6_info = libc::in6_pktinfo {
+                ipi6_addr: libc::in6_addr {
+                    s6_addr: ip.octets(),
+                },
+                ipi6_ifindex: match target {
+                    SocketAddr::V6(target) => target.scope_id(),
+                    SocketAddr::V4(_) => 0,
+                },
+            };
+            ControlMessage::Ipv6PacketInfo(&v6_info)
+        }
+    };
+    let dest = SockaddrStorage::from(target);
+    let iov = [IoSlice::new(buf)];
+    socket
+        .async_io(Interest::WRITABLE, || {
+            sendmsg(
+                socket.as_raw_fd(),
+                &iov,
+                &[cmsg],
+                MsgFlags::empty(),
+                Some(&dest),
+            )
+            .map_err(io::Error::from)
+        })
+        .await?;
+    Ok(())
+}
+
+/// Name of the interface with `index`.
+pub fn interface_name(index: u32) -> Option<String> {
+    getifaddrs::if_indextoname(index).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_select() {
+        let any = getifaddrs::getifaddrs()
+            .unwrap()
+            .find(|iface| iface.index.is_some())
+            .expect("some interface");
+        let found = Interface::select(std::slice::from_ref(&any.name), false).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, any.name);
+        assert_eq!(Some(found[0].index), any.index);
+        assert!(Interface::select(&["no-such-iface0".to_owned()], false).is_err());
+        assert!(Interface::select(&[], true).unwrap().iter().all(|iface| {
+            getifaddrs::getifaddrs()
+                .unwrap()
+                .filter(|other| other.name == iface.name)
+                .all(|other| !other.flags.contains(InterfaceFlags::LOOPBACK))
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_ingress() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        report_ingress(&socket).unwrap();
+        let addr = socket.local_addr().unwrap();
+        socket.send_to(b"hi", addr).await.unwrap();
+        let mut buf = [0u8; 8];
+        let (len, from, ingress) = std::future::poll_fn(|cx| poll_recv_from(&socket, cx, &mut buf))
+            .await
+            .unwrap();
+        assert_eq!((&buf[..len], from), (&b"hi"[..], addr));
+        assert_eq!(ingress.local, Some(addr.ip()));
+        assert_eq!(ingress.reply_source(from).unwrap(), addr.ip());
+        // whatever it's called, loopback traffic comes in on loopback.
+        let name = interface_name(ingress.index.unwrap()).unwrap();
+        assert!(
+            getifaddrs::getifaddrs()
+                .unwrap()
+                .any(|iface| iface.name == name && iface.flags.contains(InterfaceFlags::LOOPBACK))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_from() {
+        let socket = UdpSocket::bind("0.0.0.0:0").await.unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        // all of 127/8 is ours on Linux.
+        let source = IpAddr::from([127, 0, 0, 2]);
+        send_from(&socket, b"hi", peer.local_addr().unwrap(), source)
+            .await
+            .unwrap();
+        let mut buf = [0u8; 8];
+        let (len, from) = peer.recv_from(&mut buf).await.unwrap();
+        assert_eq!((&buf[..len], from.ip()), (&b"hi"[..], source));
+    }
+}
@@ -1,31 +1,119 @@
+mod auth;
 mod crc;
+mod iface;
 mod packet;
+mod tlv;
 
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Mutex,
     task::Poll,
     time::Duration,
 };
 
 use anyhow::Context;
 use futures::{Stream, never::Never};
+use sha2::{Digest, Sha256};
+use tokio::time::Instant;
 
-use self::packet::Packet;
+pub(crate) use self::iface::{poll_recv_from, report_ingress};
+use self::{
+    auth::{Nonce, Nonces},
+    iface::send_from,
+    packet::Packet,
+    tlv::TlvPacket,
+};
+pub use self::{
+    auth::{Secret, SecretArgs},
+    iface::{Interface, interface_name},
+};
+use crate::{acl::Acl, handshake::PROTOCOL_VERSION, transport::Transport};
 
 pub const DEFAULT_MULTICAST_SOCKET_V4: &str = "224.0.0.83:27056";
 pub const DEFAULT_MULTICAST_SOCKET_V6: &str = "[ff02::686F:6970]:27056";
 const DISC_REQ_REF: &[u8] = Packet::REQUEST.as_bytes();
+/// Large enough for any packet we send.
+const MAX_PACKET_SIZE: usize = 2048;
+
+/// What a peer tells about itself in discovery packets. Older peers only tell
+/// their port, so everything here may be missing.
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct PeerInfo {
+    pub hostname: Option<String>,
+    /// `--name` of the peer.
+    pub name: Option<String>,
+    /// Tells peers apart across restarts and address changes, `0` if unknown.
+    pub instance: u64,
+    /// See [`PROTOCOL_VERSION`].
+    pub version: Option<u16>,
+    /// Transports the peer accepts.
+    pub transports: Vec<Transport>,
+}
+
+impl PeerInfo {
+    /// Describes this machine. The instance id comes from the machine id,
+    /// `name` and `port`, so it stays the same as long as they do.
+    pub fn local(name: &str, port: u16, transport: Transport) -> Self {
+        let hostname = nix::unistd::gethostname()
+            .ok()
+            .and_then(|hostname| hostname.into_string().ok());
+        let machine_id = std::fs::read_to_string("/etc/machine-id")
+            .ok()
+            .or_else(|| hostname.clone())
+            .unwrap_or_default();
+        let hash = Sha256::new()
+            .chain_update(machine_id.trim())
+            .chain_update([0])
+            .chain_update(name)
+            .chain_update([0])
+            .chain_update(port.to_be_bytes())
+            .finalize();
+        Self {
+            hostname,
+            name: Some(name.to_owned()),
+            instance: u64::from_be_bytes(hash[..8].try_into().unwrap()),
+            version: Some(PROTOCOL_VERSION),
+            transports: vec![transport],
+        }
+    }
+}
+
+/// A peer that answered discovery.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Discovered {
+    pub addr: SocketAddr,
+    /// Interface the response came in on, if known.
+    pub interface: Option<String>,
+    pub info: PeerInfo,
+    /// When the request this answers was sent, if known. Only signed
+    /// responses tell which request they answer.
+    pub requested: Option<Instant>,
+}
 
 pub struct Discovery {
     bind: SocketAddr,
     socket: tokio::net::UdpSocket,
     disc_mcst: SocketAddr,
+    info: PeerInfo,
+    secret: Option<Secret>,
+    nonces: Mutex<Nonces>,
+    interfaces: Vec<Interface>,
+    /// Held while choosing the IPv4 multicast interface and sending.
+    send_lock: tokio::sync::Mutex<()>,
 }
 
 impl Discovery {
+    /// `info` goes into the packets we send. With a `secret`, only peers that
+    /// know it are answered and accepted.
+    ///
+    /// Joins the multicast group and sends on each of `interfaces`, or on the
+    /// one `bind_addr` implies if there are none.
     pub async fn new(
         mut discovery_multicast: SocketAddr,
         bind_addr: SocketAddr,
+        info: PeerInfo,
+        secret: Option<Secret>,
+        interfaces: Vec<Interface>,
     ) -> anyhow::Result<Self> {
         // this weirdness instead of SocketAddr::new to preserve scope_id.
         let mut discovery_sock = bind_addr;
@@ -34,6 +122,31 @@ impl Discovery {
             .await
             .context("Bind UDP socket")?;
         match &mut discovery_multicast {
+            SocketAddr::V4(mcast_v4) if !interfaces.is_empty() => {
+                for iface in &interfaces {
+                    let Some(ip) = iface.ipv4 else {
+                        tracing::warn!(
+                            interface = iface.name,
+                            "Interface has no IPv4 address, skipping"
+                        );
+                        continue;
+                    };
+                    socket
+                        .join_multicast_v4(*mcast_v4.ip(), ip)
+                        .with_context(|| format!("Join V4 multicast on {}", iface.name))?;
+                }
+            }
+            SocketAddr::V6(mcast_v6) if !interfaces.is_empty() => {
+                anyhow::ensure!(
+                    bind_addr.is_ipv6(),
+                    "Bind address is V4 but multicast is V6"
+                );
+                for iface in &interfaces {
+                    socket
+                        .join_multicast_v6(mcast_v6.ip(), iface.index)
+                        .with_context(|| format!("Join V6 multicast on {}", iface.name))?;
+                }
+            }
             SocketAddr::V4(mcast_v4) => {
                 socket
                     .join_multicast_v4(
@@ -56,6 +169,7 @@ impl Discovery {
                 mcast_v6.set_scope_id(iface);
             }
         }
+        report_ingress(&socket)?;
         if discovery_sock.is_ipv4() {
             socket
                 .set_multicast_loop_v4(false)
@@ -70,32 +184,110 @@ impl Discovery {
             socket,
             bind: bind_addr,
             disc_mcst: discovery_multicast,
+            info,
+            secret,
+            nonces: Mutex::default(),
+            interfaces,
+            send_lock: tokio::sync::Mutex::default(),
         })
     }
 
-    pub async fn respond(&self) -> anyhow::Result<()> {
-        let mut buf = [0u8; size_of::<Packet>() + 1];
+    /// Sends `buf` to the multicast group, once per interface.
+    async fn send_multicast(&self, buf: &[u8]) -> anyhow::Result<()> {
+        if self.interfaces.is_empty() {
+            self.socket.send_to(buf, self.disc_mcst).await?;
+            return Ok(());
+        }
+        let _lock = self.send_lock.lock().await;
+        let mut sent = false;
+        for iface in &self.interfaces {
+            let dest = match self.disc_mcst {
+                SocketAddr::V4(_) => {
+                    let Some(ip) = iface.ipv4 else {
+                        continue;
+                    };
+                    socket2::SockRef::from(&self.socket)
+                        .set_multicast_if_v4(&ip)
+                        .with_context(|| format!("Set multicast interface {}", iface.name))?;
+                    self.disc_mcst
+                }
+                SocketAddr::V6(mut mcast_v6) => {
+                    mcast_v6.set_scope_id(iface.index);
+                    mcast_v6.into()
+                }
+            };
+            // one interface going down shouldn't stop discovery on the rest.
+            match self.socket.send_to(buf, dest).await {
+                Ok(_) => sent = true,
+                Err(e) => tracing::warn!(interface = iface.name, "Multicast send failed: {e}"),
+            }
+        }
+        anyhow::ensure!(sent, "Multicast send failed on every interface");
+        Ok(())
+    }
+
+    /// `source` is the address the response is sent from, if it's signed.
+    fn response(&self, nonce: Option<Nonce>, source: Option<IpAddr>) -> Vec<u8> {
+        TlvPacket {
+            port: self.bind.port(),
+            nonce,
+            source,
+            info: self.info.clone(),
+        }
+        .to_bytes(self.secret.as_ref())
+    }
+
+    /// Answers discovery requests from peers `acl` permits, in the format
+    /// they asked in.
+    pub async fn respond(&self, acl: &Acl) -> anyhow::Result<()> {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
         loop {
-            let (sz, addr) = self
-                .socket
-                .recv_from(&mut buf)
-                .await
-                .context("Recv request from multicast socket")?;
-            if sz != size_of::<Packet>() || !matches!(&buf[..sz], DISC_REQ_REF) {
+            let (sz, addr, ingress) =
+                std::future::poll_fn(|cx| poll_recv_from(&self.socket, cx, &mut buf))
+                    .await
+                    .context("Recv request from multicast socket")?;
+            let legacy = Packet::try_from_bytes(&buf[..sz]).is_some_and(Packet::is_request);
+            let Some(request) = TlvPacket::try_from_bytes(&buf[..sz], self.secret.as_ref()) else {
+                continue;
+            };
+            if !request.is_request() || (self.secret.is_some() && request.nonce.is_none()) {
+                continue;
+            }
+            if !acl.permits(addr) {
+                tracing::debug!(
+                    requester = %addr.ip(),
+                    "Ignoring discovery request from disallowed address"
+                );
                 continue;
             }
             tracing::info!(
                 requester = %addr.ip(),
+                requester_name = request.info.name,
                 multicast_socket = %self.disc_mcst,
                 "Got discovery request"
             );
-            self.socket
-                .send_to(
-                    &Packet::new(self.bind.port()),
-                    SocketAddr::new(addr.ip(), self.disc_mcst.port()),
-                )
-                .await
-                .context("Send response to UDP socket")?;
+            // keeps the scope of link-local requesters.
+            let mut reply_to = addr;
+            reply_to.set_port(self.disc_mcst.port());
+            // signed responses tell where they come from, so they must.
+            let source = match self.secret {
+                Some(_) => Some(
+                    ingress
+                        .reply_source(reply_to)
+                        .context("Find address to respond from")?,
+                ),
+                None => None,
+            };
+            let response = if legacy {
+                Packet::new(self.bind.port()).to_vec()
+            } else {
+                self.response(request.nonce, source)
+            };
+            match source {
+                Some(source) => send_from(&self.socket, &response, reply_to, source).await,
+                None => self.socket.send_to(&response, reply_to).await.map(drop),
+            }
+            .context("Send response to UDP socket")?;
             tracing::info!(
                 requester = %addr.ip(),
                 self_addr = %self.bind,
@@ -104,9 +296,13 @@ impl Discovery {
         }
     }
 
+    /// Announces us without being asked. Does nothing with a secret, as
+    /// there's no nonce to prove the announcement fresh.
     pub async fn advertise(&self) -> anyhow::Result<()> {
-        self.socket
-            .send_to(&Packet::new(self.bind.port()), self.disc_mcst)
+        if self.secret.is_some() {
+            return Ok(());
+        }
+        self.send_multicast(&self.response(None, None))
             .await
             .context("Advertise to UDP socket")?;
         tracing::info!(
@@ -117,52 +313,112 @@ impl Discovery {
         Ok(())
     }
 
+    /// Sends a request every `period`.
     pub async fn discover(&self, period: Duration) -> anyhow::Result<Never> {
         let mut interval = tokio::time::interval(period);
         interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         loop {
             interval.tick().await;
-            tracing::info!(
-                multicast_socket = %self.disc_mcst,
-                "Broadcast discovery request"
-            );
-            self.socket
-                .send_to(DISC_REQ_REF, self.disc_mcst)
+            self.request().await?;
+        }
+    }
+
+    /// Sends a single request.
+    pub async fn request(&self) -> anyhow::Result<()> {
+        tracing::info!(
+            multicast_socket = %self.disc_mcst,
+            "Broadcast discovery request"
+        );
+        let nonce = match self.secret {
+            Some(_) => Some(self.nonces.lock().unwrap().issue()?),
+            None => None,
+        };
+        let request = TlvPacket {
+            port: 0,
+            nonce,
+            source: None,
+            info: self.info.clone(),
+        };
+        self.send_multicast(&request.to_bytes(self.secret.as_ref()))
+            .await
+            .context("Send discovery request to UDP socket")?;
+        if self.secret.is_none() {
+            // older peers only answer these.
+            self.send_multicast(DISC_REQ_REF)
                 .await
                 .context("Send discovery request to UDP socket")?;
         }
+        Ok(())
     }
 
-    pub fn discovered(&self) -> impl Stream<Item = anyhow::Result<SocketAddr>> {
-        let mut buf = [0u8; size_of::<Packet>() + 1];
+    /// Responses to [`Self::discover`] and advertisements. Peers that
+    /// understand both formats may answer twice. With a secret, only fresh
+    /// signed responses to our own requests, sent from the address they're
+    /// signed for, count, once per instance.
+    pub fn discovered(&self) -> impl Stream<Item = anyhow::Result<Discovered>> {
+        let mut buf = [0u8; MAX_PACKET_SIZE];
         futures::stream::poll_fn(move |cx| {
-            let mut buf = tokio::io::ReadBuf::new(&mut buf);
-            let (port, ip) = loop {
-                let recv = futures::ready!(self.socket.poll_recv_from(cx, &mut buf));
-                let addr = match recv {
+            let (pkt, from, ingress, requested) = loop {
+                let recv = futures::ready!(poll_recv_from(&self.socket, cx, &mut buf));
+                let (len, addr, ingress) = match recv {
                     Ok(x) => x,
                     Err(e) => return Poll::Ready(Some(Err(e.into()))),
                 };
-                let Some(pkt) = Packet::try_from_bytes(buf.filled()) else {
+                let Some(pkt) = TlvPacket::try_from_bytes(&buf[..len], self.secret.as_ref()) else {
                     continue;
                 };
                 if pkt.is_request() {
                     continue;
                 }
-                break (pkt.port, addr.ip());
+                let mut requested = None;
+                if self.secret.is_some() {
+                    // before the nonce is used up, so a copy sent on from
+                    // elsewhere can't shadow the genuine response.
+                    if pkt.source.map(|ip| ip.to_canonical()) != Some(addr.ip().to_canonical()) {
+                        tracing::debug!(
+                            addr = %addr.ip(),
+                            source = ?pkt.source,
+                            "Ignoring discovery response from an address it isn't signed for"
+                        );
+                        continue;
+                    }
+                    requested = pkt.nonce.and_then(|nonce| {
+                        self.nonces
+                            .lock()
+                            .unwrap()
+                            .accept(&nonce, pkt.info.instance)
+                    });
+                    if requested.is_none() {
+                        tracing::debug!(addr = %addr.ip(), "Ignoring stale discovery response");
+                        continue;
+                    }
+                }
+                break (pkt, addr, ingress, requested);
             };
-            let mut sock_addr = SocketAddr::new(ip, port);
+            // this weirdness instead of SocketAddr::new to preserve scope_id.
+            let mut sock_addr = from;
+            sock_addr.set_port(pkt.port);
             if let SocketAddr::V6(sock_addr) = &mut sock_addr
+                && sock_addr.scope_id() == 0
                 && let SocketAddr::V6(mcast_addr) = &self.disc_mcst
             {
-                sock_addr.set_scope_id(mcast_addr.scope_id());
+                sock_addr.set_scope_id(ingress.index.unwrap_or(mcast_addr.scope_id()));
             }
+            let interface = ingress.index.and_then(interface_name);
             tracing::info!(
                 addr = %sock_addr,
+                interface,
+                name = pkt.info.name,
+                hostname = pkt.info.hostname,
                 multicast_socket = %self.disc_mcst,
                 "Got discovery response"
             );
-            Poll::Ready(Some(Ok(sock_addr)))
+            Poll::Ready(Some(Ok(Discovered {
+                addr: sock_addr,
+                interface,
+                info: pkt.info,
+                requested,
+            })))
         })
     }
 }
@@ -2,6 +2,8 @@ use super::crc::CRC8_9B;
 
 const DISC_PFX: [u8; 4] = *b"HOIP";
 
+/// Original fixed-size discovery packet, only a port. Still understood, and
+/// answered in kind, see [`super::tlv`].
 #[repr(C, packed(1))]
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub struct Packet {
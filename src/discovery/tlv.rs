@@ -0,0 +1,298 @@
+//! Versioned discovery packet: `"HOIP"`, a version byte, a kind byte, then
+//! type-length-value fields, and a CRC8 over everything before it. Unknown
+//! fields are skipped, so new ones can be added without bumping the version.
+//!
+//! Legacy [`Packet`]s are exactly 7 bytes. Requests always carry the instance
+//! id, so that these never are.
+//!
+//! With a [`Secret`], packets carry a nonce and end with a MAC field, see
+//! [`super::auth`].
+
+use std::net::IpAddr;
+
+use clap::ValueEnum;
+
+use super::{
+    PeerInfo,
+    auth::{MAC_LEN, Nonce, Secret},
+    crc::CRC8_9B,
+    packet::Packet,
+};
+use crate::transport::Transport;
+
+const DISC_PFX: [u8; 4] = *b"HOIP";
+const VERSION: u8 = 2;
+
+const KIND_REQUEST: u8 = 0;
+const KIND_RESPONSE: u8 = 1;
+
+const FIELD_PORT: u8 = 1;
+const FIELD_HOSTNAME: u8 = 2;
+const FIELD_NAME: u8 = 3;
+const FIELD_INSTANCE: u8 = 4;
+const FIELD_VERSION: u8 = 5;
+const FIELD_TRANSPORTS: u8 = 6;
+const FIELD_NONCE: u8 = 7;
+/// Always the last field, covers everything before it.
+const FIELD_MAC: u8 = 8;
+const FIELD_SOURCE: u8 = 9;
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct TlvPacket {
+    /// Port the sender listens on, `0` for requests.
+    pub port: u16,
+    /// Request nonce, echoed in responses.
+    pub nonce: Option<Nonce>,
+    /// Address a signed response is sent from.
+    pub source: Option<IpAddr>,
+    pub info: PeerInfo,
+}
+
+impl TlvPacket {
+    pub fn is_request(&self) -> bool {
+        self.port == 0
+    }
+
+    /// Signs the packet if `secret` is given.
+    pub fn to_bytes(&self, secret: Option<&Secret>) -> Vec<u8> {
+        let mut buf = DISC_PFX.to_vec();
+        buf.push(VERSION);
+        if self.is_request() {
+            buf.push(KIND_REQUEST);
+        } else {
+            buf.push(KIND_RESPONSE);
+            field(&mut buf, FIELD_PORT, &self.port.to_be_bytes());
+        }
+        let info = &self.info;
+        if let Some(hostname) = &info.hostname {
+            field(&mut buf, FIELD_HOSTNAME, hostname.as_bytes());
+        }
+        if let Some(name) = &info.name {
+            field(&mut buf, FIELD_NAME, name.as_bytes());
+        }
+        field(&mut buf, FIELD_INSTANCE, &info.instance.to_be_bytes());
+        if let Some(version) = info.version {
+            field(&mut buf, FIELD_VERSION, &version.to_be_bytes());
+        }
+        if !info.transports.is_empty() {
+            let transports = info
+                .transports
+                .iter()
+                .filter_map(|t| t.to_possible_value())
+                .map(|t| t.get_name().to_owned())
+                .collect::<Vec<_>>()
+                .join(",");
+            field(&mut buf, FIELD_TRANSPORTS, transports.as_bytes());
+        }
+        if let Some(nonce) = &self.nonce {
+            field(&mut buf, FIELD_NONCE, nonce);
+        }
+        match self.source {
+            Some(IpAddr::V4(ip)) => field(&mut buf, FIELD_SOURCE, &ip.octets()),
+            Some(IpAddr::V6(ip)) => field(&mut buf, FIELD_SOURCE, &ip.octets()),
+            None => {}
+        }
+        if let Some(secret) = secret {
+            let mac = secret.sign(&buf);
+            field(&mut buf, FIELD_MAC, &mac);
+        }
+        buf.push(CRC8_9B.calc(&buf, buf.len(), 0));
+        buf
+    }
+
+    /// Parses either packet format. With a `secret`, only accepts packets
+    /// signed with it, which legacy ones never are.
+    pub fn try_from_bytes(bytes: &[u8], secret: Option<&Secret>) -> Option<Self> {
+        if let Some(pkt) = Packet::try_from_bytes(bytes) {
+            return secret.is_none().then_some(Self {
+                port: pkt.port,
+                nonce: None,
+                source: None,
+                info: PeerInfo::default(),
+            });
+        }
+        let (crc, body) = bytes.split_last()?;
+        let rest = body.strip_prefix(&DISC_PFX)?;
+        let [VERSION, kind, rest @ ..] = rest else {
+            return None;
+        };
+        if *crc != CRC8_9B.calc(body, body.len(), 0) {
+            return None;
+        }
+        let mut this = Self {
+            port: 0,
+            nonce: None,
+            source: None,
+            info: PeerInfo::default(),
+        };
+        let mut signed = false;
+        let mut rest = rest;
+        while let [typ, len, tail @ ..] = rest {
+            if *typ == FIELD_MAC {
+                let mac = tail
+                    .get(..MAC_LEN)
+                    .filter(|_| usize::from(*len) == MAC_LEN)?;
+                let covered = &body[..body.len() - rest.len()];
+                signed = secret.is_some_and(|secret| secret.verify(covered, mac));
+                rest = &tail[MAC_LEN..];
+                break;
+            }
+            let (value, tail) = tail.split_at_checked(usize::from(*len))?;
+            rest = tail;
+            let string = || Some(String::from_utf8_lossy(value).into_owned());
+            let info = &mut this.info;
+            match *typ {
+                FIELD_PORT => this.port = u16::from_be_bytes(value.try_into().ok()?),
+                FIELD_HOSTNAME => info.hostname = string(),
+                FIELD_NAME => info.name = string(),
+                FIELD_INSTANCE => info.instance = u64::from_be_bytes(value.try_into().ok()?),
+                FIELD_VERSION => info.version = Some(u16::from_be_bytes(value.try_into().ok()?)),
+                FIELD_NONCE => this.nonce = Some(value.try_into().ok()?),
+                FIELD_SOURCE => {
+                    this.source = Some(match value.len() {
+                        4 => IpAddr::from(<[u8; 4]>::try_from(value).ok()?),
+                        _ => IpAddr::from(<[u8; 16]>::try_from(value).ok()?),
+                    });
+                }
+                FIELD_TRANSPORTS => {
+                    info.transports = String::from_utf8_lossy(value)
+                        .split(',')
+                        .filter_map(|t| Transport::from_str(t, true).ok())
+                        .collect();
+                }
+                _ => {}
+            }
+        }
+        if !rest.is_empty() || (secret.is_some() && !signed) {
+            return None;
+        }
+        match *kind {
+            KIND_REQUEST if this.is_request() => Some(this),
+            KIND_RESPONSE if !this.is_request() => Some(this),
+            _ => None,
+        }
+    }
+}
+
+/// Appends a field, truncating values that don't fit.
+fn field(buf: &mut Vec<u8>, typ: u8, value: &[u8]) {
+    let len = value.len().min(u8::MAX.into());
+    buf.push(typ);
+    buf.push(len as u8);
+    buf.extend_from_slice(&value[..len]);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info() -> PeerInfo {
+        PeerInfo {
+            hostname: Some("desk.lan".to_owned()),
+            name: Some("hoipc".to_owned()),
+            instance: 0x0123_4567_89ab_cdef,
+            version: Some(1),
+            transports: vec![Transport::Quic, Transport::WebSocket],
+        }
+    }
+
+    fn response() -> TlvPacket {
+        TlvPacket {
+            port: 1234,
+            nonce: Some([7; 16]),
+            source: Some(IpAddr::from([192, 168, 1, 2])),
+            info: info(),
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        for port in [0, 1, 27056, u16::MAX] {
+            let pkt = TlvPacket {
+                port,
+                nonce: None,
+                source: (port != 0).then(|| "fe80::1".parse().unwrap()),
+                info: info(),
+            };
+            let bytes = pkt.to_bytes(None);
+            assert_ne!(bytes.len(), size_of::<Packet>());
+            assert_eq!(TlvPacket::try_from_bytes(&bytes, None), Some(pkt));
+        }
+        let pkt = TlvPacket {
+            port: 0,
+            nonce: None,
+            source: None,
+            info: PeerInfo {
+                instance: 1,
+                ..PeerInfo::default()
+            },
+        };
+        assert_eq!(
+            TlvPacket::try_from_bytes(&pkt.to_bytes(None), None),
+            Some(pkt)
+        );
+    }
+
+    #[test]
+    fn test_legacy() {
+        let pkt = TlvPacket::try_from_bytes(Packet::new(27056).as_bytes(), None).unwrap();
+        assert_eq!(pkt.port, 27056);
+        assert_eq!(pkt.info, PeerInfo::default());
+        assert!(
+            TlvPacket::try_from_bytes(&Packet::REQUEST, None)
+                .unwrap()
+                .is_request()
+        );
+        let secret = Secret::derive(b"secret");
+        assert_eq!(
+            TlvPacket::try_from_bytes(Packet::new(27056).as_bytes(), Some(&secret)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields() {
+        let mut bytes = response().to_bytes(None);
+        bytes.pop();
+        bytes.extend_from_slice(&[0xee, 3, 1, 2, 3]);
+        bytes.push(CRC8_9B.calc(&bytes, bytes.len(), 0));
+        assert_eq!(TlvPacket::try_from_bytes(&bytes, None), Some(response()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let bytes = response().to_bytes(None);
+        for len in 0..bytes.len() {
+            assert_eq!(TlvPacket::try_from_bytes(&bytes[..len], None), None);
+        }
+        let mut corrupt = bytes.clone();
+        corrupt[10] ^= 1;
+        assert_eq!(TlvPacket::try_from_bytes(&corrupt, None), None);
+        let mut newer = bytes;
+        newer[4] = VERSION + 1;
+        assert_eq!(TlvPacket::try_from_bytes(&newer, None), None);
+    }
+
+    #[test]
+    fn test_signed() {
+        let secret = Secret::derive(b"secret");
+        let bytes = response().to_bytes(Some(&secret));
+        assert_eq!(
+            TlvPacket::try_from_bytes(&bytes, Some(&secret)),
+            Some(response())
+        );
+        // the MAC is just an unknown field without a secret
+        assert_eq!(TlvPacket::try_from_bytes(&bytes, None), Some(response()));
+        let other = Secret::derive(b"other");
+        assert_eq!(TlvPacket::try_from_bytes(&bytes, Some(&other)), None);
+        let unsigned = response().to_bytes(None);
+        assert_eq!(TlvPacket::try_from_bytes(&unsigned, Some(&secret)), None);
+
+        // forged port, with a valid CRC
+        let mut forged = bytes;
+        forged.pop();
+        forged[8] ^= 1;
+        forged.push(CRC8_9B.calc(&forged, forged.len(), 0));
+        assert_eq!(TlvPacket::try_from_bytes(&forged, Some(&secret)), None);
+    }
+}
@@ -0,0 +1,304 @@
+use std::time::Duration;
+
+use anyhow::Context;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const MAGIC: [u8; 4] = *b"HOIP";
+
+/// Wire protocol version. Peers with different versions refuse to talk to each
+/// other; backwards-compatible extensions are negotiated via [`Features`]
+/// instead.
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// How long the handshake is allowed to take before the connection is dropped.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Optional protocol extensions. The set actually used on a connection is the
+/// intersection of what both peers advertise.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub struct Features(u32);
+
+impl Features {
+    /// Events carry their original timestamps, as an offset from a
+    /// per-connection epoch.
+    pub const TIMESTAMPS: Self = Self(1 << 0);
+    /// Each evdev packet (everything up to a `SYN_REPORT`) is sent as a single
+    /// length-prefixed frame.
+    pub const BATCH: Self = Self(1 << 1);
+    /// The connecting side describes its devices right after the handshake,
+    /// see [`crate::device`].
+    pub const DEVICES: Self = Self(1 << 2);
+    /// Packets are tagged with the index of the source device, and the
+    /// accepting side creates a separate virtual device for each. Requires
+    /// [`Self::BATCH`] and [`Self::DEVICES`].
+    pub const MULTIPLEX: Self = Self(1 << 3);
+    /// The accepting side sends LED changes on its virtual devices back, so
+    /// that they can be mirrored on the physical devices.
+    pub const LEDS: Self = Self(1 << 4);
+    /// Frames are tagged with their kind, and both sides send periodic pings,
+    /// see [`crate::heartbeat`]. Requires [`Self::BATCH`].
+    pub const HEARTBEAT: Self = Self(1 << 5);
+    /// Control messages, see [`crate::codec::Message`]. Requires
+    /// [`Self::HEARTBEAT`].
+    pub const CONTROL: Self = Self(1 << 6);
+    /// Input packets go over UDP, see [`crate::udp`]. Requires
+    /// [`Self::BATCH`]. Only offered when asked for on the command line.
+    pub const UDP: Self = Self(1 << 7);
+    /// The accepting side sends a verdict before anything else happens on the
+    /// connection, possibly after waiting for a local operator, see
+    /// [`crate::approval`].
+    pub const APPROVAL: Self = Self(1 << 8);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Everything this build knows how to speak.
+    pub const fn supported() -> Self {
+        Self::TIMESTAMPS
+            .union(Self::BATCH)
+            .union(Self::DEVICES)
+            .union(Self::MULTIPLEX)
+            .union(Self::LEDS)
+            .union(Self::HEARTBEAT)
+            .union(Self::CONTROL)
+            .union(Self::UDP)
+            .union(Self::APPROVAL)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn intersection(self, other: Self) -> Self {
+        Self(self.0 & other.0)
+    }
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+
+    /// Drops features whose prerequisites are missing.
+    pub const fn with_dependencies(self) -> Self {
+        let mut res = self;
+        if res.contains(Self::MULTIPLEX) && !res.contains(Self::BATCH.union(Self::DEVICES)) {
+            res = res.difference(Self::MULTIPLEX);
+        }
+        if res.contains(Self::HEARTBEAT) && !res.contains(Self::BATCH) {
+            res = res.difference(Self::HEARTBEAT);
+        }
+        if res.contains(Self::CONTROL) && !res.contains(Self::HEARTBEAT) {
+            res = res.difference(Self::CONTROL);
+        }
+        if res.contains(Self::UDP) && !res.contains(Self::BATCH) {
+            res = res.difference(Self::UDP);
+        }
+        res
+    }
+}
+
+/// Greeting both peers send before any events.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Hello {
+    pub version: u16,
+    pub features: Features,
+    pub name: String,
+}
+
+impl Hello {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            features: Features::supported(),
+            name: name.into(),
+        }
+    }
+
+    pub async fn write(&self, stream: &mut (impl AsyncWrite + Unpin)) -> anyhow::Result<()> {
+        let name = self.name.as_bytes();
+        let name_len = u8::try_from(name.len()).context("Peer name is too long")?;
+        let mut buf = Vec::with_capacity(MAGIC.len() + 7 + name.len());
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.features.bits().to_be_bytes());
+        buf.push(name_len);
+        buf.extend_from_slice(name);
+        stream.write_all(&buf).await.context("Send hello")?;
+        stream.flush().await.context("Flush hello")?;
+        Ok(())
+    }
+
+    pub async fn read(stream: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<Self> {
+        let mut magic = [0u8; MAGIC.len()];
+        stream.read_exact(&mut magic).await.context("Read magic")?;
+        // bail out before reading anything else, this is not a HoIP peer.
+        anyhow::ensure!(magic == MAGIC, "Remote is not a HoIP peer");
+        let version = stream.read_u16().await.context("Read protocol version")?;
+        let features = Features::from_bits(stream.read_u32().await.context("Read features")?);
+        let name_len = stream.read_u8().await.context("Read name length")?;
+        let mut name = vec![0u8; name_len.into()];
+        stream.read_exact(&mut name).await.context("Read name")?;
+        let name = String::from_utf8_lossy(&name).into_owned();
+        // names end up in logs and the known-peers file, one per line.
+        anyhow::ensure!(
+            !name.chars().any(char::is_control),
+            "Peer name {name:?} contains control characters"
+        );
+        Ok(Self {
+            version,
+            features,
+            name,
+        })
+    }
+}
+
+/// Result of a successful handshake.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Negotiated {
+    pub peer_name: String,
+    pub features: Features,
+}
+
+impl Negotiated {
+    fn new(ours: &Hello, theirs: Hello) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            ours.version == theirs.version,
+            "Protocol version mismatch: ours is {}, peer {:?} has {}",
+            ours.version,
+            theirs.name,
+            theirs.version,
+        );
+        Ok(Self {
+            features: ours
+                .features
+                .intersection(theirs.features)
+                .with_dependencies(),
+            peer_name: theirs.name,
+        })
+    }
+}
+
+/// Handshake from the connecting side (i.e. `hoips`). Sends our [`Hello`]
+/// first, then waits for the peer's.
+pub async fn connect(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    hello: &Hello,
+) -> anyhow::Result<Negotiated> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        hello.write(stream).await?;
+        Negotiated::new(hello, Hello::read(stream).await?)
+    })
+    .await
+    .context("Handshake timed out")?
+}
+
+/// Handshake from the accepting side (i.e. `hoipc`). Validates the peer's
+/// [`Hello`] before replying, so that anything that isn't a HoIP peer is
+/// dropped without a response.
+pub async fn accept(
+    stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+    hello: &Hello,
+) -> anyhow::Result<Negotiated> {
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let theirs = Hello::read(stream).await?;
+        // reply even on version mismatch so that the other side can report it.
+        hello.write(stream).await?;
+        Negotiated::new(hello, theirs)
+    })
+    .await
+    .context("Handshake timed out")?
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let (ours, theirs) = (Hello::new("server"), Hello::new("client"));
+        let (server, client) = tokio::join!(connect(&mut a, &ours), accept(&mut b, &theirs));
+        let (server, client) = (server.expect("server"), client.expect("client"));
+        assert_eq!(server.peer_name, "client");
+        assert_eq!(client.peer_name, "server");
+        assert_eq!(server.features, client.features);
+    }
+
+    #[tokio::test]
+    async fn test_version_mismatch() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        let mut old = Hello::new("server");
+        old.version = PROTOCOL_VERSION + 1;
+        let theirs = Hello::new("client");
+        let (server, client) = tokio::join!(connect(&mut a, &old), accept(&mut b, &theirs));
+        server.expect_err("server should fail");
+        client.expect_err("client should fail");
+    }
+
+    #[tokio::test]
+    async fn test_control_characters() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        Hello::new("desk\n00 laptop").write(&mut a).await.unwrap();
+        let err = Hello::read(&mut b).await.expect_err("should reject");
+        assert!(err.to_string().contains("control characters"));
+    }
+
+    #[tokio::test]
+    async fn test_not_a_peer() {
+        let (mut a, mut b) = tokio::io::duplex(64);
+        a.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        let err = accept(&mut b, &Hello::new("client"))
+            .await
+            .expect_err("should reject");
+        assert_eq!(err.to_string(), "Remote is not a HoIP peer");
+        // nothing was sent back
+        drop(b);
+        let mut rest = vec![];
+        a.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_features() {
+        let a = Features::from_bits(0b101);
+        let b = Features::from_bits(0b110);
+        assert_eq!(a.intersection(b), Features::from_bits(0b100));
+        assert!(a.contains(Features::from_bits(0b001)));
+        assert!(!a.contains(b));
+        assert_eq!(
+            Features::MULTIPLEX
+                .union(Features::BATCH)
+                .with_dependencies(),
+            Features::BATCH
+        );
+        assert_eq!(
+            Features::HEARTBEAT
+                .union(Features::TIMESTAMPS)
+                .with_dependencies(),
+            Features::TIMESTAMPS
+        );
+        // dependencies are transitive
+        assert_eq!(
+            Features::CONTROL
+                .union(Features::HEARTBEAT)
+                .with_dependencies(),
+            Features::empty()
+        );
+        assert_eq!(
+            Features::supported().with_dependencies(),
+            Features::supported()
+        );
+    }
+}
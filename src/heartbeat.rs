@@ -0,0 +1,74 @@
+use std::time::Duration;
+
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+
+/// Keeps track of when to send pings, and when the peer has been quiet for too
+/// long. Anything received from the peer counts as a heartbeat.
+pub struct Heartbeat {
+    interval: Interval,
+    timeout: Duration,
+    deadline: Instant,
+    /// Token and send time of the last ping.
+    last_ping: (u64, Instant),
+}
+
+impl Heartbeat {
+    pub fn new(interval: Duration, timeout: Duration) -> Self {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            interval,
+            timeout,
+            deadline: Instant::now() + timeout,
+            last_ping: (0, Instant::now()),
+        }
+    }
+
+    /// Call whenever something is received from the peer.
+    pub fn received(&mut self) {
+        self.deadline = Instant::now() + self.timeout;
+    }
+
+    /// Call when a pong is received. Returns the round trip time if it answers
+    /// the last ping.
+    pub fn pong(&mut self, token: u64) -> Option<Duration> {
+        self.received();
+        let (last, sent) = self.last_ping;
+        (token == last).then(|| sent.elapsed())
+    }
+
+    /// Resolves with a ping token when it's time to send a ping, or fails if
+    /// the peer didn't send anything within the timeout. Cancel-safe.
+    pub async fn tick(&mut self) -> anyhow::Result<u64> {
+        tokio::select! {
+            _ = self.interval.tick() => {
+                self.last_ping = (self.last_ping.0.wrapping_add(1), Instant::now());
+                Ok(self.last_ping.0)
+            }
+            _ = tokio::time::sleep_until(self.deadline) => {
+                anyhow::bail!("Peer sent nothing for {:?}", self.timeout)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_heartbeat() {
+        let mut heartbeat = Heartbeat::new(Duration::from_secs(1), Duration::from_millis(2500));
+        // first tick is immediate
+        heartbeat.tick().await.unwrap();
+        for _ in 0..5 {
+            let token = heartbeat.tick().await.unwrap();
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            assert_eq!(heartbeat.pong(token), Some(Duration::from_millis(10)));
+        }
+        assert_eq!(heartbeat.pong(0), None);
+        heartbeat.tick().await.unwrap();
+        heartbeat.tick().await.unwrap();
+        heartbeat.tick().await.expect_err("timed out");
+    }
+}
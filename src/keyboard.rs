@@ -0,0 +1,26 @@
+//! Keyboards physically attached to this machine, for confirmations that have
+//! to come from someone sitting in front of it.
+
+use anyhow::Context;
+use evdev::{EventStream, KeyCode};
+use futures::stream::SelectAll;
+
+/// Events from all local keyboards that have every key in `keys`.
+///
+/// Devices without a physical path are skipped. Those are virtual devices,
+/// including the ones `hoipc` creates, so a remote can't confirm on its own
+/// behalf.
+pub fn events(keys: &[KeyCode]) -> anyhow::Result<SelectAll<EventStream>> {
+    let streams = evdev::enumerate()
+        .filter(|(_, dev)| {
+            dev.physical_path().is_some_and(|phys| !phys.is_empty())
+                && dev
+                    .supported_keys()
+                    .is_some_and(|supported| keys.iter().all(|&key| supported.contains(key)))
+        })
+        .map(|(_, dev)| dev.into_event_stream())
+        .collect::<std::io::Result<Vec<_>>>()
+        .context("Open keyboards")?;
+    anyhow::ensure!(!streams.is_empty(), "No local keyboard found");
+    Ok(futures::stream::select_all(streams))
+}
@@ -2,8 +2,24 @@ use std::net::{IpAddr, SocketAddr, SocketAddrV6};
 
 use anyhow::Context;
 
+pub mod acl;
+pub mod approval;
 pub mod codec;
+pub mod device;
 pub mod discovery;
+pub mod handshake;
+pub mod heartbeat;
+pub mod keyboard;
+pub mod mdns;
+pub mod noise;
+pub mod pairing;
+pub mod quic;
+pub mod relay;
+pub mod tls;
+pub mod transport;
+pub mod udp;
+pub mod uinput;
+pub mod websocket;
 
 pub fn init_logging() {
     tracing_subscriber::fmt()
@@ -13,6 +29,8 @@ pub fn init_logging() {
                 .from_env_lossy(),
         )
         .pretty()
+        // stdout may carry the connection, see `hoipc --stdio`.
+        .with_writer(std::io::stderr)
         .init();
 }
 
@@ -0,0 +1,467 @@
+//! Just enough mDNS / DNS-SD (RFC 6762, 6763) to advertise `hoipc` as a
+//! `_hoip._tcp` service and browse for it, as an alternative to
+//! [`discovery`](crate::discovery) that standard tooling like `avahi-browse`
+//! sees, and mDNS reflectors forward. No probing, caching or known-answer
+//! suppression; the service points at the host name, whose addresses are left
+//! to the system's mDNS responder.
+
+use std::{
+    collections::VecDeque,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    task::Poll,
+    time::Duration,
+};
+
+use anyhow::Context;
+use clap::ValueEnum;
+use futures::{Stream, never::Never};
+use hickory_proto::{
+    op::{Message, MessageType, Query},
+    rr::{
+        Name, RData, Record, RecordType,
+        rdata::{A, AAAA, PTR, SRV, TXT},
+    },
+};
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::UdpSocket;
+
+use crate::{
+    acl::Acl,
+    discovery::{Discovered, PeerInfo, interface_name, poll_recv_from, report_ingress},
+    transport::Transport,
+};
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_V6: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xfb);
+
+const SERVICE: &str = "_hoip._tcp.local.";
+/// Lists service types, for `avahi-browse --all`.
+const SERVICES: &str = "_services._dns-sd._udp.local.";
+
+const TTL: u32 = 120;
+/// Answers to one-shot queries from other ports, RFC 6762 section 6.7.
+const LEGACY_TTL: u32 = 10;
+
+const MAX_PACKET_SIZE: usize = 9000;
+
+/// Longest DNS label.
+const MAX_LABEL: usize = 63;
+
+fn name(s: &str) -> Name {
+    Name::from_ascii(s).expect("constant names are valid")
+}
+
+/// `label` prepended to `parent`, cut to fit if too long.
+fn child(label: &str, parent: &Name) -> anyhow::Result<Name> {
+    let label = &label.as_bytes()[..label.len().min(MAX_LABEL)];
+    Name::from_labels([label])
+        .and_then(|label| label.append_name(parent))
+        .with_context(|| format!("Invalid DNS name {label:?}"))
+}
+
+/// First label of `name`, e.g. the host name in `desk.local`.
+fn first_label(name: &Name) -> Option<String> {
+    name.iter()
+        .next()
+        .map(|label| String::from_utf8_lossy(label).into_owned())
+}
+
+fn response(records: Vec<Record>) -> Message {
+    let mut msg = Message::new();
+    msg.set_message_type(MessageType::Response)
+        .set_authoritative(true)
+        .add_answers(records);
+    msg
+}
+
+/// The `_hoip._tcp` service we answer for.
+struct Service {
+    instance: Name,
+    port: u16,
+    target: Name,
+    txt: Vec<String>,
+}
+
+impl Service {
+    fn new(info: &PeerInfo, port: u16) -> anyhow::Result<Self> {
+        let hostname = info.hostname.as_deref().unwrap_or("localhost");
+        let peer = info.name.as_deref().unwrap_or("hoipc");
+        let mut txt = vec![format!("name={peer}")];
+        if let Some(version) = info.version {
+            txt.push(format!("version={version}"));
+        }
+        for transport in &info.transports {
+            txt.push(format!("transport={}", transport_name(*transport)));
+        }
+        Ok(Self {
+            instance: child(&format!("{peer} on {hostname}"), &name(SERVICE))?,
+            port,
+            target: child(hostname, &name("local."))?,
+            txt,
+        })
+    }
+
+    fn records(&self, ttl: u32) -> [Record; 3] {
+        [
+            Record::from_rdata(name(SERVICE), ttl, RData::PTR(PTR(self.instance.clone()))),
+            Record::from_rdata(
+                self.instance.clone(),
+                ttl,
+                RData::SRV(SRV::new(0, 0, self.port, self.target.clone())),
+            ),
+            Record::from_rdata(
+                self.instance.clone(),
+                ttl,
+                RData::TXT(TXT::new(self.txt.clone())),
+            ),
+        ]
+    }
+
+    /// Records answering `query`, if any.
+    fn answer(&self, query: &Message, ttl: u32) -> Vec<Record> {
+        let mut answers = vec![];
+        let asks = |q: &Query, name: &Name, qtype| {
+            q.name() == name && (q.query_type() == qtype || q.query_type() == RecordType::ANY)
+        };
+        for q in query.queries() {
+            if asks(q, &name(SERVICE), RecordType::PTR) || asks(q, &self.instance, RecordType::SRV)
+            {
+                // whoever asks for the service wants to resolve it, too.
+                answers.extend(self.records(ttl));
+            } else if asks(q, &self.instance, RecordType::TXT) {
+                answers.push(self.records(ttl)[2].clone());
+            } else if asks(q, &name(SERVICES), RecordType::PTR) {
+                answers.push(Record::from_rdata(
+                    name(SERVICES),
+                    ttl,
+                    RData::PTR(PTR(name(SERVICE))),
+                ));
+            }
+        }
+        answers.dedup();
+        answers
+    }
+}
+
+fn transport_name(transport: Transport) -> String {
+    transport
+        .to_possible_value()
+        .map(|value| value.get_name().to_owned())
+        .unwrap_or_default()
+}
+
+/// Multicast group matching `addr`'s family, and scope for IPv6.
+fn group(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V4(_) => (MDNS_V4, MDNS_PORT).into(),
+        SocketAddr::V6(addr) => {
+            std::net::SocketAddrV6::new(MDNS_V6, MDNS_PORT, 0, addr.scope_id()).into()
+        }
+    }
+}
+
+/// Binds the mDNS port next to whatever responder the system runs.
+fn bind_shared(group: SocketAddr, iface: IpAddr) -> anyhow::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(group), Type::DGRAM, Some(Protocol::UDP))
+        .context("Create mDNS socket")?;
+    socket.set_reuse_address(true).context("Set SO_REUSEADDR")?;
+    socket.set_reuse_port(true).context("Set SO_REUSEPORT")?;
+    match group {
+        SocketAddr::V4(group) => {
+            let iface = match iface {
+                IpAddr::V4(iface) => iface,
+                IpAddr::V6(_) => Ipv4Addr::UNSPECIFIED,
+            };
+            socket
+                .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())
+                .context("Bind mDNS port")?;
+            socket
+                .join_multicast_v4(group.ip(), &iface)
+                .context("Join V4 mDNS group")?;
+            socket
+                .set_multicast_loop_v4(false)
+                .context("Disable V4 multicast loop")?;
+        }
+        SocketAddr::V6(group) => {
+            socket.set_only_v6(true).context("Set IPV6_V6ONLY")?;
+            socket
+                .bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, MDNS_PORT)).into())
+                .context("Bind mDNS port")?;
+            socket
+                .join_multicast_v6(group.ip(), group.scope_id())
+                .context("Join V6 mDNS group")?;
+            socket
+                .set_multicast_loop_v6(false)
+                .context("Disable V6 multicast loop")?;
+        }
+    }
+    socket.set_nonblocking(true).context("Set nonblocking")?;
+    UdpSocket::from_std(socket.into()).context("Register mDNS socket")
+}
+
+/// Advertises the service described by `info` at `listen`, until dropped.
+/// Listening on an unspecified IPv6 address advertises over IPv4 too. Queries
+/// from peers `acl` doesn't permit go unanswered.
+pub async fn advertise(info: &PeerInfo, listen: SocketAddr, acl: &Acl) -> anyhow::Result<Never> {
+    let service = Service::new(info, listen.port())?;
+    let mut groups = vec![group(listen)];
+    if listen.ip().is_unspecified() && listen.is_ipv6() {
+        groups.push(group((Ipv4Addr::UNSPECIFIED, 0).into()));
+    }
+    let mut sockets = vec![];
+    for group in groups {
+        sockets.push((bind_shared(group, listen.ip())?, group));
+    }
+    tracing::info!(
+        service = first_label(&service.instance),
+        port = listen.port(),
+        "Advertising mDNS service"
+    );
+    let responders = sockets
+        .iter()
+        .map(|(socket, group)| Box::pin(respond(&service, socket, *group, acl)));
+    let (res, _, _) = futures::future::select_all(responders).await;
+    res
+}
+
+async fn respond(
+    service: &Service,
+    socket: &UdpSocket,
+    group: SocketAddr,
+    acl: &Acl,
+) -> anyhow::Result<Never> {
+    // announce twice, a second apart, RFC 6762 section 8.3.
+    let announcement = response(service.records(TTL).into())
+        .to_vec()
+        .context("Encode mDNS announcement")?;
+    for i in 0..2 {
+        if i > 0 {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        socket
+            .send_to(&announcement, group)
+            .await
+            .context("Send mDNS announcement")?;
+    }
+    let mut buf = vec![0u8; MAX_PACKET_SIZE];
+    loop {
+        let (sz, from) = socket
+            .recv_from(&mut buf)
+            .await
+            .context("Recv mDNS query")?;
+        if !acl.permits(from) {
+            tracing::debug!(requester = %from, "Ignoring mDNS query from denied address");
+            continue;
+        }
+        let Ok(query) = Message::from_vec(&buf[..sz]) else {
+            continue;
+        };
+        if query.message_type() != MessageType::Query {
+            continue;
+        }
+        let legacy = from.port() != MDNS_PORT;
+        let records = service.answer(&query, if legacy { LEGACY_TTL } else { TTL });
+        if records.is_empty() {
+            continue;
+        }
+        tracing::debug!(requester = %from, "Answering mDNS query");
+        let mut response = response(records);
+        let to = if legacy {
+            response
+                .set_id(query.id())
+                .add_queries(query.queries().to_vec());
+            from
+        } else {
+            group
+        };
+        let response = response.to_vec().context("Encode mDNS response")?;
+        socket
+            .send_to(&response, to)
+            .await
+            .context("Send mDNS response")?;
+    }
+}
+
+/// Browses for `_hoip._tcp` services with one-shot queries, so that it
+/// doesn't need the mDNS port.
+pub struct Browser {
+    socket: UdpSocket,
+    group: SocketAddr,
+}
+
+impl Browser {
+    /// Queries go to the group in `multicast`'s family, from `bind`.
+    pub async fn new(bind: SocketAddr, multicast: SocketAddr) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(bind).await.context("Bind UDP socket")?;
+        report_ingress(&socket)?;
+        Ok(Self {
+            socket,
+            group: group(multicast),
+        })
+    }
+
+    /// Sends queries every `period`.
+    pub async fn discover(&self, period: Duration) -> anyhow::Result<Never> {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            self.query().await?;
+        }
+    }
+
+    /// Sends a single query.
+    pub async fn query(&self) -> anyhow::Result<()> {
+        let mut query = Message::new();
+        query.add_query(Query::query(name(SERVICE), RecordType::PTR));
+        let query = query.to_vec().context("Encode mDNS query")?;
+        tracing::info!(group = %self.group, "Send mDNS query");
+        self.socket
+            .send_to(&query, self.group)
+            .await
+            .context("Send mDNS query")?;
+        Ok(())
+    }
+
+    /// Services found by [`Self::discover`].
+    pub fn discovered(&self) -> impl Stream<Item = anyhow::Result<Discovered>> {
+        let mut buf = vec![0u8; MAX_PACKET_SIZE];
+        let mut pending = VecDeque::new();
+        futures::stream::poll_fn(move |cx| {
+            while pending.is_empty() {
+                let (len, from, ingress) =
+                    match futures::ready!(poll_recv_from(&self.socket, cx, &mut buf)) {
+                        Ok(recv) => recv,
+                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                    };
+                let Ok(msg) = Message::from_vec(&buf[..len]) else {
+                    continue;
+                };
+                let interface = ingress.index.and_then(interface_name);
+                pending.extend(
+                    services(&msg, from.ip())
+                        .into_iter()
+                        .map(|peer| Discovered {
+                            interface: interface.clone(),
+                            ..peer
+                        }),
+                );
+            }
+            let peer = pending.pop_front().unwrap();
+            tracing::info!(
+                addr = %peer.addr,
+                interface = peer.interface,
+                name = peer.info.name,
+                hostname = peer.info.hostname,
+                "Got mDNS response"
+            );
+            Poll::Ready(Some(Ok(peer)))
+        })
+    }
+}
+
+/// Services in a response from `from`. Addresses come from the response if it
+/// has them, else it's the sender's.
+fn services(msg: &Message, from: IpAddr) -> Vec<Discovered> {
+    if msg.message_type() != MessageType::Response {
+        return vec![];
+    }
+    let mut res = vec![];
+    for record in msg.all_sections() {
+        let RData::PTR(PTR(instance)) = record.data() else {
+            continue;
+        };
+        if record.name() != &name(SERVICE) || record.ttl() == 0 {
+            continue;
+        }
+        let records = || msg.all_sections().filter(|r| r.name() == instance);
+        let Some(srv) = records().find_map(|r| match r.data() {
+            RData::SRV(srv) => Some(srv),
+            _ => None,
+        }) else {
+            continue;
+        };
+        let target = srv.target();
+        let ip = msg
+            .all_sections()
+            .filter(|r| r.name() == target)
+            .find_map(|r| match *r.data() {
+                RData::A(A(ip)) if from.is_ipv4() => Some(IpAddr::V4(ip)),
+                RData::AAAA(AAAA(ip)) if from.is_ipv6() => Some(IpAddr::V6(ip)),
+                _ => None,
+            })
+            .unwrap_or(from);
+        let mut info = PeerInfo {
+            hostname: first_label(target),
+            ..PeerInfo::default()
+        };
+        for txt in records().filter_map(|r| match r.data() {
+            RData::TXT(txt) => Some(txt),
+            _ => None,
+        }) {
+            for entry in txt.iter() {
+                match String::from_utf8_lossy(entry).split_once('=') {
+                    Some(("name", name)) => info.name = Some(name.to_owned()),
+                    Some(("version", version)) => info.version = version.parse().ok(),
+                    Some(("transport", transport)) => {
+                        info.transports
+                            .extend(Transport::from_str(transport, true).ok());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        res.push(Discovered {
+            addr: SocketAddr::new(ip, srv.port()),
+            interface: None,
+            info,
+            requested: None,
+        });
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info() -> PeerInfo {
+        PeerInfo {
+            hostname: Some("desk".to_owned()),
+            name: Some("hoipc".to_owned()),
+            instance: 1,
+            version: Some(1),
+            transports: vec![Transport::Tcp],
+        }
+    }
+
+    #[test]
+    fn test_answer_and_browse() {
+        let service = Service::new(&info(), 27056).unwrap();
+        let mut query = Message::new();
+        query.add_query(Query::query(name("_HOIP._tcp.local."), RecordType::PTR));
+        let response = response(service.answer(&query, TTL)).to_vec().unwrap();
+        let response = Message::from_vec(&response).unwrap();
+        let from = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 2));
+        let found = services(&response, from);
+        assert_eq!(
+            found,
+            vec![Discovered {
+                addr: SocketAddr::new(from, 27056),
+                interface: None,
+                info: PeerInfo {
+                    instance: 0,
+                    ..info()
+                },
+                requested: None,
+            }]
+        );
+        assert_eq!(first_label(&service.instance).unwrap(), "hoipc on desk");
+
+        let mut other = Message::new();
+        other.add_query(Query::query(name("_http._tcp.local."), RecordType::PTR));
+        assert!(service.answer(&other, TTL).is_empty());
+        assert!(services(&query, from).is_empty());
+    }
+}
@@ -0,0 +1,543 @@
+//! Optional encryption with a pre-shared key, using the Noise `NNpsk0`
+//! pattern. Lighter than [TLS](crate::tls): both sides just need the same
+//! passphrase or key file.
+//!
+//! With [`Identity`] keys for [pairing](crate::pairing), the `XX` pattern is
+//! used instead, or `XXpsk3` if there's a pre-shared key as well.
+//!
+//! The stream is wrapped before the HoIP handshake, so a peer without the key
+//! is dropped before anything else happens. After that, each write is sent as
+//! a separate Noise message: `u16` length, then the ciphertext. Messages are
+//! authenticated, and the implicit nonces make replayed, reordered or dropped
+//! messages fail to decrypt.
+
+use std::{
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context as TaskContext, Poll, ready},
+};
+
+use anyhow::Context;
+use sha2::{Digest, Sha256};
+use snow::{
+    HandshakeState, TransportState,
+    params::DHChoice,
+    resolvers::{CryptoResolver, DefaultResolver},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio_util::bytes::{Buf, BytesMut};
+
+use crate::{
+    handshake::HANDSHAKE_TIMEOUT,
+    transport::{Stream, Transport},
+};
+
+const PATTERN_PSK: &str = "Noise_NNpsk0_25519_ChaChaPoly_BLAKE2s";
+/// With [`Identity`] keys, so that peers learn each other's public keys.
+const PATTERN_XX: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const PATTERN_XX_PSK: &str = "Noise_XXpsk3_25519_ChaChaPoly_BLAKE2s";
+/// Largest Noise message, ciphertext included.
+const MAX_MESSAGE_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+const MAX_PAYLOAD_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+/// So that the same passphrase doesn't give the same key as elsewhere.
+const KEY_CONTEXT: &[u8] = b"hoip psk v1\0";
+/// Argon2id salt for `--psk` passphrases. Fixed, as both sides have to
+/// arrive at the same key on their own.
+const PASSPHRASE_SALT: &[u8] = b"hoip psk passphrase v1";
+/// Argon2id memory cost in KiB, and number of passes.
+const PASSPHRASE_MEMORY: u32 = 64 * 1024;
+const PASSPHRASE_PASSES: u32 = 3;
+
+/// Pre-shared key options shared by `hoips` and `hoipc`.
+#[derive(clap::Args, Debug)]
+pub struct PskArgs {
+    /// Passphrase shared with the peer. Enables encryption; both sides have
+    /// to use the same one. It's stretched with Argon2id, which takes a
+    /// moment at startup; make it long anyway, anyone who records a connection
+    /// attempt can try guessing it offline.
+    #[arg(
+        long,
+        env = "HOIP_PSK",
+        hide_env_values = true,
+        conflicts_with_all = ["psk_file", "tls_cert"]
+    )]
+    pub psk: Option<String>,
+    /// File with the key shared with the peer, as an alternative to `--psk`.
+    /// Any contents will do, e.g. `head -c32 /dev/urandom > hoip.psk`.
+    #[arg(long, conflicts_with = "tls_cert")]
+    pub psk_file: Option<PathBuf>,
+}
+
+impl PskArgs {
+    /// Loads the key, if configured.
+    pub fn load(&self, transport: Transport) -> anyhow::Result<Option<Psk>> {
+        let (secret, passphrase) = match (&self.psk, &self.psk_file) {
+            (Some(psk), _) => (psk.as_bytes().to_vec(), true),
+            (None, Some(path)) => (
+                std::fs::read(path)
+                    .with_context(|| format!("Read pre-shared key from {}", path.display()))?,
+                false,
+            ),
+            (None, None) => return Ok(None),
+        };
+        anyhow::ensure!(!secret.is_empty(), "Pre-shared key is empty");
+        anyhow::ensure!(
+            transport.is_stream(),
+            "Pre-shared keys only work with TCP and WebSocket transports, use TLS with QUIC"
+        );
+        if passphrase {
+            Psk::from_passphrase(&secret).map(Some)
+        } else {
+            Ok(Some(Psk::derive(&secret)))
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Psk([u8; 32]);
+
+impl Psk {
+    /// For key files, which are expected to be random already.
+    pub fn derive(secret: &[u8]) -> Self {
+        Self(
+            Sha256::new()
+                .chain_update(KEY_CONTEXT)
+                .chain_update(secret)
+                .finalize()
+                .into(),
+        )
+    }
+
+    /// For passphrases, which are expected to be guessable: stretches them
+    /// with Argon2id, so that every offline guess at a recorded handshake
+    /// costs 64 MiB and a few hundred milliseconds.
+    pub fn from_passphrase(passphrase: &[u8]) -> anyhow::Result<Self> {
+        let params = argon2::Params::new(PASSPHRASE_MEMORY, PASSPHRASE_PASSES, 1, Some(32))
+            .map_err(|e| anyhow::anyhow!("Argon2 parameters: {e}"))?;
+        let argon2 =
+            argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+        let mut key = [0u8; 32];
+        argon2
+            .hash_password_into(passphrase, PASSPHRASE_SALT, &mut key)
+            .map_err(|e| anyhow::anyhow!("Derive key from passphrase: {e}"))?;
+        Ok(Self(key))
+    }
+}
+
+impl std::fmt::Debug for Psk {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Psk(..)")
+    }
+}
+
+/// Our static key, so that peers can recognize us across connections.
+#[derive(Clone)]
+pub struct Identity {
+    private: Vec<u8>,
+    public: Vec<u8>,
+}
+
+impl Identity {
+    pub fn generate() -> anyhow::Result<Self> {
+        let keypair = snow::Builder::new(PATTERN_XX.parse()?).generate_keypair()?;
+        Ok(Self {
+            private: keypair.private,
+            public: keypair.public,
+        })
+    }
+
+    fn from_private(private: Vec<u8>) -> anyhow::Result<Self> {
+        anyhow::ensure!(private.len() == 32, "Wrong identity key length");
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .context("No Curve25519 implementation")?;
+        dh.set(&private);
+        let public = dh.pubkey().to_vec();
+        Ok(Self { private, public })
+    }
+
+    /// The key peers see.
+    pub fn public(&self) -> &[u8] {
+        &self.public
+    }
+
+    /// Loads the key from `path`, generating it first if the file doesn't
+    /// exist.
+    pub fn load_or_generate(path: &Path) -> anyhow::Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(hex) => decode_hex(hex.trim())
+                .and_then(Self::from_private)
+                .with_context(|| format!("Parse identity key in {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Self::generate()?;
+                let mut file = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .mode(0o600)
+                    .open(path)
+                    .with_context(|| format!("Create identity key {}", path.display()))?;
+                writeln!(file, "{}", encode_hex(&identity.private))
+                    .with_context(|| format!("Write identity key {}", path.display()))?;
+                tracing::info!(path = %path.display(), "Generated new identity key");
+                Ok(identity)
+            }
+            Err(e) => Err(e).with_context(|| format!("Read identity key from {}", path.display())),
+        }
+    }
+}
+
+impl std::fmt::Debug for Identity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Identity(..)")
+    }
+}
+
+/// The peer's static key, available when both sides use an [`Identity`].
+#[derive(Clone, Debug)]
+pub struct PeerIdentity {
+    pub key: Vec<u8>,
+}
+
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+pub fn decode_hex(hex: &str) -> anyhow::Result<Vec<u8>> {
+    anyhow::ensure!(hex.len().is_multiple_of(2), "Odd number of hex digits");
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            let byte = hex.get(i..i + 2).context("Not a hex string")?;
+            u8::from_str_radix(byte, 16).context("Not a hex string")
+        })
+        .collect()
+}
+
+/// Runs the handshake in whichever pattern the configured keys call for.
+/// Both sides have to configure the same kinds of keys.
+async fn handshake(
+    psk: Option<&Psk>,
+    identity: Option<&Identity>,
+    initiator: bool,
+    mut stream: Stream,
+) -> anyhow::Result<(Stream, Option<PeerIdentity>)> {
+    let builder = match (psk, identity) {
+        (Some(psk), None) => snow::Builder::new(PATTERN_PSK.parse()?).psk(0, &psk.0),
+        (None, Some(identity)) => {
+            snow::Builder::new(PATTERN_XX.parse()?).local_private_key(&identity.private)
+        }
+        (Some(psk), Some(identity)) => snow::Builder::new(PATTERN_XX_PSK.parse()?)
+            .local_private_key(&identity.private)
+            .psk(3, &psk.0),
+        (None, None) => anyhow::bail!("Noise needs a pre-shared key or an identity"),
+    };
+    let mut state = if initiator {
+        builder.build_initiator()?
+    } else {
+        builder.build_responder()?
+    };
+    tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        while !state.is_handshake_finished() {
+            if state.is_my_turn() {
+                write_message(&mut stream, &mut state).await?;
+            } else {
+                read_message(&mut stream, &mut state)
+                    .await
+                    .context(if psk.is_some() {
+                        "Peer doesn't have the same pre-shared key"
+                    } else {
+                        "Invalid handshake message"
+                    })?;
+            }
+        }
+        anyhow::Ok(())
+    })
+    .await
+    .context("Noise handshake timed out")?
+    .context("Noise handshake")?;
+    let peer = state
+        .get_remote_static()
+        .map(|key| PeerIdentity { key: key.to_vec() });
+    let stream = Box::new(NoiseStream::new(stream, state.into_transport_mode()?));
+    Ok((stream, peer))
+}
+
+async fn write_message(stream: &mut Stream, state: &mut HandshakeState) -> anyhow::Result<()> {
+    let mut buf = vec![0u8; MAX_MESSAGE_LEN];
+    let len = state.write_message(&[], &mut buf)?;
+    stream.write_u16(len.try_into()?).await?;
+    stream.write_all(&buf[..len]).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+async fn read_message(stream: &mut Stream, state: &mut HandshakeState) -> anyhow::Result<()> {
+    let len = stream.read_u16().await?;
+    let mut message = vec![0u8; len.into()];
+    stream.read_exact(&mut message).await?;
+    let mut payload = vec![0u8; MAX_MESSAGE_LEN];
+    state.read_message(&message, &mut payload)?;
+    Ok(())
+}
+
+/// Noise handshake from the connecting side.
+pub async fn connect(
+    psk: Option<&Psk>,
+    identity: Option<&Identity>,
+    stream: Stream,
+) -> anyhow::Result<(Stream, Option<PeerIdentity>)> {
+    handshake(psk, identity, true, stream).await
+}
+
+/// Noise handshake from the accepting side.
+pub async fn accept(
+    psk: Option<&Psk>,
+    identity: Option<&Identity>,
+    stream: Stream,
+) -> anyhow::Result<(Stream, Option<PeerIdentity>)> {
+    handshake(psk, identity, false, stream).await
+}
+
+struct NoiseStream {
+    inner: Stream,
+    state: TransportState,
+    /// Ciphertext read but not decrypted yet.
+    read_buf: BytesMut,
+    /// Plaintext decrypted but not read yet.
+    plain: BytesMut,
+    /// Ciphertext not written yet.
+    write_buf: BytesMut,
+}
+
+impl NoiseStream {
+    fn new(inner: Stream, state: TransportState) -> Self {
+        Self {
+            inner,
+            state,
+            read_buf: BytesMut::new(),
+            plain: BytesMut::new(),
+            write_buf: BytesMut::new(),
+        }
+    }
+
+    /// Decrypts the next message from `read_buf` into `plain`, if it's all
+    /// there.
+    fn decrypt(&mut self) -> io::Result<bool> {
+        let Some(len) = self.read_buf.get(..2) else {
+            return Ok(false);
+        };
+        let len = usize::from(u16::from_be_bytes([len[0], len[1]]));
+        if self.read_buf.len() < 2 + len {
+            return Ok(false);
+        }
+        self.read_buf.advance(2);
+        let message = self.read_buf.split_to(len);
+        self.plain.resize(len, 0);
+        let len = self
+            .state
+            .read_message(&message, &mut self.plain)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.plain.truncate(len);
+        Ok(true)
+    }
+
+    fn poll_write_buf(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        while !self.write_buf.is_empty() {
+            let n = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write_buf.advance(n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncRead for NoiseStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.plain.is_empty() {
+            if this.decrypt()? {
+                continue;
+            }
+            this.read_buf.reserve(4096);
+            let n = ready!(tokio_util::io::poll_read_buf(
+                Pin::new(&mut this.inner),
+                cx,
+                &mut this.read_buf
+            ))?;
+            if n == 0 {
+                if this.read_buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        let n = buf.remaining().min(this.plain.len());
+        buf.put_slice(&this.plain.split_to(n));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for NoiseStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        let n = buf.len().min(MAX_PAYLOAD_LEN);
+        // empty by now, so its storage gets reused for the next message.
+        this.write_buf.resize(2 + n + TAG_LEN, 0);
+        let len = match this
+            .state
+            .write_message(&buf[..n], &mut this.write_buf[2..])
+        {
+            Ok(len) => len,
+            Err(e) => {
+                this.write_buf.clear();
+                return Poll::Ready(Err(io::Error::other(e)));
+            }
+        };
+        this.write_buf.truncate(2 + len);
+        this.write_buf[..2].copy_from_slice(&(len as u16).to_be_bytes());
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_buf(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn pair(client: &Psk, server: &Psk) -> anyhow::Result<(Stream, Stream)> {
+        let (a, b) = tokio::io::duplex(MAX_MESSAGE_LEN * 2);
+        let (a, b) = tokio::join!(
+            connect(Some(client), None, Box::new(a)),
+            accept(Some(server), None, Box::new(b))
+        );
+        Ok((a?.0, b?.0))
+    }
+
+    #[tokio::test]
+    async fn test_roundtrip() {
+        let psk = Psk::derive(b"correct horse battery staple");
+        let (mut client, mut server) = pair(&psk, &psk).await.unwrap();
+        // larger than a single message
+        let data: Vec<u8> = (0..200_000u32).map(|x| x as u8).collect();
+        let (_, received) = tokio::join!(
+            async {
+                client.write_all(&data).await.unwrap();
+                client.flush().await.unwrap();
+            },
+            async {
+                let mut received = vec![0u8; data.len()];
+                server.read_exact(&mut received).await.unwrap();
+                received
+            }
+        );
+        assert_eq!(received, data);
+        server.write_all(b"back").await.unwrap();
+        server.flush().await.unwrap();
+        let mut buf = [0u8; 4];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"back");
+    }
+
+    #[test]
+    fn test_passphrase() {
+        let psk = Psk::from_passphrase(b"hunter2").unwrap();
+        assert_eq!(psk.0, Psk::from_passphrase(b"hunter2").unwrap().0);
+        assert_ne!(psk.0, Psk::from_passphrase(b"hunter3").unwrap().0);
+        assert_ne!(psk.0, Psk::derive(b"hunter2").0);
+    }
+
+    #[tokio::test]
+    async fn test_identity() {
+        let (client_id, server_id) = (Identity::generate().unwrap(), Identity::generate().unwrap());
+        let loaded = Identity::from_private(client_id.private.clone()).unwrap();
+        assert_eq!(loaded.public(), client_id.public());
+        let psk = Psk::derive(b"key");
+        for psk in [None, Some(&psk)] {
+            let (a, b) = tokio::io::duplex(4096);
+            let (a, b) = tokio::join!(
+                connect(psk, Some(&client_id), Box::new(a)),
+                accept(psk, Some(&server_id), Box::new(b))
+            );
+            let (client_sees, server_sees) = (a.unwrap().1.unwrap(), b.unwrap().1.unwrap());
+            assert_eq!(client_sees.key, server_id.public());
+            assert_eq!(server_sees.key, client_id.public());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_wrong_key() {
+        let res = pair(&Psk::derive(b"one"), &Psk::derive(b"two")).await;
+        assert!(res.is_err());
+    }
+
+    /// Reads one message from `from` into `buf`, returning its length.
+    async fn read_raw(from: &mut tokio::io::DuplexStream, buf: &mut [u8]) -> usize {
+        let len = from.read_u16().await.unwrap().into();
+        from.read_exact(&mut buf[..len]).await.unwrap();
+        len
+    }
+
+    async fn write_raw(to: &mut tokio::io::DuplexStream, message: &[u8]) {
+        to.write_u16(message.len() as u16).await.unwrap();
+        to.write_all(message).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tampered() {
+        let (a, mut mitm_a) = tokio::io::duplex(4096);
+        let (mut mitm_b, b) = tokio::io::duplex(4096);
+        let client = tokio::spawn(async move {
+            let mut client = connect(Some(&Psk::derive(b"key")), None, Box::new(a))
+                .await
+                .unwrap()
+                .0;
+            client.write_all(b"secret").await.unwrap();
+            client.flush().await.unwrap();
+            client
+        });
+        let server =
+            tokio::spawn(
+                async move { accept(Some(&Psk::derive(b"key")), None, Box::new(b)).await },
+            );
+        // pass the handshake through unchanged, then flip a bit in the first
+        // transport message.
+        let mut buf = vec![0u8; 4096];
+        let len = read_raw(&mut mitm_a, &mut buf).await;
+        write_raw(&mut mitm_b, &buf[..len]).await;
+        let len = read_raw(&mut mitm_b, &mut buf).await;
+        write_raw(&mut mitm_a, &buf[..len]).await;
+        let mut server = server.await.unwrap().unwrap().0;
+        let _client = client.await.unwrap();
+        let len = read_raw(&mut mitm_a, &mut buf).await;
+        buf[0] ^= 1;
+        write_raw(&mut mitm_b, &buf[..len]).await;
+        let mut received = [0u8; 6];
+        let err = server.read_exact(&mut received).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,299 @@
+//! Trust-on-first-use pairing, SSH style. Each side has a persistent
+//! [`Identity`] key used in the [Noise](crate::noise) handshake, and keeps
+//! the keys of peers it paired with in a known-peers file, by peer name.
+//!
+//! When a peer isn't known yet, both sides show the fingerprint of the pair
+//! of keys, ours and the peer's, and the operators confirm it matches by
+//! pressing Enter (or refuse with Escape) on a keyboard physically attached to
+//! that machine. Nothing is remembered unless both sides confirm. A known peer
+//! presenting a different key is refused outright.
+//!
+//! The fingerprint is the whole SHA-256 of both keys. Someone in the middle
+//! has a different key on each side, so matching fingerprints would take a
+//! SHA-256 collision.
+//!
+//! Known-peers file format: one peer per line, hex-encoded public key, a
+//! space, then the peer name. Empty lines and lines starting with `#` are
+//! ignored.
+
+use std::{
+    io::{self, Write},
+    path::PathBuf,
+    time::Duration,
+};
+
+use anyhow::Context;
+use evdev::{EventSummary, InputEvent, KeyCode};
+use futures::TryStreamExt;
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::{
+    handshake::HANDSHAKE_TIMEOUT,
+    keyboard,
+    noise::{Identity, PeerIdentity, decode_hex, encode_hex},
+    transport::{Stream, Transport},
+};
+
+/// How long the operator has to confirm a new peer.
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Pairing options shared by `hoips` and `hoipc`.
+#[derive(clap::Args, Debug)]
+pub struct PairingArgs {
+    /// File with our identity key, created if it doesn't exist. Enables
+    /// pairing; both sides have to enable it. Can be combined with `--psk`.
+    #[arg(long, requires = "known_peers", conflicts_with = "tls_cert")]
+    pub identity: Option<PathBuf>,
+    /// File with the keys of peers paired so far, created if it doesn't
+    /// exist. Peers with unknown keys have to be confirmed on both sides with
+    /// Enter, peers with changed keys are refused.
+    #[arg(long, requires = "identity")]
+    pub known_peers: Option<PathBuf>,
+}
+
+impl PairingArgs {
+    /// Loads (or creates) the identity key, if pairing is enabled.
+    pub fn load(&self, transport: Transport) -> anyhow::Result<Option<Pairing>> {
+        let (Some(identity), Some(known_peers)) = (&self.identity, &self.known_peers) else {
+            return Ok(None);
+        };
+        anyhow::ensure!(
+            transport.is_stream(),
+            "Pairing only works with TCP and WebSocket transports"
+        );
+        Ok(Some(Pairing {
+            identity: Identity::load_or_generate(identity)?,
+            known_peers: known_peers.clone(),
+        }))
+    }
+}
+
+#[derive(PartialEq, Eq, Debug)]
+pub enum Status {
+    Known,
+    Unknown,
+    /// There's a peer with that name, but with a different key.
+    Changed,
+}
+
+#[derive(Debug)]
+pub struct Pairing {
+    pub identity: Identity,
+    known_peers: PathBuf,
+}
+
+impl Pairing {
+    fn entries(&self) -> anyhow::Result<Vec<(Vec<u8>, String)>> {
+        let contents = match std::fs::read_to_string(&self.known_peers) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!("Read known peers from {}", self.known_peers.display())
+                });
+            }
+        };
+        contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'))
+            .map(|(idx, line)| {
+                let (key, name) = line
+                    .split_once(' ')
+                    .context("Expected a key and a name")
+                    .and_then(|(key, name)| Ok((decode_hex(key)?, name.to_owned())))
+                    .with_context(|| {
+                        format!("Parse line {} of {}", idx + 1, self.known_peers.display())
+                    })?;
+                Ok((key, name))
+            })
+            .collect()
+    }
+
+    pub fn check(&self, name: &str, key: &[u8]) -> anyhow::Result<Status> {
+        let entries = self.entries()?;
+        Ok(if entries.iter().any(|(k, n)| n == name && k == key) {
+            Status::Known
+        } else if entries.iter().any(|(_, n)| n == name) {
+            Status::Changed
+        } else {
+            Status::Unknown
+        })
+    }
+
+    pub fn remember(&self, name: &str, key: &[u8]) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            !name.chars().any(char::is_control),
+            "Peer name {name:?} contains control characters"
+        );
+        let context = || format!("Write known peers to {}", self.known_peers.display());
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(&self.known_peers)
+            .with_context(context)?;
+        writeln!(file, "{} {name}", encode_hex(key)).with_context(context)
+    }
+
+    /// Checks the peer against known peers, asking the local operator via
+    /// `confirm` if it's new, then agrees with the peer on the outcome. Both
+    /// sides have to call this right after the HoIP handshake.
+    pub async fn pair(
+        &self,
+        stream: &mut Stream,
+        peer_name: &str,
+        peer: &PeerIdentity,
+        confirm: impl Future<Output = anyhow::Result<bool>>,
+    ) -> anyhow::Result<()> {
+        let fingerprint = fingerprint(&peer.key);
+        let status = self.check(peer_name, &peer.key)?;
+        let accepted = match status {
+            Status::Known => true,
+            Status::Changed => {
+                tracing::error!(
+                    peer_name,
+                    fingerprint,
+                    known_peers = %self.known_peers.display(),
+                    "Peer key changed! Someone could be impersonating it. If the change is \
+                    expected, remove the old entry from known peers"
+                );
+                false
+            }
+            Status::Unknown => {
+                tracing::warn!(
+                    peer_name,
+                    fingerprint,
+                    pair_fingerprint = pair_fingerprint(self.identity.public(), &peer.key),
+                    "New peer. If the peer shows the same pair fingerprint, press Enter to \
+                    pair, or Escape to refuse"
+                );
+                match tokio::time::timeout(CONFIRM_TIMEOUT, confirm).await {
+                    Ok(res) => res.context("Confirm pairing")?,
+                    Err(_) => {
+                        tracing::warn!("Pairing not confirmed in time");
+                        false
+                    }
+                }
+            }
+        };
+        stream.write_u8(accepted.into()).await?;
+        stream.flush().await?;
+        anyhow::ensure!(accepted, "Refused peer {peer_name}");
+        // the peer might still be waiting for its operator.
+        let theirs = tokio::time::timeout(CONFIRM_TIMEOUT + HANDSHAKE_TIMEOUT, stream.read_u8())
+            .await
+            .context("Timed out waiting for the peer to confirm pairing")?
+            .context("Read pairing answer")?;
+        anyhow::ensure!(theirs != 0, "Peer refused pairing");
+        if status == Status::Unknown {
+            self.remember(peer_name, &peer.key)?;
+            tracing::info!(peer_name, fingerprint, "Paired with peer");
+        }
+        Ok(())
+    }
+}
+
+/// Short form of a public key, for logs.
+/// Fingerprint of our key and the peer's, the same on both sides if each got
+/// the other's actual key.
+pub fn pair_fingerprint(ours: &[u8], theirs: &[u8]) -> String {
+    let (first, second) = if ours <= theirs {
+        (ours, theirs)
+    } else {
+        (theirs, ours)
+    };
+    fingerprint(&[first, second].concat())
+}
+
+pub fn fingerprint(key: &[u8]) -> String {
+    Sha256::digest(key)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+/// The operator's answer, if `evt` is one: Enter to accept, Escape to refuse.
+pub fn answer(evt: &InputEvent) -> Option<bool> {
+    match evt.destructure() {
+        EventSummary::Key(_, KeyCode::KEY_ENTER | KeyCode::KEY_KPENTER, 1) => Some(true),
+        EventSummary::Key(_, KeyCode::KEY_ESC, 1) => Some(false),
+        _ => None,
+    }
+}
+
+/// Waits for Enter or Escape on any keyboard attached to this machine.
+pub async fn confirm_on_keyboards() -> anyhow::Result<bool> {
+    let mut events =
+        keyboard::events(&[KeyCode::KEY_ENTER]).context("No keyboard to confirm pairing on")?;
+    while let Some(evt) = events.try_next().await.context("Read keyboard")? {
+        if let Some(answer) = answer(&evt) {
+            return Ok(answer);
+        }
+    }
+    anyhow::bail!("Keyboards went away")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn pairing(name: &str) -> Pairing {
+        let path =
+            std::env::temp_dir().join(format!("hoip-known-peers-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        Pairing {
+            identity: Identity::generate().unwrap(),
+            known_peers: path,
+        }
+    }
+
+    #[test]
+    fn test_known_peers() {
+        let pairing = pairing("check");
+        assert_eq!(pairing.check("laptop", b"key1").unwrap(), Status::Unknown);
+        pairing.remember("laptop", b"key1").unwrap();
+        pairing.remember("desk top", b"key2").unwrap();
+        assert_eq!(pairing.check("laptop", b"key1").unwrap(), Status::Known);
+        assert_eq!(pairing.check("desk top", b"key2").unwrap(), Status::Known);
+        assert_eq!(pairing.check("laptop", b"key2").unwrap(), Status::Changed);
+        assert_eq!(pairing.check("other", b"key1").unwrap(), Status::Unknown);
+        std::fs::remove_file(&pairing.known_peers).unwrap();
+    }
+
+    #[test]
+    fn test_pair_fingerprint() {
+        assert_eq!(pair_fingerprint(b"a", b"b"), pair_fingerprint(b"b", b"a"));
+        assert_ne!(pair_fingerprint(b"a", b"b"), pair_fingerprint(b"a", b"c"));
+        assert_eq!(pair_fingerprint(b"a", b"b").len(), 32 * 3 - 1);
+    }
+
+    #[tokio::test]
+    async fn test_pair() {
+        let (ours, theirs) = (pairing("ours"), pairing("theirs"));
+        let peer = |key: &[u8]| PeerIdentity { key: key.to_vec() };
+        let (ours_key, theirs_key) = (peer(b"ours"), peer(b"theirs"));
+        let run = async |ok_ours: bool, ok_theirs: bool| {
+            let (a, b) = tokio::io::duplex(64);
+            let (mut a, mut b): (Stream, Stream) = (Box::new(a), Box::new(b));
+            tokio::join!(
+                ours.pair(&mut a, "theirs", &theirs_key, async move { Ok(ok_ours) }),
+                theirs.pair(&mut b, "ours", &ours_key, async move { Ok(ok_theirs) }),
+            )
+        };
+        // nothing is remembered unless both confirm
+        let (a, b) = run(true, false).await;
+        assert!(a.is_err() && b.is_err());
+        assert_eq!(ours.check("theirs", b"theirs").unwrap(), Status::Unknown);
+        let (a, b) = run(true, true).await;
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(ours.check("theirs", b"theirs").unwrap(), Status::Known);
+        assert_eq!(theirs.check("ours", b"ours").unwrap(), Status::Known);
+        // no confirmation needed the second time around
+        let (a, b) = run(false, false).await;
+        assert!(a.is_ok() && b.is_ok());
+        std::fs::remove_file(&ours.known_peers).unwrap();
+        std::fs::remove_file(&theirs.known_peers).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+//! QUIC transport. The handshake, control messages, and everything but pointer
+//! motion go over a single bidirectional stream, same as with TCP. Pointer
+//! motion goes over unreliable datagrams, so that a lost packet doesn't hold
+//! up the ones after it.
+//!
+//! QUIC is always encrypted. With [`Tls`] configured, peers authenticate each
+//! other same as with TLS over TCP. Otherwise they're not authenticated: the
+//! accepting side generates a throwaway self-signed certificate, and the
+//! connecting side accepts any certificate. The accepting side follows the connecting side
+//! across address changes (connection migration), so a laptop switching
+//! networks keeps its session.
+
+use std::{net::SocketAddr, sync::Arc};
+
+use anyhow::Context;
+use quinn::{
+    ClientConfig, Endpoint, ServerConfig,
+    crypto::rustls::{QuicClientConfig, QuicServerConfig},
+};
+use rustls::{
+    DigitallySignedStruct, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime},
+};
+use tokio::io::Join;
+use tokio_util::{
+    bytes::{Buf, BufMut, BytesMut},
+    codec::{Decoder, Encoder},
+};
+
+use crate::{
+    acl::Acl,
+    codec::{Codec, Message, Packet},
+    handshake::Features,
+    tls::{SERVER_NAME, Tls, provider},
+    udp::is_after,
+};
+
+const ALPN: &[u8] = b"hoip";
+
+pub type Stream = Join<quinn::RecvStream, quinn::SendStream>;
+
+/// Accepts any server certificate. Traffic is still encrypted, but the server
+/// is not authenticated.
+#[derive(Debug)]
+struct NoServerVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoServerVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+fn client_config(tls: Option<&Tls>) -> anyhow::Result<ClientConfig> {
+    let mut crypto = match tls {
+        Some(tls) => tls.client_config()?,
+        None => {
+            let provider = provider();
+            rustls::ClientConfig::builder_with_provider(provider.clone())
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .context("Set TLS versions")?
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoServerVerification(provider)))
+                .with_no_client_auth()
+        }
+    };
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(crypto).context("Build QUIC client config")?;
+    Ok(ClientConfig::new(Arc::new(crypto)))
+}
+
+fn server_config(tls: Option<&Tls>) -> anyhow::Result<ServerConfig> {
+    let mut crypto = match tls {
+        Some(tls) => tls.server_config()?,
+        None => {
+            let cert = rcgen::generate_simple_self_signed([SERVER_NAME.to_owned()])
+                .context("Generate self-signed certificate")?;
+            let key =
+                PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()));
+            rustls::ServerConfig::builder_with_provider(provider())
+                .with_protocol_versions(&[&rustls::version::TLS13])
+                .context("Set TLS versions")?
+                .with_no_client_auth()
+                .with_single_cert(vec![cert.cert.der().clone()], key)
+                .context("Set certificate")?
+        }
+    };
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicServerConfig::try_from(crypto).context("Build QUIC server config")?;
+    Ok(ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Connects to `remote` and opens the main stream.
+pub async fn connect(
+    remote: SocketAddr,
+    tls: Option<&Tls>,
+) -> anyhow::Result<(quinn::Connection, Stream)> {
+    let bind: SocketAddr = if remote.is_ipv4() {
+        "0.0.0.0:0".parse()?
+    } else {
+        "[::]:0".parse()?
+    };
+    let mut endpoint = Endpoint::client(bind).context("Bind QUIC endpoint")?;
+    endpoint.set_default_client_config(client_config(tls)?);
+    let conn = endpoint
+        .connect(remote, SERVER_NAME)
+        .context("Start QUIC connection")?
+        .await
+        .context("Establish QUIC connection")?;
+    let (send, recv) = conn.open_bi().await.context("Open QUIC stream")?;
+    Ok((conn, tokio::io::join(recv, send)))
+}
+
+pub struct Listener {
+    endpoint: Endpoint,
+}
+
+impl Listener {
+    pub fn bind(addr: SocketAddr, tls: Option<&Tls>) -> anyhow::Result<Self> {
+        let endpoint = Endpoint::server(server_config(tls)?, addr).context("Bind QUIC endpoint")?;
+        Ok(Self { endpoint })
+    }
+
+    /// Next connection attempt `acl` permits, see [`establish`]. Others are
+    /// refused before the QUIC handshake.
+    pub async fn incoming(&self, acl: &Acl) -> anyhow::Result<quinn::Incoming> {
+        loop {
+            let incoming = self.endpoint.accept().await.context("Endpoint closed")?;
+            let remote = incoming.remote_address();
+            if acl.permits(remote) {
+                return Ok(incoming);
+            }
+            tracing::warn!(%remote, "Rejected connection from disallowed address");
+            incoming.refuse();
+        }
+    }
+}
+
+/// Completes the QUIC handshake, and accepts the main stream.
+pub async fn establish(incoming: quinn::Incoming) -> anyhow::Result<(quinn::Connection, Stream)> {
+    let conn = incoming.await.context("Establish QUIC connection")?;
+    // the stream only shows up once the peer sends something on it, which
+    // it does right away with the handshake.
+    let (send, recv) = conn.accept_bi().await.context("Accept QUIC stream")?;
+    Ok((conn, tokio::io::join(recv, send)))
+}
+
+/// Pointer motion over QUIC datagrams. Each datagram is a `u32` sequence
+/// number followed by the packet as encoded by [`Codec`]. Datagrams older than
+/// the last one received are dropped.
+pub struct Datagrams {
+    conn: quinn::Connection,
+    codec: Codec,
+    next_seq: u32,
+    last_seq: Option<u32>,
+}
+
+impl Datagrams {
+    pub fn new(conn: quinn::Connection, features: Features) -> Self {
+        Self {
+            conn,
+            codec: Codec::new(features),
+            next_seq: 0,
+            last_seq: None,
+        }
+    }
+
+    /// Whether `packet` should go over datagrams rather than the stream.
+    pub fn carries(&self, packet: &Packet) -> bool {
+        packet.is_motion() && self.conn.max_datagram_size().is_some()
+    }
+
+    pub async fn send(&mut self, packet: Packet) -> anyhow::Result<()> {
+        let mut datagram = BytesMut::new();
+        datagram.put_u32(self.next_seq);
+        self.codec
+            .encode(packet.into(), &mut datagram)
+            .context("Encode packet")?;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        match self.conn.send_datagram(datagram.freeze()) {
+            // motion is fine to lose, the next packet will make up for it
+            Err(quinn::SendDatagramError::TooLarge) => {
+                tracing::debug!("Dropping motion packet too large for a datagram");
+                Ok(())
+            }
+            res => res.context("Send datagram"),
+        }
+    }
+
+    /// Resolves with the next datagram that's newer than all received so far.
+    /// Cancel-safe.
+    pub async fn recv(&mut self) -> anyhow::Result<Packet> {
+        loop {
+            let datagram = self
+                .conn
+                .read_datagram()
+                .await
+                .context("Receive datagram")?;
+            let mut datagram = BytesMut::from(datagram);
+            let seq = datagram.try_get_u32()?;
+            let packet = match self.codec.decode(&mut datagram)? {
+                Some(Message::Packet(packet)) if datagram.is_empty() => packet,
+                // invalid packet, already logged by the codec
+                None if datagram.is_empty() => continue,
+                _ => anyhow::bail!("Malformed datagram"),
+            };
+            if self.last_seq.is_some_and(|last| !is_after(seq, last)) {
+                tracing::trace!(seq, "Dropping stale motion");
+                continue;
+            }
+            self.last_seq = Some(seq);
+            return Ok(packet);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use evdev::{EventType, InputEvent, RelativeAxisCode};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_quic() {
+        let listener = Listener::bind("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let addr = listener.endpoint.local_addr().unwrap();
+        let acl = Acl::default();
+        let (client, server) = tokio::join!(
+            async {
+                let (conn, mut stream) = connect(addr, None).await.unwrap();
+                stream.write_all(b"hello").await.unwrap();
+                stream.flush().await.unwrap();
+                (conn, stream)
+            },
+            async { establish(listener.incoming(&acl).await?).await },
+        );
+        let (client_conn, _client_stream) = client;
+        let (server_conn, mut server_stream) = server.unwrap();
+        let mut buf = [0u8; 5];
+        server_stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+
+        let mut client = Datagrams::new(client_conn, Features::BATCH);
+        let mut server = Datagrams::new(server_conn, Features::BATCH);
+        let motion = Packet {
+            device: 0,
+            events: vec![InputEvent::new(
+                EventType::RELATIVE.0,
+                RelativeAxisCode::REL_X.0,
+                5,
+            )],
+        };
+        assert!(client.carries(&motion));
+        client.send(motion.clone()).await.unwrap();
+        let received = server.recv().await.unwrap();
+        assert_eq!(received.events[0].value(), 5);
+    }
+}
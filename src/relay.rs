@@ -0,0 +1,480 @@
+//! Sessions through `hoipr`, a relay both sides connect out to, for machines
+//! that can't reach each other directly.
+//!
+//! Right after a peer connects, the relay sends a random challenge, and the
+//! peer answers with an [`Intro`] signed with the relay [`Secret`]: a `hoipc`
+//! registers under its name and waits, a `hoips` asks for a client by name and
+//! gets a `u8` back, nonzero if the client is there. Without the secret,
+//! nobody can register, or take over a name someone else registered.
+//!
+//! Sessions encrypted end to end, with TLS, pre-shared keys or pairing, are
+//! [`forward`]ed as they are. Others are [`splice`]d, following the session
+//! closely enough to release keys the server left pressed. The secret only
+//! guards the relay itself; anyone on the path can still read and alter an
+//! unencrypted session.
+
+use std::{collections::BTreeSet, path::PathBuf, time::SystemTime};
+
+use anyhow::Context;
+use evdev::{EventSummary, EventType, InputEvent, KeyCode};
+use futures::{SinkExt, TryStreamExt};
+use ring::{
+    hmac,
+    rand::{SecureRandom, SystemRandom},
+};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_util::codec::Framed;
+
+use crate::{
+    approval::APPROVAL_TIMEOUT,
+    codec::{Codec, Message, Packet},
+    device,
+    handshake::{Features, HANDSHAKE_TIMEOUT, Hello, PROTOCOL_VERSION},
+    transport::Stream,
+};
+
+const MAGIC: [u8; 4] = *b"HOIR";
+
+const KIND_CLIENT: u8 = 0;
+const KIND_SERVER: u8 = 1;
+
+const FLAG_ENCRYPTED: u8 = 1;
+
+const KEY_CONTEXT: &[u8] = b"hoip relay v1";
+
+type Challenge = [u8; 16];
+
+const MAC_LEN: usize = 32;
+
+/// Features the relay can follow. [`Features::UDP`] would bypass it.
+pub const FEATURES: Features = Features::supported().difference(Features::UDP);
+
+/// Relay secret options shared by `hoips`, `hoipc` and `hoipr`.
+#[derive(clap::Args, Debug)]
+pub struct RelaySecretArgs {
+    /// Passphrase for the `hoipr` relay, which only lets in servers and
+    /// clients that know it. Required by `hoipr`, and for using it.
+    #[arg(
+        long,
+        env = "HOIP_RELAY_SECRET",
+        hide_env_values = true,
+        conflicts_with = "relay_secret_file"
+    )]
+    pub relay_secret: Option<String>,
+    /// File with the relay secret, as an alternative to `--relay-secret`. Any
+    /// contents will do.
+    #[arg(long)]
+    pub relay_secret_file: Option<PathBuf>,
+}
+
+impl RelaySecretArgs {
+    /// Loads the secret, if configured.
+    pub fn load(&self) -> anyhow::Result<Option<Secret>> {
+        let secret = match (&self.relay_secret, &self.relay_secret_file) {
+            (Some(secret), _) => secret.as_bytes().to_vec(),
+            (None, Some(path)) => std::fs::read(path)
+                .with_context(|| format!("Read relay secret from {}", path.display()))?,
+            (None, None) => return Ok(None),
+        };
+        anyhow::ensure!(!secret.is_empty(), "Relay secret is empty");
+        Ok(Some(Secret::derive(&secret)))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Secret(hmac::Key);
+
+impl Secret {
+    pub fn derive(secret: &[u8]) -> Self {
+        let key = Sha256::new()
+            .chain_update(KEY_CONTEXT)
+            .chain_update(secret)
+            .finalize();
+        Self(hmac::Key::new(hmac::HMAC_SHA256, &key))
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Role {
+    /// A `hoipc` waiting for servers.
+    Client,
+    /// A `hoips` asking for a client.
+    Server,
+}
+
+/// What a peer wants from the relay.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Intro {
+    pub role: Role,
+    /// The client's name, either way.
+    pub name: String,
+    /// Whether the session will be encrypted end to end, so that the relay
+    /// can only [`forward`] it.
+    pub encrypted: bool,
+}
+
+impl Intro {
+    /// Answers the relay's challenge.
+    pub async fn write(
+        &self,
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        secret: &Secret,
+    ) -> anyhow::Result<()> {
+        let mut magic = [0u8; MAGIC.len()];
+        let mut challenge = Challenge::default();
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+            stream.read_exact(&mut magic).await?;
+            stream.read_exact(&mut challenge).await
+        })
+        .await
+        .context("Timed out waiting for the relay")?
+        .context("Read relay challenge")?;
+        anyhow::ensure!(magic == MAGIC, "Remote is not a HoIP relay");
+        let mut buf = self.to_bytes()?;
+        let mac = hmac::sign(&secret.0, &[&challenge[..], &buf].concat());
+        buf.extend_from_slice(mac.as_ref());
+        stream.write_all(&buf).await.context("Send relay intro")?;
+        stream.flush().await.context("Flush relay intro")
+    }
+
+    /// Challenges the peer, and reads its answer. Fails unless it was signed
+    /// with `secret`.
+    pub async fn read(
+        stream: &mut (impl AsyncRead + AsyncWrite + Unpin),
+        secret: &Secret,
+    ) -> anyhow::Result<Self> {
+        let mut challenge = Challenge::default();
+        SystemRandom::new()
+            .fill(&mut challenge)
+            .map_err(|_| anyhow::anyhow!("Generate challenge"))?;
+        stream
+            .write_all(&[&MAGIC[..], &challenge].concat())
+            .await
+            .context("Send relay challenge")?;
+        stream.flush().await.context("Send relay challenge")?;
+        let mut magic = [0u8; MAGIC.len()];
+        stream.read_exact(&mut magic).await.context("Read magic")?;
+        anyhow::ensure!(magic == MAGIC, "Peer doesn't speak the relay protocol");
+        let kind = stream.read_u8().await.context("Read intro kind")?;
+        let flags = stream.read_u8().await.context("Read intro flags")?;
+        let name_len = stream.read_u8().await.context("Read name length")?;
+        let mut name = vec![0u8; name_len.into()];
+        stream.read_exact(&mut name).await.context("Read name")?;
+        let mut mac = [0u8; MAC_LEN];
+        stream
+            .read_exact(&mut mac)
+            .await
+            .context("Read intro MAC")?;
+        let role = match kind {
+            KIND_CLIENT => Role::Client,
+            KIND_SERVER => Role::Server,
+            _ => anyhow::bail!("Unknown intro kind {kind}"),
+        };
+        let this = Self {
+            role,
+            name: String::from_utf8_lossy(&name).into_owned(),
+            encrypted: flags & FLAG_ENCRYPTED != 0,
+        };
+        // checks the name as received, before any lossy conversion.
+        let signed = [&challenge[..], &MAGIC, &[kind, flags, name_len], &name].concat();
+        hmac::verify(&secret.0, &signed, &mac)
+            .map_err(|_| anyhow::anyhow!("Intro not signed with the relay secret"))?;
+        Ok(this)
+    }
+
+    fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let name = self.name.as_bytes();
+        let name_len = u8::try_from(name.len()).context("Name is too long")?;
+        let kind = match self.role {
+            Role::Client => KIND_CLIENT,
+            Role::Server => KIND_SERVER,
+        };
+        let flags = if self.encrypted { FLAG_ENCRYPTED } else { 0 };
+        let mut buf = Vec::with_capacity(MAGIC.len() + 3 + name.len() + MAC_LEN);
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&[kind, flags, name_len]);
+        buf.extend_from_slice(name);
+        Ok(buf)
+    }
+}
+
+/// Server side: asks the relay for `client`.
+pub async fn request(
+    stream: &mut Stream,
+    client: &str,
+    secret: &Secret,
+    encrypted: bool,
+) -> anyhow::Result<()> {
+    let intro = Intro {
+        role: Role::Server,
+        name: client.to_owned(),
+        encrypted,
+    };
+    intro.write(stream, secret).await?;
+    let found = tokio::time::timeout(HANDSHAKE_TIMEOUT, stream.read_u8())
+        .await
+        .context("Timed out waiting for the relay")?
+        .context("Read relay answer")?;
+    anyhow::ensure!(found != 0, "Client {client:?} isn't connected to the relay");
+    Ok(())
+}
+
+/// Forwards an encrypted session between `server` and `client` as it is.
+/// Nothing gets released if the server goes away, `hoipc` does that itself.
+pub async fn forward(mut server: Stream, mut client: Stream) -> anyhow::Result<()> {
+    tokio::io::copy_bidirectional(&mut server, &mut client)
+        .await
+        .context("Forward session")?;
+    Ok(())
+}
+
+/// Forwards a session between `server` and `client`, which have both sent
+/// their [`Intro`] already. When either goes away, releases whatever keys the
+/// server left pressed, and says goodbye to the other side if it can.
+pub async fn splice(mut server: Stream, mut client: Stream) -> anyhow::Result<()> {
+    let Some(features) = setup(&mut server, &mut client).await? else {
+        return Ok(());
+    };
+    // same epoch on both sides, so that timestamps pass through unchanged.
+    let epoch = SystemTime::now();
+    let mut server = Framed::new(server, Codec::with_epoch(features, epoch));
+    let mut client = Framed::new(client, Codec::with_epoch(features, epoch));
+    let mut pressed = BTreeSet::<(u16, KeyCode)>::new();
+    let control = features.contains(Features::CONTROL);
+    let res = loop {
+        tokio::select! {
+            msg = server.try_next() => {
+                let msg = match msg {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e).context("Read from server"),
+                };
+                let goodbye = matches!(msg, Message::Goodbye(_) | Message::Error(_));
+                match &msg {
+                    Message::Packet(packet) => track(&mut pressed, packet),
+                    Message::ReleaseAll => pressed.clear(),
+                    _ => {}
+                }
+                // if this fails, there's no one left to clean up for.
+                client.send(msg).await.context("Send to client")?;
+                if goodbye {
+                    return Ok(());
+                }
+            }
+            msg = client.try_next() => {
+                let msg = match msg {
+                    Ok(Some(msg)) => msg,
+                    Ok(None) => {
+                        if control {
+                            let bye = Message::Error("Client went away".to_owned());
+                            let _ = tokio::time::timeout(HANDSHAKE_TIMEOUT, server.send(bye)).await;
+                        }
+                        return Ok(());
+                    }
+                    Err(e) => return Err(e).context("Read from client"),
+                };
+                let goodbye = matches!(msg, Message::Goodbye(_) | Message::Error(_));
+                server.send(msg).await.context("Send to server")?;
+                if goodbye {
+                    return Ok(());
+                }
+            }
+        }
+    };
+    // the server went away, possibly mid-keypress.
+    if !pressed.is_empty() {
+        tracing::info!(
+            keys = pressed.len(),
+            "Releasing keys the server left pressed"
+        );
+    }
+    let release = async {
+        for (device, events) in releases(pressed) {
+            client.feed(Packet { device, events }.into()).await?;
+        }
+        if control {
+            client
+                .feed(Message::Error("Server went away".to_owned()))
+                .await?;
+        }
+        client.flush().await
+    };
+    let _ = tokio::time::timeout(HANDSHAKE_TIMEOUT, release).await;
+    res
+}
+
+/// Relays everything before the event stream, returns the negotiated features
+/// if the session goes ahead.
+async fn setup(server: &mut Stream, client: &mut Stream) -> anyhow::Result<Option<Features>> {
+    let (server_hello, client_hello) = tokio::time::timeout(HANDSHAKE_TIMEOUT, async {
+        let mut server_hello = Hello::read(server).await.context("Read server hello")?;
+        server_hello.features = server_hello.features.intersection(FEATURES);
+        server_hello.write(client).await?;
+        let mut client_hello = Hello::read(client).await.context("Read client hello")?;
+        client_hello.features = client_hello.features.intersection(FEATURES);
+        client_hello.write(server).await?;
+        anyhow::Ok((server_hello, client_hello))
+    })
+    .await
+    .context("Handshake timed out")??;
+    tracing::info!(
+        server = server_hello.name,
+        client = client_hello.name,
+        "Relaying session"
+    );
+    if server_hello.version != PROTOCOL_VERSION || client_hello.version != PROTOCOL_VERSION {
+        // the peers will tell.
+        tracing::warn!("Protocol version mismatch");
+        return Ok(None);
+    }
+    let features = server_hello
+        .features
+        .intersection(client_hello.features)
+        .with_dependencies();
+    if features.contains(Features::APPROVAL) {
+        let verdict = tokio::time::timeout(APPROVAL_TIMEOUT + HANDSHAKE_TIMEOUT, client.read_u8())
+            .await
+            .context("Timed out waiting for approval")?
+            .context("Read approval verdict")?;
+        server
+            .write_u8(verdict)
+            .await
+            .context("Send approval verdict")?;
+        server.flush().await.context("Send approval verdict")?;
+        if verdict == 0 {
+            return Ok(None);
+        }
+    }
+    if features.contains(Features::DEVICES) {
+        let infos = device::recv(server).await?;
+        device::send(client, &infos).await?;
+    }
+    Ok(Some(features))
+}
+
+fn track(pressed: &mut BTreeSet<(u16, KeyCode)>, packet: &Packet) {
+    for evt in &packet.events {
+        if let EventSummary::Key(_, key, value) = evt.destructure() {
+            if value == 0 {
+                pressed.remove(&(packet.device, key));
+            } else {
+                pressed.insert((packet.device, key));
+            }
+        }
+    }
+}
+
+/// Key releases for everything in `pressed`, grouped by device.
+fn releases(pressed: BTreeSet<(u16, KeyCode)>) -> Vec<(u16, Vec<InputEvent>)> {
+    let mut res: Vec<(u16, Vec<InputEvent>)> = vec![];
+    for (device, key) in pressed {
+        let evt = InputEvent::new(EventType::KEY.0, key.0, 0);
+        match res.last_mut() {
+            Some((last, events)) if *last == device => events.push(evt),
+            _ => res.push((device, vec![evt])),
+        }
+    }
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use crate::handshake;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_intro() {
+        let secret = Secret::derive(b"secret");
+        for intro in [
+            Intro {
+                role: Role::Client,
+                name: "desk".into(),
+                encrypted: false,
+            },
+            Intro {
+                role: Role::Server,
+                name: "laptop".into(),
+                encrypted: true,
+            },
+        ] {
+            let (mut a, mut b) = tokio::io::duplex(256);
+            let (written, read) =
+                tokio::join!(intro.write(&mut a, &secret), Intro::read(&mut b, &secret));
+            written.unwrap();
+            assert_eq!(read.unwrap(), intro);
+        }
+
+        let intro = Intro {
+            role: Role::Client,
+            name: "desk".into(),
+            encrypted: false,
+        };
+        let (mut a, mut b) = tokio::io::duplex(256);
+        let other = Secret::derive(b"other");
+        let (_, read) = tokio::join!(intro.write(&mut a, &other), Intro::read(&mut b, &secret));
+        assert!(read.is_err());
+
+        // an intro recorded earlier doesn't answer a new challenge.
+        let (mut a, mut b) = tokio::io::duplex(256);
+        b.write_all(&[&MAGIC[..], &[0; 16]].concat()).await.unwrap();
+        intro.write(&mut a, &secret).await.unwrap();
+        let mut recorded = vec![0u8; MAGIC.len() + 3 + 4 + MAC_LEN];
+        b.read_exact(&mut recorded).await.unwrap();
+        let (mut a, mut b) = tokio::io::duplex(256);
+        a.write_all(&recorded).await.unwrap();
+        assert!(Intro::read(&mut b, &secret).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_splice() {
+        let (server, relay_server) = tokio::io::duplex(1024);
+        let (client, relay_client) = tokio::io::duplex(1024);
+        let relay = tokio::spawn(splice(Box::new(relay_server), Box::new(relay_client)));
+        let (mut server, mut client): (Stream, Stream) = (Box::new(server), Box::new(client));
+
+        let server_hello = Hello::new("server");
+        let mut client_hello = Hello::new("client");
+        client_hello.features = Features::supported().difference(Features::APPROVAL);
+        let (s, c) = tokio::join!(
+            handshake::connect(&mut server, &server_hello),
+            handshake::accept(&mut client, &client_hello),
+        );
+        let (s, c) = (s.unwrap(), c.unwrap());
+        assert_eq!(s.features, c.features);
+        assert!(!s.features.contains(Features::UDP));
+        assert!(!s.features.contains(Features::APPROVAL));
+
+        device::send(&mut server, &[]).await.unwrap();
+        assert!(device::recv(&mut client).await.unwrap().is_empty());
+
+        let mut server = Framed::new(server, Codec::new(s.features));
+        let mut client = Framed::new(client, Codec::new(c.features));
+        let key = |value| InputEvent::new(EventType::KEY.0, KeyCode::KEY_A.0, value);
+        let summary = |evt: &InputEvent| (evt.event_type(), evt.code(), evt.value());
+        let packet = Packet {
+            device: 0,
+            events: vec![key(1)],
+        };
+        server.send(packet.clone().into()).await.unwrap();
+        let Some(Message::Packet(got)) = client.try_next().await.unwrap() else {
+            panic!("expected a packet");
+        };
+        assert_eq!(summary(&got.events[0]), summary(&packet.events[0]));
+        client.send(Message::Ping(7)).await.unwrap();
+        assert_eq!(server.try_next().await.unwrap(), Some(Message::Ping(7)));
+
+        // the server goes away with the key still pressed
+        drop(server);
+        let Some(Message::Packet(got)) = client.try_next().await.unwrap() else {
+            panic!("expected a release");
+        };
+        assert_eq!(summary(&got.events[0]), summary(&key(0)));
+        assert!(matches!(
+            client.try_next().await.unwrap(),
+            Some(Message::Error(_))
+        ));
+        relay.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,372 @@
+//! Optional TLS with mutual authentication. Both sides present a certificate,
+//! and only accept peers whose certificate is in their trust file. There's no
+//! CA or host name involved, certificates are compared as-is, so self-signed
+//! ones made with `--generate-cert` work fine.
+//!
+//! With TCP, the stream is wrapped in TLS before the HoIP handshake. QUIC uses
+//! the same certificates for its own TLS instead.
+
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Context;
+use rustls::{
+    DigitallySignedStruct, DistinguishedName, SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::CryptoProvider,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime, pem::PemObject},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::{
+    handshake::HANDSHAKE_TIMEOUT,
+    transport::{Stream, Transport},
+};
+
+/// Name certificates are issued for. Not checked, connections go to whatever
+/// address was given or discovered.
+pub const SERVER_NAME: &str = "hoip";
+
+/// TLS options shared by `hoips` and `hoipc`.
+#[derive(clap::Args, Debug)]
+pub struct TlsArgs {
+    /// Certificate to present to the peer, in PEM format. Enables TLS; both
+    /// sides have to enable it. Create one with `--generate-cert`.
+    #[arg(long, requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+    /// Private key for `--tls-cert`, in PEM format.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+    /// Peer certificates to accept, in PEM format. Only peers presenting one
+    /// of these exact certificates are allowed. Required with `--tls-cert`.
+    #[arg(long, requires = "tls_cert")]
+    pub tls_trust: Option<PathBuf>,
+    /// Generate a self-signed certificate and key, write them to `--tls-cert`
+    /// and `--tls-key`, and exit. Give the certificate to the peer to put in
+    /// its `--tls-trust`.
+    #[arg(long, requires = "tls_cert")]
+    pub generate_cert: bool,
+}
+
+impl TlsArgs {
+    /// Loads the configured certificates, if TLS is enabled.
+    pub fn load(&self, transport: Transport) -> anyhow::Result<Option<Tls>> {
+        let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) else {
+            return Ok(None);
+        };
+        anyhow::ensure!(
+            transport != Transport::Udp,
+            "UDP transport sends input packets unencrypted, use TCP or QUIC with TLS"
+        );
+        let trust = self
+            .tls_trust
+            .as_ref()
+            .context("--tls-trust is required with --tls-cert")?;
+        let tls = Tls {
+            certs: CertificateDer::pem_file_iter(cert)
+                .and_then(|iter| iter.collect())
+                .with_context(|| format!("Read certificate from {}", cert.display()))?,
+            key: PrivateKeyDer::from_pem_file(key)
+                .with_context(|| format!("Read private key from {}", key.display()))?,
+            trusted: CertificateDer::pem_file_iter(trust)
+                .and_then(|iter| iter.collect())
+                .with_context(|| format!("Read trusted certificates from {}", trust.display()))?,
+        };
+        anyhow::ensure!(
+            !tls.certs.is_empty(),
+            "No certificate in {}",
+            cert.display()
+        );
+        anyhow::ensure!(
+            !tls.trusted.is_empty(),
+            "No certificates in {}",
+            trust.display()
+        );
+        Ok(Some(tls))
+    }
+
+    /// Handles `--generate-cert`. Returns whether it was given.
+    pub fn generate(&self) -> anyhow::Result<bool> {
+        if !self.generate_cert {
+            return Ok(false);
+        }
+        let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) else {
+            anyhow::bail!("--generate-cert needs both --tls-cert and --tls-key");
+        };
+        let pair = rcgen::generate_simple_self_signed([SERVER_NAME.to_owned()])
+            .context("Generate certificate")?;
+        write_new(key, &pair.signing_key.serialize_pem(), 0o600)
+            .with_context(|| format!("Write private key to {}", key.display()))?;
+        write_new(cert, &pair.cert.pem(), 0o644)
+            .with_context(|| format!("Write certificate to {}", cert.display()))?;
+        tracing::info!(cert = %cert.display(), key = %key.display(), "Generated certificate");
+        Ok(true)
+    }
+}
+
+/// Writes a file that doesn't exist yet.
+fn write_new(path: &std::path::Path, contents: &str, mode: u32) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(mode)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+pub(crate) fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+/// Our certificate and key, and the peer certificates we accept.
+#[derive(Debug)]
+pub struct Tls {
+    certs: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    trusted: Vec<CertificateDer<'static>>,
+}
+
+impl Tls {
+    fn verifier(&self) -> Arc<Pinned> {
+        Arc::new(Pinned {
+            trusted: self.trusted.clone(),
+            provider: provider(),
+        })
+    }
+
+    /// Configuration for the connecting side.
+    pub fn client_config(&self) -> anyhow::Result<rustls::ClientConfig> {
+        rustls::ClientConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .context("Set TLS versions")?
+            .dangerous()
+            .with_custom_certificate_verifier(self.verifier())
+            .with_client_auth_cert(self.certs.clone(), self.key.clone_key())
+            .context("Set certificate")
+    }
+
+    /// Configuration for the accepting side.
+    pub fn server_config(&self) -> anyhow::Result<rustls::ServerConfig> {
+        rustls::ServerConfig::builder_with_provider(provider())
+            .with_protocol_versions(&[&rustls::version::TLS13])
+            .context("Set TLS versions")?
+            .with_client_cert_verifier(self.verifier())
+            .with_single_cert(self.certs.clone(), self.key.clone_key())
+            .context("Set certificate")
+    }
+
+    pub fn connector(&self) -> anyhow::Result<TlsConnector> {
+        Ok(TlsConnector::from(Arc::new(self.client_config()?)))
+    }
+
+    pub fn acceptor(&self) -> anyhow::Result<TlsAcceptor> {
+        Ok(TlsAcceptor::from(Arc::new(self.server_config()?)))
+    }
+}
+
+/// TLS handshake from the connecting side.
+pub async fn connect(connector: &TlsConnector, stream: Stream) -> anyhow::Result<Stream> {
+    let name = ServerName::try_from(SERVER_NAME)?;
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, connector.connect(name, stream))
+        .await
+        .context("TLS handshake timed out")?
+        .context("TLS handshake")?;
+    Ok(Box::new(stream))
+}
+
+/// TLS handshake from the accepting side.
+pub async fn accept(acceptor: &TlsAcceptor, stream: Stream) -> anyhow::Result<Stream> {
+    let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+        .await
+        .context("TLS handshake timed out")?
+        .context("TLS handshake")?;
+    Ok(Box::new(stream))
+}
+
+/// Accepts exactly the trusted certificates, on either side.
+#[derive(Debug)]
+struct Pinned {
+    trusted: Vec<CertificateDer<'static>>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl Pinned {
+    fn check(&self, end_entity: &CertificateDer<'_>) -> Result<(), rustls::Error> {
+        if self.trusted.iter().any(|cert| cert == end_entity) {
+            Ok(())
+        } else {
+            Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls13(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+impl ServerCertVerifier for Pinned {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_tls13(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.schemes()
+    }
+}
+
+impl ClientCertVerifier for Pinned {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        self.check(end_entity)?;
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verify_tls13(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.schemes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn tls(
+        ours: &rcgen::CertifiedKey<rcgen::KeyPair>,
+        theirs: &rcgen::CertifiedKey<rcgen::KeyPair>,
+    ) -> Tls {
+        Tls {
+            certs: vec![ours.cert.der().clone()],
+            key: PrivateKeyDer::Pkcs8(ours.signing_key.serialize_der().into()),
+            trusted: vec![theirs.cert.der().clone()],
+        }
+    }
+
+    fn pair() -> rcgen::CertifiedKey<rcgen::KeyPair> {
+        rcgen::generate_simple_self_signed([SERVER_NAME.to_owned()]).unwrap()
+    }
+
+    async fn handshake(client: Tls, server: Tls) -> anyhow::Result<()> {
+        let (a, b) = tokio::io::duplex(4096);
+        let connector = client.connector()?;
+        let acceptor = server.acceptor()?;
+        let (client, server) = tokio::join!(
+            connect(&connector, Box::new(a)),
+            accept(&acceptor, Box::new(b)),
+        );
+        let (mut client, mut server) = (client?, server?);
+        client.write_all(b"ping").await?;
+        client.flush().await?;
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mutual() {
+        let (client, server) = (pair(), pair());
+        handshake(tls(&client, &server), tls(&server, &client))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_untrusted() {
+        let (client, server, other) = (pair(), pair(), pair());
+        // server doesn't trust the client
+        assert!(
+            handshake(tls(&client, &server), tls(&server, &other))
+                .await
+                .is_err()
+        );
+        // client doesn't trust the server
+        assert!(
+            handshake(tls(&client, &other), tls(&server, &client))
+                .await
+                .is_err()
+        );
+    }
+}
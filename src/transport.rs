@@ -0,0 +1,683 @@
+//! Connection setup for each [`Transport`]. Whatever the transport, the
+//! handshake and everything after it goes over a single byte stream; input
+//! packets may additionally go over a [`SideChannel`].
+
+use std::{
+    fmt,
+    net::{SocketAddr, ToSocketAddrs},
+    os::unix::fs::FileTypeExt,
+    path::PathBuf,
+    pin::Pin,
+    process::Stdio,
+    str::FromStr,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, Join, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    process::{Child, ChildStdin, ChildStdout, Command},
+};
+
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    acl::Acl,
+    codec::Packet,
+    handshake::{Features, HANDSHAKE_TIMEOUT},
+    noise::{self, Identity, PeerIdentity, Psk},
+    pairing::Pairing,
+    quic, relay,
+    tls::{self, Tls},
+    udp::UdpTransport,
+    websocket,
+};
+
+/// How input events travel between peers.
+#[derive(clap::ValueEnum, PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Transport {
+    /// Everything over a single TCP connection, or whatever stream a Unix
+    /// socket, command or stdio endpoint provides.
+    #[default]
+    Tcp,
+    /// Handshake and control messages over TCP, input packets over UDP. Lost
+    /// pointer motion is not resent. Falls back to TCP if the peer doesn't
+    /// ask for UDP too.
+    Udp,
+    /// Everything over an encrypted QUIC connection, with pointer motion as
+    /// unreliable datagrams. Survives address changes. Note QUIC runs over
+    /// UDP, so the listen port must differ from the discovery port, which the
+    /// default for QUIC does.
+    Quic,
+    /// Everything as binary messages over a WebSocket, e.g. to get through an
+    /// HTTP reverse proxy. `hoips` can also connect to `ws://` URLs with the
+    /// TCP transport.
+    #[value(name = "websocket")]
+    WebSocket,
+}
+
+impl Transport {
+    /// Features to offer in the handshake.
+    pub fn features(self) -> Features {
+        match self {
+            Transport::Tcp | Transport::Quic | Transport::WebSocket => {
+                Features::supported().difference(Features::UDP)
+            }
+            Transport::Udp => Features::supported(),
+        }
+    }
+
+    /// Whether everything goes over a single stream of our own, which TLS and
+    /// Noise can wrap.
+    pub fn is_stream(self) -> bool {
+        matches!(self, Transport::Tcp | Transport::WebSocket)
+    }
+}
+
+/// Where a connection goes to, or comes from.
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Endpoint {
+    Addr(SocketAddr),
+    /// Unix domain socket, `unix:PATH`.
+    Unix(PathBuf),
+    /// Standard input and output of a shell command, `exec:COMMAND`, e.g.
+    /// `exec:ssh host hoipc --stdio`.
+    Exec(String),
+    /// WebSocket URL, `ws://` or `wss://`, see [`websocket`].
+    WebSocket(String),
+    /// A client connected to a relay, `relay:HOST:PORT/CLIENT`, see [`relay`].
+    Relay {
+        relay: SocketAddr,
+        client: String,
+    },
+    /// Our own standard input and output.
+    Stdio,
+}
+
+impl Endpoint {
+    pub fn addr(&self) -> Option<SocketAddr> {
+        match self {
+            Endpoint::Addr(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            return Ok(Endpoint::Unix(path.into()));
+        }
+        if let Some(command) = s.strip_prefix("exec:") {
+            return Ok(Endpoint::Exec(command.to_owned()));
+        }
+        if let Some(rest) = s.strip_prefix("relay:") {
+            let (relay, client) = rest
+                .rsplit_once('/')
+                .context("Expected relay:HOST:PORT/CLIENT")?;
+            anyhow::ensure!(!client.is_empty(), "No client name");
+            let relay = relay
+                .to_socket_addrs()?
+                .next()
+                .with_context(|| format!("{relay} did not resolve to an address"))?;
+            return Ok(Endpoint::Relay {
+                relay,
+                client: client.to_owned(),
+            });
+        }
+        if s.starts_with("ws://") || s.starts_with("wss://") {
+            return Ok(Endpoint::WebSocket(s.to_owned()));
+        }
+        s.to_socket_addrs()?
+            .next()
+            .map(Endpoint::Addr)
+            .with_context(|| format!("{s} did not resolve to an address"))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Addr(addr) => addr.fmt(f),
+            Endpoint::Unix(path) => write!(f, "unix:{}", path.display()),
+            Endpoint::Exec(command) => write!(f, "exec:{command}"),
+            Endpoint::WebSocket(url) => f.write_str(url),
+            Endpoint::Relay { relay, client } => write!(f, "relay:{relay}/{client}"),
+            Endpoint::Stdio => f.write_str("stdio"),
+        }
+    }
+}
+
+/// How connections are secured, if at all.
+#[derive(Default, Debug)]
+pub struct Security {
+    pub tls: Option<Tls>,
+    pub psk: Option<Psk>,
+    pub pairing: Option<Pairing>,
+    /// Secret of the relay, when connecting through one.
+    pub relay: Option<relay::Secret>,
+}
+
+impl Security {
+    /// Whether sessions are encrypted end to end.
+    pub fn encrypted(&self) -> bool {
+        self.tls.is_some() || self.psk.is_some() || self.pairing.is_some()
+    }
+
+    fn identity(&self) -> Option<&Identity> {
+        self.pairing.as_ref().map(|pairing| &pairing.identity)
+    }
+}
+
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
+
+pub type Stream = Box<dyn AsyncStream>;
+
+/// A command's stdout and stdin as a single stream. The command is killed
+/// when this is dropped.
+struct ChildStream {
+    io: Join<ChildStdout, ChildStdin>,
+    _child: Child,
+}
+
+impl ChildStream {
+    fn spawn(command: &str) -> anyhow::Result<Self> {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()
+            .with_context(|| format!("Run {command}"))?;
+        let stdout = child.stdout.take().context("Get command stdout")?;
+        let stdin = child.stdin.take().context("Get command stdin")?;
+        Ok(Self {
+            io: tokio::io::join(stdout, stdin),
+            _child: child,
+        })
+    }
+}
+
+impl AsyncRead for ChildStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ChildStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+pub struct Connection {
+    pub stream: Stream,
+    pub remote: Endpoint,
+    /// Set when pairing is enabled, see [`Pairing::pair`].
+    pub peer: Option<PeerIdentity>,
+    /// Unset for endpoints other than IP addresses.
+    local: Option<SocketAddr>,
+    /// Whether this is the listening side.
+    accepted: bool,
+    quic: Option<quinn::Connection>,
+}
+
+impl Connection {
+    fn tcp(stream: TcpStream, remote: SocketAddr, accepted: bool) -> anyhow::Result<Self> {
+        set_keepalive(&stream)?;
+        Ok(Self {
+            local: Some(stream.local_addr().context("Get local address")?),
+            stream: Box::new(stream),
+            remote: Endpoint::Addr(remote),
+            peer: None,
+            accepted,
+            quic: None,
+        })
+    }
+
+    fn pipe(stream: Stream, remote: Endpoint, accepted: bool) -> Self {
+        Self {
+            stream,
+            remote,
+            peer: None,
+            local: None,
+            accepted,
+            quic: None,
+        }
+    }
+
+    fn quic(conn: quinn::Connection, stream: quic::Stream, accepted: bool) -> Self {
+        Self {
+            stream: Box::new(stream),
+            remote: Endpoint::Addr(conn.remote_address()),
+            peer: None,
+            local: None,
+            accepted,
+            quic: Some(conn),
+        }
+    }
+
+    /// Sets up the side channel for input packets, if `features` call for
+    /// one. Both sides have to call this at the same point in the exchange,
+    /// right after the device descriptors.
+    pub async fn side_channel(
+        &mut self,
+        features: Features,
+    ) -> anyhow::Result<Option<SideChannel>> {
+        if let Some(conn) = &self.quic {
+            if !features.contains(Features::BATCH) {
+                return Ok(None);
+            }
+            return Ok(Some(SideChannel::Quic(quic::Datagrams::new(
+                conn.clone(),
+                features,
+            ))));
+        }
+        if !features.contains(Features::UDP) {
+            return Ok(None);
+        }
+        let (Some(remote), Some(local)) = (self.remote.addr(), self.local) else {
+            anyhow::bail!("UDP transport needs an IP connection");
+        };
+        let udp = if self.accepted {
+            UdpTransport::accept(&mut self.stream, local, remote.ip(), features).await
+        } else {
+            UdpTransport::connect(&mut self.stream, remote, features).await
+        };
+        Ok(Some(SideChannel::Udp(udp.context("Set up UDP transport")?)))
+    }
+}
+
+pub async fn connect(
+    transport: Transport,
+    remote: &Endpoint,
+    security: &Security,
+) -> anyhow::Result<Connection> {
+    let mut conn = match (transport, remote) {
+        (Transport::Quic, Endpoint::Addr(remote)) => {
+            let (conn, stream) = quic::connect(*remote, security.tls.as_ref()).await?;
+            return Ok(Connection::quic(conn, stream, false));
+        }
+        (Transport::WebSocket, Endpoint::Addr(addr)) => Connection::pipe(
+            websocket::connect(&format!("ws://{addr}/")).await?,
+            remote.clone(),
+            false,
+        ),
+        (Transport::Tcp | Transport::WebSocket, Endpoint::WebSocket(url)) => {
+            Connection::pipe(websocket::connect(url).await?, remote.clone(), false)
+        }
+        (Transport::Tcp | Transport::Udp, Endpoint::Addr(remote)) => {
+            let stream = TcpStream::connect(remote)
+                .await
+                .context("Open TCP stream")?;
+            Connection::tcp(stream, *remote, false)?
+        }
+        (Transport::Tcp, Endpoint::Relay { relay, client }) => {
+            let secret = security
+                .relay
+                .as_ref()
+                .context("Connecting through a relay needs --relay-secret")?;
+            let stream = TcpStream::connect(relay).await.context("Open TCP stream")?;
+            let mut conn = Connection::tcp(stream, *relay, false)?;
+            relay::request(&mut conn.stream, client, secret, security.encrypted()).await?;
+            conn.remote = remote.clone();
+            conn
+        }
+        (Transport::Tcp, Endpoint::Unix(path)) => {
+            let stream = UnixStream::connect(path)
+                .await
+                .with_context(|| format!("Connect to {}", path.display()))?;
+            Connection::pipe(Box::new(stream), remote.clone(), false)
+        }
+        (Transport::Tcp, Endpoint::Exec(command)) => Connection::pipe(
+            Box::new(ChildStream::spawn(command)?),
+            remote.clone(),
+            false,
+        ),
+        (Transport::Tcp, Endpoint::Stdio) => Connection::pipe(
+            Box::new(tokio::io::join(tokio::io::stdin(), tokio::io::stdout())),
+            remote.clone(),
+            false,
+        ),
+        _ => anyhow::bail!("Only the TCP transport works with {remote}"),
+    };
+    if let Some(tls) = &security.tls {
+        conn.stream = tls::connect(&tls.connector()?, conn.stream).await?;
+    }
+    if security.psk.is_some() || security.pairing.is_some() {
+        (conn.stream, conn.peer) =
+            noise::connect(security.psk.as_ref(), security.identity(), conn.stream).await?;
+    }
+    Ok(conn)
+}
+
+/// Makes the kernel notice dead peers on idle connections, e.g. ones queued in
+/// reverse mode after a NAT silently dropped the mapping.
+pub(crate) fn set_keepalive(stream: &TcpStream) -> anyhow::Result<()> {
+    let keepalive = socket2::TcpKeepalive::new()
+        .with_time(Duration::from_secs(15))
+        .with_interval(Duration::from_secs(5))
+        .with_retries(3);
+    socket2::SockRef::from(stream)
+        .set_tcp_keepalive(&keepalive)
+        .context("Enable TCP keepalive")
+}
+
+/// Security layers applied to accepted streams.
+pub struct Acceptor {
+    tls: Option<TlsAcceptor>,
+    psk: Option<Psk>,
+    identity: Option<Identity>,
+}
+
+impl Acceptor {
+    pub fn new(security: &Security) -> anyhow::Result<Self> {
+        Ok(Self {
+            tls: security.tls.as_ref().map(Tls::acceptor).transpose()?,
+            psk: security.psk.clone(),
+            identity: security.identity().cloned(),
+        })
+    }
+
+    /// Runs the TLS and Noise handshakes on `conn`, as configured.
+    pub async fn secure(&self, mut conn: Connection) -> anyhow::Result<Connection> {
+        if let Some(tls) = &self.tls {
+            conn.stream = tls::accept(tls, conn.stream).await?;
+        }
+        if self.psk.is_some() || self.identity.is_some() {
+            let remote = &conn.remote;
+            (conn.stream, conn.peer) =
+                noise::accept(self.psk.as_ref(), self.identity.as_ref(), conn.stream)
+                    .await
+                    .with_context(|| format!("Securing connection from {remote}"))?;
+        }
+        Ok(conn)
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener, Arc<Acceptor>),
+    /// Upgrades incoming HTTP connections to WebSockets.
+    WebSocket(TcpListener, Arc<Acceptor>),
+    Unix(UnixListener, PathBuf, Arc<Acceptor>),
+    /// Accepts our own standard input and output as the connection.
+    Stdio(Arc<Acceptor>),
+    Quic(quic::Listener),
+}
+
+/// A connection that was accepted, but hasn't been through the handshakes of
+/// its security layers yet, see [`Incoming::secure`].
+pub enum Incoming {
+    Stream {
+        conn: Connection,
+        websocket: bool,
+        acceptor: Arc<Acceptor>,
+    },
+    Quic(Box<quinn::Incoming>),
+}
+
+impl Incoming {
+    /// Runs the WebSocket upgrade, TLS and Noise handshakes, as configured.
+    /// Each can take up to [`HANDSHAKE_TIMEOUT`].
+    pub async fn secure(self) -> anyhow::Result<Connection> {
+        match self {
+            Incoming::Stream {
+                mut conn,
+                websocket,
+                acceptor,
+            } => {
+                if websocket {
+                    let remote = &conn.remote;
+                    conn.stream = websocket::accept(conn.stream)
+                        .await
+                        .with_context(|| format!("Upgrading connection from {remote}"))?;
+                }
+                acceptor.secure(conn).await
+            }
+            Incoming::Quic(incoming) => {
+                let (conn, stream) =
+                    tokio::time::timeout(HANDSHAKE_TIMEOUT, quic::establish(*incoming))
+                        .await
+                        .context("QUIC handshake timed out")??;
+                Ok(Connection::quic(conn, stream, true))
+            }
+        }
+    }
+}
+
+impl Listener {
+    pub async fn bind(
+        transport: Transport,
+        local: &Endpoint,
+        security: &Security,
+    ) -> anyhow::Result<Self> {
+        Ok(match (transport, local) {
+            (Transport::Tcp | Transport::Udp, Endpoint::Addr(addr)) => Self::Tcp(
+                TcpListener::bind(addr).await.context("Bind TCP listener")?,
+                Arc::new(Acceptor::new(security)?),
+            ),
+            (Transport::WebSocket, Endpoint::Addr(addr)) => Self::WebSocket(
+                TcpListener::bind(addr).await.context("Bind TCP listener")?,
+                Arc::new(Acceptor::new(security)?),
+            ),
+            (Transport::Quic, Endpoint::Addr(addr)) => {
+                Self::Quic(quic::Listener::bind(*addr, security.tls.as_ref())?)
+            }
+            (Transport::Tcp, Endpoint::Unix(path)) => {
+                // left over from a previous run.
+                if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path)
+                        .with_context(|| format!("Remove stale socket {}", path.display()))?;
+                }
+                Self::Unix(
+                    UnixListener::bind(path)
+                        .with_context(|| format!("Bind Unix socket {}", path.display()))?,
+                    path.clone(),
+                    Arc::new(Acceptor::new(security)?),
+                )
+            }
+            (Transport::Tcp, Endpoint::Stdio) => Self::Stdio(Arc::new(Acceptor::new(security)?)),
+            (_, Endpoint::Exec(_) | Endpoint::WebSocket(_) | Endpoint::Relay { .. }) => {
+                anyhow::bail!("Can't listen on {local}")
+            }
+            _ => anyhow::bail!("Only the TCP transport works with {local}"),
+        })
+    }
+
+    /// Accepts the next connection `acl` permits, and secures it. Others are
+    /// closed right away. Only TCP and QUIC connections are checked against
+    /// `acl`, as the others come from this machine.
+    pub async fn accept(&self, acl: &Acl) -> anyhow::Result<Connection> {
+        self.incoming(acl).await?.secure().await
+    }
+
+    /// Like [`Self::accept`], but leaves the handshakes to the caller, so that
+    /// one slow peer doesn't hold up the next.
+    pub async fn incoming(&self, acl: &Acl) -> anyhow::Result<Incoming> {
+        let stream = |conn, websocket, acceptor: &Arc<Acceptor>| Incoming::Stream {
+            conn,
+            websocket,
+            acceptor: acceptor.clone(),
+        };
+        Ok(match self {
+            Listener::Tcp(listener, acceptor) => {
+                let (tcp, remote) = accept_tcp(listener, acl).await?;
+                stream(Connection::tcp(tcp, remote, true)?, false, acceptor)
+            }
+            Listener::WebSocket(listener, acceptor) => {
+                // behind a reverse proxy, `acl` sees the proxy's address.
+                let (tcp, remote) = accept_tcp(listener, acl).await?;
+                stream(Connection::tcp(tcp, remote, true)?, true, acceptor)
+            }
+            Listener::Unix(listener, path, acceptor) => {
+                let (unix, _) = listener.accept().await.context("Accept connection")?;
+                // peers are usually unnamed, so go by our own path.
+                let remote = Endpoint::Unix(path.clone());
+                stream(
+                    Connection::pipe(Box::new(unix), remote, true),
+                    false,
+                    acceptor,
+                )
+            }
+            Listener::Stdio(acceptor) => {
+                let stdio = tokio::io::join(tokio::io::stdin(), tokio::io::stdout());
+                stream(
+                    Connection::pipe(Box::new(stdio), Endpoint::Stdio, true),
+                    false,
+                    acceptor,
+                )
+            }
+            Listener::Quic(listener) => Incoming::Quic(Box::new(listener.incoming(acl).await?)),
+        })
+    }
+}
+
+async fn accept_tcp(listener: &TcpListener, acl: &Acl) -> anyhow::Result<(TcpStream, SocketAddr)> {
+    loop {
+        let (stream, remote) = listener.accept().await.context("Accept connection")?;
+        if acl.permits(remote) {
+            return Ok((stream, remote));
+        }
+        tracing::warn!(%remote, "Rejected connection from disallowed address");
+    }
+}
+
+/// Alternative path for input packets.
+pub enum SideChannel {
+    Udp(UdpTransport),
+    Quic(quic::Datagrams),
+}
+
+impl SideChannel {
+    /// Whether `packet` should go over the side channel rather than the
+    /// stream.
+    pub fn carries(&self, packet: &Packet) -> bool {
+        match self {
+            SideChannel::Udp(_) => true,
+            SideChannel::Quic(datagrams) => datagrams.carries(packet),
+        }
+    }
+
+    pub async fn send(&mut self, packet: Packet) -> anyhow::Result<()> {
+        match self {
+            SideChannel::Udp(udp) => udp.send(packet).await,
+            SideChannel::Quic(datagrams) => datagrams.send(packet).await,
+        }
+    }
+
+    /// Cancel-safe.
+    pub async fn recv(&mut self) -> anyhow::Result<Packet> {
+        match self {
+            SideChannel::Udp(udp) => udp.recv().await,
+            SideChannel::Quic(datagrams) => datagrams.recv().await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_endpoint() {
+        let parse = |s: &str| s.parse::<Endpoint>().unwrap();
+        assert_eq!(
+            parse("127.0.0.1:27056"),
+            Endpoint::Addr("127.0.0.1:27056".parse().unwrap())
+        );
+        assert_eq!(
+            parse("unix:/run/hoip.sock"),
+            Endpoint::Unix("/run/hoip.sock".into())
+        );
+        assert_eq!(
+            parse("exec:ssh host hoipc --stdio"),
+            Endpoint::Exec("ssh host hoipc --stdio".to_owned())
+        );
+        assert_eq!(
+            parse("wss://example.com/hoip"),
+            Endpoint::WebSocket("wss://example.com/hoip".to_owned())
+        );
+        assert_eq!(
+            parse("relay:127.0.0.1:27058/desk top"),
+            Endpoint::Relay {
+                relay: "127.0.0.1:27058".parse().unwrap(),
+                client: "desk top".to_owned()
+            }
+        );
+        assert!("relay:127.0.0.1:27058/".parse::<Endpoint>().is_err());
+        assert_eq!(parse("exec:cat").to_string(), "exec:cat");
+        assert!("no-port".parse::<Endpoint>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_exec() {
+        let remote = Endpoint::Exec("cat".to_owned());
+        let mut conn = connect(Transport::Tcp, &remote, &Security::default())
+            .await
+            .unwrap();
+        conn.stream.write_all(b"hello").await.unwrap();
+        conn.stream.flush().await.unwrap();
+        let mut buf = [0; 5];
+        conn.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(
+            connect(Transport::Quic, &remote, &Security::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unix() {
+        let path = std::env::temp_dir().join(format!("hoip-test-{}.sock", std::process::id()));
+        let local = Endpoint::Unix(path.clone());
+        let (acl, security) = (Acl::default(), Security::default());
+        let listener = Listener::bind(Transport::Tcp, &local, &security)
+            .await
+            .unwrap();
+        let (a, b) = tokio::join!(
+            listener.accept(&acl),
+            connect(Transport::Tcp, &local, &security),
+        );
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        b.stream.write_all(b"hi").await.unwrap();
+        let mut buf = [0; 2];
+        a.stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hi");
+        // binding again replaces the socket left behind.
+        drop(listener);
+        Listener::bind(Transport::Tcp, &local, &security)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,453 @@
+//! Input packets over UDP, for when TCP head-of-line blocking hurts more than
+//! the occasional lost packet. Used alongside the TCP connection when
+//! [`Features::UDP`] is negotiated; everything but input packets still goes
+//! over TCP.
+//!
+//! Packets that are only pointer motion are sent once, and the receiver drops
+//! them if something newer has already arrived. Everything else, keys and
+//! buttons in particular, is retransmitted until acknowledged and delivered in
+//! order, so that a key-up is never lost or reordered before its key-down.
+//!
+//! Datagram formats:
+//! - data: `u8` [`KIND_DATA`], `u8` reliable flag, `u32` sequence number,
+//!   `u32` reliable sequence number, then the packet as encoded by [`Codec`];
+//! - ack: `u8` [`KIND_ACK`], `u32` next expected reliable sequence number.
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use anyhow::Context;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::UdpSocket,
+    time::Instant,
+};
+use tokio_util::{
+    bytes::{Buf, BufMut, BytesMut},
+    codec::{Decoder, Encoder},
+};
+
+use crate::{
+    codec::{Codec, Message, Packet},
+    handshake::Features,
+};
+
+const KIND_DATA: u8 = 0;
+const KIND_ACK: u8 = 1;
+const DATA_HEADER_LEN: usize = 10;
+/// Largest datagram we expect. Packets are a few dozen bytes in practice.
+const MAX_DATAGRAM_LEN: usize = 65536;
+/// How long to wait for an ack before sending a reliable packet again.
+const RETRANSMIT_TIMEOUT: Duration = Duration::from_millis(50);
+/// Limit on reliable packets in flight, or buffered out of order on the
+/// receiving side.
+const MAX_IN_FLIGHT: usize = 1024;
+
+/// Whether `a` comes after `b`, accounting for wraparound.
+pub(crate) fn is_after(a: u32, b: u32) -> bool {
+    (a.wrapping_sub(b) as i32) > 0
+}
+
+struct Unacked {
+    reliable_seq: u32,
+    datagram: BytesMut,
+    sent: Instant,
+}
+
+/// A well-formed datagram.
+enum Datagram {
+    Ack {
+        next: u32,
+    },
+    Data {
+        reliable: bool,
+        seq: u32,
+        reliable_seq: u32,
+        packet: Packet,
+    },
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    /// Datagrams from anywhere else are ignored. On the receiving side, the
+    /// port is learned from the first well-formed datagram.
+    peer_ip: IpAddr,
+    peer: Option<SocketAddr>,
+    codec: Codec,
+    // sending side
+    next_seq: u32,
+    next_reliable_seq: u32,
+    unacked: VecDeque<Unacked>,
+    // receiving side
+    last_seq: Option<u32>,
+    expected_reliable_seq: u32,
+    out_of_order: BTreeMap<u32, (u32, Packet)>,
+    ready: VecDeque<Packet>,
+    recv_buf: Box<[u8]>,
+}
+
+impl UdpTransport {
+    fn new(
+        socket: UdpSocket,
+        peer_ip: IpAddr,
+        peer: Option<SocketAddr>,
+        features: Features,
+    ) -> Self {
+        Self {
+            socket,
+            peer_ip,
+            peer,
+            codec: Codec::new(features),
+            next_seq: 0,
+            next_reliable_seq: 0,
+            unacked: VecDeque::new(),
+            last_seq: None,
+            expected_reliable_seq: 0,
+            out_of_order: BTreeMap::new(),
+            ready: VecDeque::new(),
+            recv_buf: vec![0u8; MAX_DATAGRAM_LEN].into_boxed_slice(),
+        }
+    }
+
+    /// Sending side. Reads the receiver's UDP port, as sent with [`accept`],
+    /// from `stream`. Only the IP of `peer` is used.
+    ///
+    /// [`accept`]: Self::accept
+    pub async fn connect(
+        stream: &mut (impl AsyncRead + Unpin),
+        mut peer: SocketAddr,
+        features: Features,
+    ) -> anyhow::Result<Self> {
+        let port = stream.read_u16().await.context("Read UDP port")?;
+        // this weirdness instead of SocketAddr::new to preserve scope_id.
+        peer.set_port(port);
+        let bind: IpAddr = if peer.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let socket = UdpSocket::bind((bind, 0))
+            .await
+            .context("Bind UDP socket")?;
+        socket.connect(peer).await.context("Connect UDP socket")?;
+        Ok(Self::new(socket, peer.ip(), Some(peer), features))
+    }
+
+    /// Receiving side. Binds to an ephemeral port on the IP of `local`, and
+    /// tells the peer which one over `stream`.
+    pub async fn accept(
+        stream: &mut (impl AsyncWrite + Unpin),
+        mut local: SocketAddr,
+        peer_ip: IpAddr,
+        features: Features,
+    ) -> anyhow::Result<Self> {
+        local.set_port(0);
+        let socket = UdpSocket::bind(local).await.context("Bind UDP socket")?;
+        let port = socket.local_addr().context("Get UDP port")?.port();
+        stream.write_u16(port).await.context("Send UDP port")?;
+        stream.flush().await.context("Flush UDP port")?;
+        Ok(Self::new(socket, peer_ip, None, features))
+    }
+
+    pub async fn send(&mut self, packet: Packet) -> anyhow::Result<()> {
+        let reliable = !packet.is_motion();
+        anyhow::ensure!(
+            !reliable || self.unacked.len() < MAX_IN_FLIGHT,
+            "Too many unacknowledged packets"
+        );
+        let mut datagram = BytesMut::new();
+        datagram.put_u8(KIND_DATA);
+        datagram.put_u8(reliable.into());
+        datagram.put_u32(self.next_seq);
+        datagram.put_u32(self.next_reliable_seq);
+        self.codec
+            .encode(packet.into(), &mut datagram)
+            .context("Encode packet")?;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        self.send_to_peer(&datagram).await?;
+        if reliable {
+            self.unacked.push_back(Unacked {
+                reliable_seq: self.next_reliable_seq,
+                datagram,
+                sent: Instant::now(),
+            });
+            self.next_reliable_seq = self.next_reliable_seq.wrapping_add(1);
+        }
+        Ok(())
+    }
+
+    /// Handles incoming datagrams and retransmissions. Resolves with the next
+    /// received packet; on the sending side, it never does, but still has to
+    /// be polled for retransmissions to happen. Cancel-safe.
+    pub async fn recv(&mut self) -> anyhow::Result<Packet> {
+        loop {
+            if let Some(packet) = self.ready.pop_front() {
+                return Ok(packet);
+            }
+            let retransmit_at = self.unacked.front().map(|x| x.sent + RETRANSMIT_TIMEOUT);
+            tokio::select! {
+                res = self.socket.recv_from(&mut self.recv_buf) => {
+                    let (len, from) = match res {
+                        // ICMP port unreachable, the peer might come back
+                        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => continue,
+                        res => res.context("Receive datagram")?,
+                    };
+                    if from.ip() != self.peer_ip || self.peer.is_some_and(|peer| peer != from) {
+                        tracing::debug!(%from, "Ignoring datagram from unexpected address");
+                        continue;
+                    }
+                    // datagrams aren't authenticated, a spoofed one mustn't
+                    // end the session or pick the peer's port.
+                    let datagram = match self.decode(BytesMut::from(&self.recv_buf[..len])) {
+                        Ok(Some(datagram)) => datagram,
+                        Ok(None) => continue,
+                        Err(e) => {
+                            tracing::debug!(%from, "Ignoring malformed datagram: {e:#}");
+                            continue;
+                        }
+                    };
+                    self.peer.get_or_insert(from);
+                    self.handle(datagram).await?;
+                }
+                _ = tokio::time::sleep_until(retransmit_at.unwrap_or_else(Instant::now)),
+                    if retransmit_at.is_some() =>
+                {
+                    self.retransmit().await?;
+                }
+            }
+        }
+    }
+
+    async fn retransmit(&mut self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        for i in 0..self.unacked.len() {
+            if self.unacked[i].sent + RETRANSMIT_TIMEOUT <= now {
+                tracing::trace!(seq = self.unacked[i].reliable_seq, "Retransmitting");
+                let datagram = self.unacked[i].datagram.clone();
+                self.send_to_peer(&datagram).await?;
+                self.unacked[i].sent = now;
+            }
+        }
+        // keep the queue ordered by send time, so that the front is always the
+        // next one to retransmit.
+        self.unacked.make_contiguous().sort_by_key(|x| x.sent);
+        Ok(())
+    }
+
+    /// Parses `datagram`, or `None` if it carries a packet the codec
+    /// rejected.
+    fn decode(&mut self, mut datagram: BytesMut) -> anyhow::Result<Option<Datagram>> {
+        match datagram.try_get_u8()? {
+            KIND_ACK => {
+                let next = datagram.try_get_u32()?;
+                anyhow::ensure!(datagram.is_empty(), "Malformed datagram");
+                Ok(Some(Datagram::Ack { next }))
+            }
+            KIND_DATA => {
+                anyhow::ensure!(datagram.len() >= DATA_HEADER_LEN - 1, "Truncated datagram");
+                let reliable = datagram.get_u8() != 0;
+                let seq = datagram.get_u32();
+                let reliable_seq = datagram.get_u32();
+                let packet = match self.codec.decode(&mut datagram)? {
+                    Some(Message::Packet(packet)) if datagram.is_empty() => packet,
+                    // invalid packet, already logged by the codec
+                    None if datagram.is_empty() => return Ok(None),
+                    _ => anyhow::bail!("Malformed datagram"),
+                };
+                Ok(Some(Datagram::Data {
+                    reliable,
+                    seq,
+                    reliable_seq,
+                    packet,
+                }))
+            }
+            kind => anyhow::bail!("Unknown datagram kind {kind}"),
+        }
+    }
+
+    async fn handle(&mut self, datagram: Datagram) -> anyhow::Result<()> {
+        match datagram {
+            Datagram::Ack { next } => {
+                self.unacked.retain(|x| !is_after(next, x.reliable_seq));
+            }
+            Datagram::Data {
+                reliable: true,
+                seq,
+                reliable_seq,
+                packet,
+            } => {
+                self.handle_reliable(seq, reliable_seq, packet);
+                let mut ack = BytesMut::new();
+                ack.put_u8(KIND_ACK);
+                ack.put_u32(self.expected_reliable_seq);
+                self.send_to_peer(&ack).await?;
+            }
+            Datagram::Data { seq, packet, .. } => {
+                if self.last_seq.is_none_or(|last| is_after(seq, last)) {
+                    self.last_seq = Some(seq);
+                    self.ready.push_back(packet);
+                } else {
+                    tracing::trace!(seq, "Dropping stale motion");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_reliable(&mut self, seq: u32, reliable_seq: u32, packet: Packet) {
+        if reliable_seq != self.expected_reliable_seq {
+            if is_after(reliable_seq, self.expected_reliable_seq)
+                && self.out_of_order.len() < MAX_IN_FLIGHT
+            {
+                self.out_of_order.insert(reliable_seq, (seq, packet));
+            }
+            return;
+        }
+        let mut next = Some((seq, packet));
+        while let Some((seq, packet)) = next {
+            if self.last_seq.is_none_or(|last| is_after(seq, last)) {
+                self.last_seq = Some(seq);
+            }
+            self.ready.push_back(packet);
+            self.expected_reliable_seq = self.expected_reliable_seq.wrapping_add(1);
+            next = self.out_of_order.remove(&self.expected_reliable_seq);
+        }
+    }
+
+    async fn send_to_peer(&self, datagram: &[u8]) -> anyhow::Result<()> {
+        let Some(peer) = self.peer else {
+            // haven't heard from the peer yet, nothing to answer to
+            return Ok(());
+        };
+        match self.socket.send_to(datagram, peer).await {
+            // ICMP port unreachable, retransmission will take care of it
+            Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+            res => res.map(drop).context("Send datagram"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use evdev::{EventType, InputEvent, KeyCode, RelativeAxisCode};
+
+    use super::*;
+
+    fn key(code: KeyCode, value: i32) -> Packet {
+        Packet {
+            device: 0,
+            events: vec![InputEvent::new(EventType::KEY.0, code.0, value)],
+        }
+    }
+
+    fn motion(x: i32) -> Packet {
+        Packet {
+            device: 0,
+            events: vec![InputEvent::new(
+                EventType::RELATIVE.0,
+                RelativeAxisCode::REL_X.0,
+                x,
+            )],
+        }
+    }
+
+    fn summary(packet: &Packet) -> Vec<(u16, i32)> {
+        packet
+            .events
+            .iter()
+            .map(|evt| (evt.code(), evt.value()))
+            .collect()
+    }
+
+    async fn pair() -> (UdpTransport, UdpTransport) {
+        let localhost = SocketAddr::from((Ipv4Addr::LOCALHOST, 0));
+        let features = Features::BATCH;
+        let mut port = vec![];
+        let receiver = UdpTransport::accept(&mut port, localhost, localhost.ip(), features)
+            .await
+            .unwrap();
+        let sender = UdpTransport::connect(&mut &port[..], localhost, features)
+            .await
+            .unwrap();
+        (sender, receiver)
+    }
+
+    #[tokio::test]
+    async fn test_delivery() {
+        let (mut sender, mut receiver) = pair().await;
+        let packets = [key(KeyCode::KEY_A, 1), motion(1), key(KeyCode::KEY_A, 0)];
+        for packet in &packets {
+            sender.send(packet.clone()).await.unwrap();
+        }
+        for packet in &packets {
+            let received = receiver.recv().await.unwrap();
+            assert_eq!(summary(&received), summary(packet));
+        }
+        // acks arrive
+        tokio::time::timeout(Duration::from_millis(200), sender.recv())
+            .await
+            .expect_err("sender never yields packets");
+        assert!(sender.unacked.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_reordering() {
+        let (_, mut receiver) = pair().await;
+        // key-up arrives before key-down
+        receiver.handle_reliable(2, 1, key(KeyCode::KEY_A, 0));
+        assert!(receiver.ready.is_empty());
+        receiver.handle_reliable(0, 0, key(KeyCode::KEY_A, 1));
+        let ready: Vec<_> = receiver.ready.drain(..).map(|x| summary(&x)).collect();
+        assert_eq!(
+            ready,
+            [
+                summary(&key(KeyCode::KEY_A, 1)),
+                summary(&key(KeyCode::KEY_A, 0))
+            ]
+        );
+        // duplicates are ignored
+        receiver.handle_reliable(0, 0, key(KeyCode::KEY_A, 1));
+        assert!(receiver.ready.is_empty());
+        assert_eq!(receiver.expected_reliable_seq, 2);
+    }
+
+    #[tokio::test]
+    async fn test_malformed() {
+        let (mut sender, mut receiver) = pair().await;
+        let port = receiver.socket.local_addr().unwrap();
+        let spoofer = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        for garbage in [&[][..], &[KIND_DATA, 1], &[KIND_ACK], &[42, 0, 0]] {
+            spoofer.send_to(garbage, port).await.unwrap();
+        }
+        sender.send(key(KeyCode::KEY_A, 1)).await.unwrap();
+        let received = tokio::select! {
+            res = receiver.recv() => res.unwrap(),
+            res = sender.recv() => panic!("{res:?}"),
+        };
+        assert_eq!(summary(&received), summary(&key(KeyCode::KEY_A, 1)));
+        assert_eq!(receiver.peer, Some(sender.socket.local_addr().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_retransmit() {
+        let (mut sender, receiver) = pair().await;
+        let port = receiver.socket.local_addr().unwrap();
+        drop(receiver);
+        sender.send(key(KeyCode::KEY_A, 1)).await.unwrap();
+        sender.send(motion(1)).await.unwrap();
+        // the receiver went away before getting anything; a new one takes its
+        // place and still gets the key, but not the motion.
+        let socket = UdpSocket::bind(port).await.unwrap();
+        let mut receiver =
+            UdpTransport::new(socket, Ipv4Addr::LOCALHOST.into(), None, Features::BATCH);
+        let received = tokio::select! {
+            res = receiver.recv() => res.unwrap(),
+            res = sender.recv() => panic!("{res:?}"),
+        };
+        assert_eq!(summary(&received), summary(&key(KeyCode::KEY_A, 1)));
+    }
+}
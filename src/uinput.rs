@@ -0,0 +1,263 @@
+//! Minimal uinput virtual device. Unlike [`evdev::uinput::VirtualDevice`], it
+//! can declare LEDs, and reads back events written to the device by the
+//! system it runs on.
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    fs::OpenOptions,
+    io,
+    os::{
+        fd::{AsRawFd, OwnedFd},
+        unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    },
+    path::{Path, PathBuf},
+    sync::Mutex,
+    task::{Context, Poll, ready},
+};
+
+use anyhow::Context as _;
+use evdev::{EventType, InputEvent, SynchronizationCode};
+use tokio::io::unix::AsyncFd;
+
+use crate::device::{Capabilities, DeviceInfo};
+
+const UINPUT_PATH: &str = "/dev/uinput";
+const SYSFS_PATH: &str = "/sys/devices/virtual/input";
+
+mod sys {
+    use nix::{ioctl_none, ioctl_read_buf, ioctl_write_int, ioctl_write_ptr};
+
+    const UINPUT_IOCTL_BASE: u8 = b'U';
+    ioctl_none!(ui_dev_create, UINPUT_IOCTL_BASE, 1);
+    ioctl_write_ptr!(ui_dev_setup, UINPUT_IOCTL_BASE, 3, libc::uinput_setup);
+    ioctl_write_ptr!(ui_abs_setup, UINPUT_IOCTL_BASE, 4, libc::uinput_abs_setup);
+    ioctl_write_int!(ui_set_evbit, UINPUT_IOCTL_BASE, 100);
+    ioctl_write_int!(ui_set_keybit, UINPUT_IOCTL_BASE, 101);
+    ioctl_write_int!(ui_set_relbit, UINPUT_IOCTL_BASE, 102);
+    ioctl_write_int!(ui_set_absbit, UINPUT_IOCTL_BASE, 103);
+    ioctl_write_int!(ui_set_mscbit, UINPUT_IOCTL_BASE, 104);
+    ioctl_write_int!(ui_set_ledbit, UINPUT_IOCTL_BASE, 105);
+    ioctl_write_int!(ui_set_swbit, UINPUT_IOCTL_BASE, 109);
+    ioctl_write_int!(ui_set_propbit, UINPUT_IOCTL_BASE, 110);
+    ioctl_read_buf!(ui_get_sysname, UINPUT_IOCTL_BASE, 44, u8);
+}
+
+type SetBit = unsafe fn(libc::c_int, nix::sys::ioctl::ioctl_param_type) -> nix::Result<libc::c_int>;
+
+pub struct VirtualDevice {
+    fd: AsyncFd<OwnedFd>,
+    info: DeviceInfo,
+    capabilities: Capabilities,
+    /// Last LED state the system set on this device.
+    leds: Mutex<BTreeMap<u16, i32>>,
+}
+
+impl VirtualDevice {
+    /// Creates a virtual device with the capabilities from `info`. Must be
+    /// called from within a tokio runtime.
+    pub fn new(info: &DeviceInfo) -> anyhow::Result<Self> {
+        let fd: OwnedFd = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(UINPUT_PATH)
+            .with_context(|| format!("Open {UINPUT_PATH}"))?
+            .into();
+        let raw = fd.as_raw_fd();
+        let set_bits =
+            |evt: Option<EventType>, set_bit: SetBit, codes: &mut dyn Iterator<Item = u16>| {
+                let mut codes = codes.peekable();
+                if codes.peek().is_none() {
+                    return Ok(());
+                }
+                if let Some(evt) = evt {
+                    // SAFETY: raw is a valid uinput fd, the argument is passed by
+                    // value.
+                    unsafe { sys::ui_set_evbit(raw, evt.0.into()) }?;
+                }
+                for code in codes {
+                    // SAFETY: as above.
+                    unsafe { set_bit(raw, code.into()) }?;
+                }
+                nix::Result::Ok(())
+            };
+        set_bits(
+            None,
+            sys::ui_set_propbit,
+            &mut info.properties.iter().map(|x| x.0),
+        )
+        .context("Set device properties")?;
+        set_bits(
+            Some(EventType::KEY),
+            sys::ui_set_keybit,
+            &mut info.keys.iter().map(|x| x.0),
+        )
+        .context("Set device keys")?;
+        set_bits(
+            Some(EventType::RELATIVE),
+            sys::ui_set_relbit,
+            &mut info.relative_axes.iter().map(|x| x.0),
+        )
+        .context("Set device relative axes")?;
+        set_bits(
+            Some(EventType::ABSOLUTE),
+            sys::ui_set_absbit,
+            &mut info.absolute_axes.iter().map(|x| x.code()),
+        )
+        .context("Set device absolute axes")?;
+        for axis in &info.absolute_axes {
+            let setup = libc::uinput_abs_setup {
+                code: axis.code(),
+                absinfo: axis.absinfo().into(),
+            };
+            // SAFETY: raw is a valid uinput fd, setup outlives the call.
+            unsafe { sys::ui_abs_setup(raw, &setup) }.context("Set up absolute axis")?;
+        }
+        set_bits(
+            Some(EventType::SWITCH),
+            sys::ui_set_swbit,
+            &mut info.switches.iter().map(|x| x.0),
+        )
+        .context("Set device switches")?;
+        set_bits(
+            Some(EventType::LED),
+            sys::ui_set_ledbit,
+            &mut info.leds.iter().map(|x| x.0),
+        )
+        .context("Set device LEDs")?;
+        set_bits(
+            Some(EventType::MISC),
+            sys::ui_set_mscbit,
+            &mut info.misc.iter().map(|x| x.0),
+        )
+        .context("Set device misc events")?;
+
+        let mut setup = libc::uinput_setup {
+            id: libc::input_id {
+                bustype: info.input_id.bus_type().0,
+                vendor: info.input_id.vendor(),
+                product: info.input_id.product(),
+                version: info.input_id.version(),
+            },
+            name: [0; libc::UINPUT_MAX_NAME_SIZE],
+            ff_effects_max: 0,
+        };
+        // leave room for the terminating NUL.
+        let name = info.name.as_bytes();
+        let name = &name[..name.len().min(setup.name.len() - 1)];
+        for (dst, src) in setup.name.iter_mut().zip(name) {
+            *dst = *src as libc::c_char;
+        }
+        // SAFETY: raw is a valid uinput fd, setup outlives the call.
+        unsafe { sys::ui_dev_setup(raw, &setup) }.context("Set up device")?;
+        // SAFETY: raw is a valid uinput fd.
+        unsafe { sys::ui_dev_create(raw) }.context("Create device")?;
+
+        Ok(Self {
+            fd: AsyncFd::new(fd).context("Register uinput fd")?,
+            info: info.clone(),
+            capabilities: info.capabilities(),
+            leds: Mutex::new(BTreeMap::new()),
+        })
+    }
+
+    /// Capabilities the device was created with.
+    pub fn info(&self) -> &DeviceInfo {
+        &self.info
+    }
+
+    /// Whether the device was created able to emit `evt`.
+    pub fn supports(&self, evt: &InputEvent) -> bool {
+        self.capabilities.supports(evt)
+    }
+
+    /// The syspath of the input node, e.g. `/sys/devices/virtual/input/input123`.
+    pub fn syspath(&self) -> io::Result<PathBuf> {
+        let mut buf = vec![0u8; 256];
+        // SAFETY: fd is a valid uinput fd, buf is valid for its whole length.
+        let len = unsafe { sys::ui_get_sysname(self.fd.as_raw_fd(), &mut buf) }?;
+        buf.truncate((len as usize).saturating_sub(1));
+        Ok(Path::new(SYSFS_PATH).join(OsStr::from_bytes(&buf)))
+    }
+
+    /// Writes `events` to the device, followed by a `SYN_REPORT`.
+    pub fn emit(&self, events: &[InputEvent]) -> io::Result<()> {
+        let syn = InputEvent::new(
+            EventType::SYNCHRONIZATION.0,
+            SynchronizationCode::SYN_REPORT.0,
+            0,
+        );
+        let raw: Vec<libc::input_event> =
+            events.iter().chain([&syn]).map(|x| (*x).into()).collect();
+        // SAFETY: input_event is plain old data, so viewing it as bytes is fine.
+        let mut bytes = unsafe {
+            std::slice::from_raw_parts(raw.as_ptr() as *const u8, size_of_val(raw.as_slice()))
+        };
+        while !bytes.is_empty() {
+            // SAFETY: fd is valid, bytes is valid for its whole length.
+            let res = unsafe {
+                libc::write(self.fd.as_raw_fd(), bytes.as_ptr() as *const _, bytes.len())
+            };
+            match res {
+                ..0 => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+                written => bytes = &bytes[written as usize..],
+            }
+        }
+        Ok(())
+    }
+
+    /// Polls for the next event the system wrote to this device, e.g. LED
+    /// changes.
+    pub fn poll_event(&self, cx: &mut Context<'_>) -> Poll<io::Result<InputEvent>> {
+        loop {
+            let mut guard = ready!(self.fd.poll_read_ready(cx))?;
+            let res = guard.try_io(|fd| {
+                let mut evt = std::mem::MaybeUninit::<libc::input_event>::uninit();
+                // SAFETY: fd is valid, evt is valid for size_of::<input_event>
+                // bytes.
+                let res = unsafe {
+                    libc::read(
+                        fd.as_raw_fd(),
+                        evt.as_mut_ptr() as *mut _,
+                        size_of::<libc::input_event>(),
+                    )
+                };
+                if res < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if res as usize != size_of::<libc::input_event>() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Short read from uinput",
+                    ));
+                }
+                // SAFETY: the kernel filled the whole struct.
+                Ok(InputEvent::from(unsafe { evt.assume_init() }))
+            });
+            if let Ok(res) = res {
+                if let Ok(evt) = &res
+                    && evt.event_type() == EventType::LED
+                {
+                    self.leds.lock().unwrap().insert(evt.code(), evt.value());
+                }
+                return Poll::Ready(res);
+            }
+        }
+    }
+
+    /// LED state last set by the system, as a list of `EV_LED` events.
+    pub fn led_state(&self) -> Vec<InputEvent> {
+        self.leds
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(code, value)| InputEvent::new(EventType::LED.0, *code, *value))
+            .collect()
+    }
+}
@@ -0,0 +1,183 @@
+//! WebSocket transport. The byte stream goes as binary messages over an
+//! upgraded HTTP connection, so that it passes through HTTP reverse proxies.
+//! TLS and Noise, if enabled, run inside the WebSocket, end to end; `wss://`
+//! URLs are for proxies that only talk HTTPS, and are checked against the
+//! usual web CAs.
+
+use std::{io, sync::Arc};
+
+use anyhow::Context;
+use futures::{SinkExt, StreamExt, TryStreamExt, future};
+use rustls::pki_types::ServerName;
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_tungstenite::{
+    WebSocketStream,
+    tungstenite::{
+        Error, Message,
+        client::IntoClientRequest,
+        error::ProtocolError,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{StatusCode, header::ORIGIN},
+    },
+};
+use tokio_util::{
+    bytes::Bytes,
+    io::{CopyToBytes, SinkWriter, StreamReader},
+};
+
+use crate::{
+    handshake::HANDSHAKE_TIMEOUT,
+    tls,
+    transport::{self, Stream},
+};
+
+/// Opens a WebSocket connection to a `ws://` or `wss://` URL.
+pub async fn connect(url: &str) -> anyhow::Result<Stream> {
+    let request = url.into_client_request().context("Invalid WebSocket URL")?;
+    let uri = request.uri();
+    let host = uri.host().context("No host in WebSocket URL")?.to_owned();
+    let secure = match uri.scheme_str() {
+        Some("ws") => false,
+        Some("wss") => true,
+        _ => anyhow::bail!("WebSocket URL has to start with ws:// or wss://"),
+    };
+    let port = uri.port_u16().unwrap_or(if secure { 443 } else { 80 });
+    // brackets around IPv6 addresses are part of the URL syntax only.
+    let addr = (host.trim_start_matches('[').trim_end_matches(']'), port);
+    let stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("Open TCP stream to {host}:{port}"))?;
+    transport::set_keepalive(&stream)?;
+    let stream: Stream = if secure {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+        let config = rustls::ClientConfig::builder_with_provider(tls::provider())
+            .with_safe_default_protocol_versions()
+            .context("Set TLS versions")?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let name = ServerName::try_from(addr.0.to_owned()).context("Invalid server name")?;
+        let connect = TlsConnector::from(Arc::new(config)).connect(name, stream);
+        let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, connect)
+            .await
+            .context("HTTPS handshake timed out")?
+            .context("HTTPS handshake")?;
+        Box::new(stream)
+    } else {
+        Box::new(stream)
+    };
+    let (ws, _) = tokio::time::timeout(
+        HANDSHAKE_TIMEOUT,
+        tokio_tungstenite::client_async(request, stream),
+    )
+    .await
+    .context("WebSocket handshake timed out")?
+    .context("WebSocket handshake")?;
+    Ok(wrap(ws))
+}
+
+/// Upgrades an incoming HTTP connection to a WebSocket, whatever the path.
+/// Requests with an `Origin` header are refused: browsers always send one,
+/// `hoips` never does, so this keeps web pages from typing into the client.
+pub async fn accept(stream: Stream) -> anyhow::Result<Stream> {
+    let accept = tokio_tungstenite::accept_hdr_async(stream, check_origin);
+    let ws = tokio::time::timeout(HANDSHAKE_TIMEOUT, accept)
+        .await
+        .context("WebSocket handshake timed out")?
+        .context("WebSocket handshake")?;
+    Ok(wrap(ws))
+}
+
+// the error type is tungstenite's to choose.
+#[allow(clippy::result_large_err)]
+fn check_origin(request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+    let Some(origin) = request.headers().get(ORIGIN) else {
+        return Ok(response);
+    };
+    tracing::warn!(?origin, "Refusing WebSocket request from a browser");
+    let mut error = ErrorResponse::new(Some("Browsers are not allowed".to_owned()));
+    *error.status_mut() = StatusCode::FORBIDDEN;
+    Err(error)
+}
+
+/// Turns binary messages back into a byte stream. Each write goes out as one
+/// message; anything but binary messages is skipped. A peer going away
+/// without a closing handshake is the end of the stream, as with TCP.
+fn wrap(ws: WebSocketStream<Stream>) -> Stream {
+    let ws = ws
+        .take_while(|msg| {
+            future::ready(!matches!(
+                msg,
+                Err(Error::Protocol(ProtocolError::ResetWithoutClosingHandshake))
+            ))
+        })
+        .sink_map_err(io::Error::other)
+        .with(|data: Bytes| future::ready(Ok::<_, io::Error>(Message::Binary(data))))
+        .map_err(io::Error::other)
+        .try_filter_map(|msg| {
+            future::ready(Ok(match msg {
+                Message::Binary(data) => Some(data),
+                _ => None,
+            }))
+        });
+    Box::new(SinkWriter::new(StreamReader::new(CopyToBytes::new(ws))))
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_websocket() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/some/path", listener.local_addr().unwrap());
+        let accept = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept(Box::new(stream)).await
+        };
+        let (a, b) = tokio::join!(accept, connect(&url));
+        let (mut a, mut b) = (a.unwrap(), b.unwrap());
+        b.write_all(b"hello").await.unwrap();
+        b.write_all(b" world").await.unwrap();
+        b.flush().await.unwrap();
+        let mut buf = [0; 11];
+        a.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+        drop(b);
+        assert_eq!(a.read(&mut buf).await.unwrap(), 0);
+
+        assert!(connect("http://localhost/").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_origin() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/", listener.local_addr().unwrap());
+        let accept = async {
+            let (stream, _) = listener.accept().await.unwrap();
+            accept(Box::new(stream)).await
+        };
+        let connect = async {
+            let mut request = url.as_str().into_client_request().unwrap();
+            request
+                .headers_mut()
+                .insert(ORIGIN, "http://evil.example".parse().unwrap());
+            let stream = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            tokio_tungstenite::client_async(request, stream).await
+        };
+        let (a, b) = tokio::join!(accept, connect);
+        assert!(a.is_err());
+        let Err(Error::Http(response)) = b else {
+            panic!("expected an HTTP error");
+        };
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}